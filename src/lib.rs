@@ -1 +1,6 @@
 pub mod storage;
+pub mod table;
+pub mod text;
+pub mod tuple;
+
+pub use storage::{check, recover};
@@ -1,3 +1,575 @@
-fn main() {
-    println!("Hello, world!");
+use boxerdb::storage::{import_csv, read_dump, write_dump, BTree, CsvOptions, DumpFormat, ImportMode, KeyColumn, RangeIter, StorageConfig};
+use boxerdb::text::Format;
+use boxerdb::{check as check_file, recover as recover_file};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, IsTerminal, Result, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "usage: boxerdb [--format utf8|hex|base64] [--bucket <name>] <command> ...
+
+reading and writing entries, keys and values are given and printed in --format (utf8 by default,
+other bytes escaped as \\xNN), in the main tree or in --bucket:
+  boxerdb get <db> <key>
+  boxerdb put <db> <key> <value> [--ttl <seconds>]
+  boxerdb delete <db> <key>
+  boxerdb scan <db> [--prefix <prefix> | --start <key> --end <key>] [--limit <n>]
+  boxerdb dump <db> [<file>] [--as jsonl|tsv]         write every entry as JSON Lines (the default) or key<TAB>value
+                                                      lines in --format, to stdout without <file>
+  boxerdb load <db> <file> [--as jsonl|tsv]           load a dump, bulk loaded into an empty tree, inserted otherwise
+  boxerdb import-csv <db> <file> --key <column>[:string|int|uint|float],... [--mode append|replace] [--delimiter <c>]
+                                                      load a CSV file with a header row, keys are the tuple of the
+                                                      --key columns, values the tuple of the other columns
+  boxerdb buckets <db>                                list the buckets
+  boxerdb create-bucket <db> <name>
+  boxerdb drop-bucket <db> <name>                     drop a bucket and everything in it
+  boxerdb stats <db>                                  height, page counts and fill per level of the main tree,
+                                                      file size and dead pages
+  boxerdb page <db> <offset> [--hexdump yes]          show the header, child pointers, slots and cells of a page with
+                                                      their byte ranges, unused space and anything that doesn't add up
+  boxerdb viz <db> <file> [--depth <n>]               write the pages of the main tree as a Graphviz graph, down to
+                                                      depth <n> below the root
+  boxerdb shell <db>                                  run the commands above from stdin, without the <db>
+
+whole files:
+  boxerdb check <db>                                  verify the trees of <db> and print a JSON report
+  boxerdb compact <db>                                rewrite <db> without free pages, drops its history, offline only
+  boxerdb backup <db> <dest>                          copy the current state of <db> into a new compact file
+  boxerdb incremental-backup <db> <since> <dest>      write the pages committed to <db> since commit <since>
+  boxerdb restore <dest> <base> [<incremental>...]    rebuild a database from a backup and its incrementals
+  boxerdb recover <src> <dest>                        salvage every readable entry of a damaged <src> into <dest>";
+
+const SHELL_HELP: &str = "commands: get, put, delete, scan, dump, load, import-csv, buckets, create-bucket, drop-bucket, stats, viz, page and check
+as on the command line without the <db>, bucket [<name>] to switch to a bucket or back to the main tree,
+format <format> to change the format, exit";
+
+// commands run against an open database, they take it right after the command
+const DB_COMMANDS: [&str; 13] = ["get", "put", "delete", "scan", "dump", "load", "import-csv", "buckets", "create-bucket", "drop-bucket", "stats", "viz", "page"];
+// the commands that create the database when it doesn't exist yet, the others fail on a mistyped path
+const CREATING_COMMANDS: [&str; 4] = ["put", "load", "import-csv", "create-bucket"];
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let args = Args::parse(args)?;
+    let words: Vec<&str> = args.words.iter().map(String::as_str).collect();
+    match words.as_slice() {
+        ["check", db] => args.only(&[]).and_then(|_| check(db)),
+        ["compact", db] => args.only(&[]).and_then(|_| compact(db)),
+        ["backup", db, dest] => args.only(&[]).and_then(|_| backup(db, dest)),
+        ["incremental-backup", db, since, dest] => args.only(&[]).and_then(|_| incremental_backup(db, since, dest)),
+        ["restore", dest, base, chain @ ..] => args.only(&[]).and_then(|_| restore(dest, base, chain)),
+        ["recover", src, dest] => args.only(&[]).and_then(|_| recover(src, dest)),
+        ["shell", db] => {
+            args.only(&[])?;
+            shell(Session::open(db, &args, false)?)
+        }
+        [command, db, rest @ ..] if DB_COMMANDS.contains(command) => {
+            let mut session = Session::open(db, &args, CREATING_COMMANDS.contains(command))?;
+            let mut words = vec![*command];
+            words.extend(rest);
+            session.exec(&words, &args)
+        }
+        _ => Err(invalid(USAGE.to_string())),
+    }
+}
+
+/// Words of a command split into positional arguments and --name value options
+struct Args {
+    words: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut words = vec![];
+        let mut options = HashMap::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    let value = args.next().ok_or_else(|| invalid(format!("--{} needs a value", name)))?;
+                    options.insert(name.to_string(), value.clone());
+                }
+                None => words.push(arg.clone()),
+            }
+        }
+        Ok(Self { words, options })
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    /// Fail on options other than --format, --bucket and the allowed ones
+    fn only(&self, allowed: &[&str]) -> Result<()> {
+        let unexpected = self.options.keys().find(|name| !["format", "bucket"].contains(&name.as_str()) && !allowed.contains(&name.as_str()));
+        match unexpected {
+            Some(name) => Err(invalid(format!("unexpected option --{}", name))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An open database with the format and bucket commands use unless they say otherwise
+struct Session {
+    path: String,
+    btree: BTree,
+    format: Format,
+    bucket: Option<String>,
+}
+
+impl Session {
+    fn open(path: &str, args: &Args, create: bool) -> Result<Self> {
+        let format = args.option("format").map_or(Ok(Format::Utf8), Format::parse)?;
+        if !create && !Path::new(path).is_file() {
+            return Err(Error::new(ErrorKind::NotFound, format!("{} does not exist", path)));
+        }
+        let btree = BTree::new(path, None)?;
+        Ok(Self {
+            path: path.to_string(),
+            btree,
+            format,
+            bucket: args.option("bucket").map(str::to_string),
+        })
+    }
+
+    fn exec(&mut self, words: &[&str], args: &Args) -> Result<()> {
+        let format = args.option("format").map_or(Ok(self.format), Format::parse)?;
+        let bucket = args.option("bucket").map(str::to_string).or(self.bucket.clone());
+        let bucket = bucket.as_deref();
+        match words {
+            ["get", key] => {
+                args.only(&[])?;
+                let key = format.decode(key)?;
+                let value = match bucket {
+                    Some(name) => self.btree.open_bucket(name)?.get(&key)?,
+                    None => self.btree.get(&key)?,
+                };
+                let value = value.ok_or_else(|| Error::new(ErrorKind::NotFound, "key not found"))?;
+                println!("{}", format.encode(&value));
+            }
+            ["put", key, value] => {
+                args.only(&["ttl"])?;
+                let (key, value) = (format.decode(key)?, format.decode(value)?);
+                let ttl = args.option("ttl").map(|ttl| parse_number(ttl).map(Duration::from_secs)).transpose()?;
+                match (bucket, ttl) {
+                    (Some(_), Some(_)) => return Err(invalid("--ttl only works in the main tree".to_string())),
+                    (Some(name), None) => self.btree.open_bucket(name)?.insert(key, value)?,
+                    (None, Some(ttl)) => self.btree.insert_with_ttl(key, value, ttl)?,
+                    (None, None) => self.btree.insert(key, value)?,
+                };
+            }
+            ["delete", key] => {
+                args.only(&[])?;
+                let key = format.decode(key)?;
+                let removed = match bucket {
+                    Some(name) => self.btree.open_bucket(name)?.delete(&key)?,
+                    None => self.btree.delete(&key)?,
+                };
+                removed.ok_or_else(|| Error::new(ErrorKind::NotFound, "key not found"))?;
+            }
+            ["scan"] => {
+                args.only(&["prefix", "start", "end", "limit"])?;
+                let decode = |name| args.option(name).map(|text| format.decode(text)).transpose();
+                let prefix = decode("prefix")?;
+                let (start, end) = match (&prefix, decode("start")?, decode("end")?) {
+                    (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+                        return Err(invalid("--prefix can't be combined with --start or --end".to_string()));
+                    }
+                    (Some(prefix), None, None) => (Bound::Included(prefix.clone()), Bound::Unbounded),
+                    (None, start, end) => (start.map_or(Bound::Unbounded, Bound::Included), end.map_or(Bound::Unbounded, Bound::Excluded)),
+                };
+                let mut limit = args.option("limit").map_or(Ok(u64::MAX), parse_number)?;
+                let mut out = io::stdout().lock();
+                self.for_each(bucket, (start, end), &mut |key, value| {
+                    // keys sort after the prefix range once they stop starting with it
+                    if limit == 0 || prefix.as_ref().is_some_and(|prefix| !key.starts_with(prefix)) {
+                        return Ok(false);
+                    }
+                    limit -= 1;
+                    writeln!(out, "{}\t{}", format.encode(&key), format.encode(&value))?;
+                    Ok(true)
+                })?;
+            }
+            ["dump"] | ["dump", _] => {
+                args.only(&["as"])?;
+                let dump_format = dump_format(args, format)?;
+                let mut out: Box<dyn Write> = match words.get(1) {
+                    Some(file) => Box::new(BufWriter::new(OpenOptions::new().write(true).create_new(true).open(file)?)),
+                    None => Box::new(io::stdout().lock()),
+                };
+                let count = match bucket {
+                    Some(name) => write_dump(&mut out, dump_format, self.btree.open_bucket(name)?.range(..)?)?,
+                    None => write_dump(&mut out, dump_format, self.btree.range(..))?,
+                };
+                eprintln!("dumped {} entries", count);
+            }
+            ["load", file] => {
+                args.only(&["as"])?;
+                let config = self.btree.storage_config.clone();
+                let entries = read_dump(BufReader::new(File::open(file)?), dump_format(args, format)?)
+                    .enumerate()
+                    .map(move |(i, entry)| {
+                        let (key, value) = entry?;
+                        check_size(&config, &key, &value).map_err(|e| invalid(format!("entry {}: {}", i + 1, e)))?;
+                        Ok((key, value))
+                    });
+                if let Some(name) = bucket
+                    && !self.btree.list_buckets()?.iter().any(|existing| existing == name)
+                {
+                    self.btree.create_bucket(name)?;
+                }
+                // an empty tree is bulk loaded, which needs the dump in key order, otherwise entries are inserted one by one
+                let count = match bucket {
+                    Some(name) => {
+                        let mut target = self.btree.open_bucket(name)?;
+                        match target.range(..)?.next().is_none() {
+                            true => target.bulk_load(entries)?,
+                            false => insert_all(entries, &mut |key, value| target.insert(key, value).map(|_| ()))?,
+                        }
+                    }
+                    None => match self.btree.range(..).next().is_none() {
+                        true => self.btree.bulk_load(entries)?,
+                        false => insert_all(entries, &mut |key, value| self.btree.insert(key, value).map(|_| ()))?,
+                    },
+                };
+                println!("loaded {} entries", count);
+            }
+            ["import-csv", file] => {
+                args.only(&["key", "mode", "delimiter"])?;
+                let keys = args.option("key").ok_or_else(|| invalid("import-csv needs --key <column>[:<type>],...".to_string()))?;
+                let mut options = CsvOptions::new(keys.split(',').map(KeyColumn::parse).collect::<Result<_>>()?);
+                options.mode = match args.option("mode") {
+                    None | Some("append") => ImportMode::Append,
+                    Some("replace") => ImportMode::Replace,
+                    Some(mode) => return Err(invalid(format!("unknown mode {}, expected append or replace", mode))),
+                };
+                if let Some(delimiter) = args.option("delimiter") {
+                    let mut chars = delimiter.chars();
+                    options.delimiter = match (chars.next(), chars.next()) {
+                        (Some(c), None) => c,
+                        _ => return Err(invalid(format!("the delimiter has to be a single character, not {}", delimiter))),
+                    };
+                }
+                options.bucket = bucket.map(str::to_string);
+                let summary = import_csv(&mut self.btree, BufReader::new(File::open(file)?), &options)?;
+                for rejection in &summary.rejections {
+                    eprintln!("line {}: {}", rejection.line, rejection.reason);
+                }
+                println!("loaded {} rows, rejected {}", summary.rows_loaded, summary.rows_rejected);
+            }
+            ["viz", file] => {
+                args.only(&["depth"])?;
+                let depth = args.option("depth").map_or(Ok(u64::MAX), parse_number)?;
+                let dot = self.btree.to_dot(usize::try_from(depth).unwrap_or(usize::MAX))?;
+                OpenOptions::new().write(true).create_new(true).open(file)?.write_all(dot.as_bytes())?;
+            }
+            ["buckets"] => {
+                args.only(&[])?;
+                for name in self.btree.list_buckets()? {
+                    println!("{}", name);
+                }
+            }
+            ["create-bucket", name] => {
+                args.only(&[])?;
+                self.btree.create_bucket(name)?;
+            }
+            ["drop-bucket", name] => {
+                args.only(&[])?;
+                self.btree.drop_bucket(name)?;
+                if self.bucket.as_deref() == Some(*name) {
+                    self.bucket = None;
+                }
+            }
+            ["page", offset] => {
+                args.only(&["hexdump"])?;
+                let layout = self.btree.disk_manager.inspect_page(parse_number(offset)?)?;
+                print!("{}", layout);
+                match args.option("hexdump") {
+                    None | Some("no") => {}
+                    Some("yes") => print!("{}", layout.hexdump()),
+                    Some(other) => return Err(invalid(format!("--hexdump takes yes or no, not {}", other))),
+                }
+            }
+            ["stats"] => {
+                args.only(&[])?;
+                self.stats()?;
+            }
+            _ => return Err(invalid(USAGE.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Call visit with every entry in range until it returns false
+    fn for_each(
+        &mut self,
+        bucket: Option<&str>,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        visit: &mut dyn FnMut(Vec<u8>, Vec<u8>) -> Result<bool>,
+    ) -> Result<()> {
+        fn visit_all(entries: RangeIter, visit: &mut dyn FnMut(Vec<u8>, Vec<u8>) -> Result<bool>) -> Result<()> {
+            for entry in entries {
+                let (key, value) = entry?;
+                if !visit(key, value)? {
+                    break;
+                }
+            }
+            Ok(())
+        }
+        match bucket {
+            Some(name) => visit_all(self.btree.open_bucket(name)?.range(range)?, visit),
+            None => visit_all(self.btree.range(range), visit),
+        }
+    }
+
+    fn stats(&mut self) -> Result<()> {
+        let btree = &mut self.btree;
+        let stats = btree.stats()?;
+        println!("file_size: {}", stats.file_size);
+        println!("page_size: {}", stats.page_size);
+        println!("pages: {}", stats.total_pages);
+        println!("dead_pages: {} ({} free, {} retired)", stats.dead_pages, btree.disk_manager.free_page_count(), btree.disk_manager.retired_page_count());
+        println!("height: {}", stats.height);
+        println!("internal_pages: {}", stats.internal_pages);
+        println!("leaf_pages: {}", stats.leaf_pages);
+        println!("keys: {}", stats.keys);
+        for (depth, level) in stats.levels.iter().enumerate() {
+            println!(
+                "level {}: {} pages, fill min {:.2} avg {:.2} max {:.2}",
+                depth, level.pages, level.min_fill, level.avg_fill, level.max_fill
+            );
+        }
+        println!("commit_id: {}", btree.commit_id);
+        println!("buckets: {}", btree.list_buckets()?.len());
+        println!("snapshots: {}", btree.list_snapshots()?.len());
+        println!("history: {}", btree.history()?.len());
+        Ok(())
+    }
+}
+
+/// Read commands from stdin until it ends or says exit, errors are printed and the shell carries on
+fn shell(mut session: Session) -> Result<()> {
+    let interactive = io::stdin().is_terminal();
+    let mut line = String::new();
+    loop {
+        if interactive {
+            match &session.bucket {
+                Some(name) => print!("boxerdb:{}> ", name),
+                None => print!("boxerdb> "),
+            }
+            io::stdout().flush()?;
+        }
+        line.clear();
+        if io::stdin().read_line(&mut line)? == 0 {
+            break;
+        }
+        let result = split_words(&line).and_then(|words| Args::parse(&words)).and_then(|args| {
+            let words: Vec<&str> = args.words.iter().map(String::as_str).collect();
+            match words.as_slice() {
+                [] => Ok(true),
+                ["exit"] | ["quit"] => Ok(false),
+                ["help"] => {
+                    println!("{}", SHELL_HELP);
+                    Ok(true)
+                }
+                ["bucket"] => {
+                    session.bucket = None;
+                    Ok(true)
+                }
+                ["bucket", name] => {
+                    session.btree.open_bucket(name)?;
+                    session.bucket = Some(name.to_string());
+                    Ok(true)
+                }
+                ["format", name] => {
+                    session.format = Format::parse(name)?;
+                    Ok(true)
+                }
+                ["check"] => check(&session.path).map(|_| true),
+                [command, ..] if !DB_COMMANDS.contains(command) => Err(invalid(format!("unknown command {}, try help", command))),
+                words => session.exec(words, &args).map(|_| true),
+            }
+        });
+        match result {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Split a shell line into words, single or double quotes keep spaces in a word
+/// inside double quotes \" is a quote, other backslashes are left for the format to read
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' | '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some('\\') if c == '"' => match chars.next() {
+                            Some('"') => word.push('"'),
+                            Some(next) => {
+                                word.push('\\');
+                                word.push(next);
+                            }
+                            None => return Err(invalid("unterminated quote".to_string())),
+                        },
+                        Some(next) => word.push(next),
+                        None => return Err(invalid("unterminated quote".to_string())),
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn check(db: &str) -> Result<()> {
+    let report = check_file(db)?;
+    println!("{}", report.to_json());
+    if !report.is_ok() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} violations found", report.violations.len())));
+    }
+    Ok(())
+}
+
+/// Replace db with a compact copy of its current commit
+fn compact(db: &str) -> Result<()> {
+    let mut btree = BTree::new(db, None)?;
+    if !btree.list_snapshots()?.is_empty() {
+        return Err(invalid(format!("{} has named snapshots that compacting would drop, drop them first", db)));
+    }
+    let before = btree.disk_manager.page_offsets()?.len();
+    let compacted = format!("{}.compact", db);
+    btree.backup_to(&compacted)?;
+    drop(btree);
+    fs::rename(&compacted, db)?;
+
+    let after = BTree::new(db, None)?.disk_manager.page_offsets()?.len();
+    println!("compacted {} from {} to {} pages", db, before, after);
+    Ok(())
+}
+
+fn backup(db: &str, dest: &str) -> Result<()> {
+    let mut btree = BTree::new(db, None)?;
+    let mut backup = btree.begin_backup(dest)?;
+    // copy in chunks so a long backup reports progress
+    while !backup.step(&mut btree, 1024)? {
+        eprintln!("copied {} pages", backup.pages_copied());
+    }
+    println!("backed up commit {} of {} to {} ({} pages)", backup.commit_id(), db, dest, backup.pages_copied());
+    Ok(())
+}
+
+fn incremental_backup(db: &str, since: &str, dest: &str) -> Result<()> {
+    let since = parse_number(since)?;
+    let mut btree = BTree::new(db, None)?;
+    let pages = btree.incremental_backup(since, dest)?;
+    println!("backed up commits {} to {} of {} to {} ({} pages)", since, btree.commit_id, db, dest, pages);
+    Ok(())
+}
+
+fn restore(dest: &str, base: &str, chain: &[&str]) -> Result<()> {
+    let btree = BTree::restore(base, chain, dest, None)?;
+    println!("restored commit {} to {}", btree.commit_id, dest);
+    Ok(())
+}
+
+fn recover(src: &str, dest: &str) -> Result<()> {
+    let report = recover_file(src, dest)?;
+    println!(
+        "recovered {} keys from {} of {} pages into {} ({} pages skipped, {} duplicate keys)",
+        report.keys_recovered, report.leaves_recovered, report.pages_scanned, dest, report.pages_skipped, report.duplicates,
+    );
+    Ok(())
+}
+
+/// --as jsonl (the default) or tsv, tsv fields are written in format
+fn dump_format(args: &Args, format: Format) -> Result<DumpFormat> {
+    match args.option("as") {
+        None | Some("jsonl") => Ok(DumpFormat::JsonLines),
+        Some("tsv") => Ok(DumpFormat::Tsv(format)),
+        Some(name) => Err(invalid(format!("unknown dump format {}, expected jsonl or tsv", name))),
+    }
+}
+
+/// Fail on a key or value too long for the pages of the database, rather than on the write
+fn check_size(config: &StorageConfig, key: &[u8], value: &[u8]) -> Result<()> {
+    if key.len() > config.max_key_size as usize {
+        return Err(invalid(format!("key is {} bytes, longer than the maximum of {}", key.len(), config.max_key_size)));
+    }
+    if value.len() > config.max_val_size as usize {
+        return Err(invalid(format!("value is {} bytes, longer than the maximum of {}", value.len(), config.max_val_size)));
+    }
+    Ok(())
+}
+
+/// Insert every entry of a dump, returns how many there were
+fn insert_all(entries: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>, insert: &mut dyn FnMut(Vec<u8>, Vec<u8>) -> Result<()>) -> Result<usize> {
+    let mut count = 0;
+    for entry in entries {
+        let (key, value) = entry?;
+        insert(key, value)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn parse_number(text: &str) -> Result<u64> {
+    text.parse().map_err(|_| invalid(format!("{} is not a number", text)))
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_words() {
+        let words = split_words("put 'a key' \"say \\\"hi\\\" \\x00\"  --ttl 5\n").unwrap();
+        assert_eq!(words, vec!["put", "a key", "say \"hi\" \\x00", "--ttl", "5"]);
+        assert_eq!(split_words("  ").unwrap(), Vec::<String>::new());
+        assert_eq!(split_words("get ''").unwrap(), vec!["get", ""]);
+        assert!(split_words("get 'open").is_err());
+
+        let args = Args::parse(&words).unwrap();
+        assert_eq!(args.words, vec!["put", "a key", "say \"hi\" \\x00"]);
+        assert_eq!(args.option("ttl"), Some("5"));
+        assert!(args.only(&[]).is_err());
+        assert!(args.only(&["ttl"]).is_ok());
+    }
+
+    #[test]
+    fn test_check_size() {
+        let config = StorageConfig::default();
+        assert!(check_size(&config, &[0; 1000], &[0; 3000]).is_ok());
+        assert_eq!(check_size(&config, &[0; 1001], b"").unwrap_err().to_string(), "key is 1001 bytes, longer than the maximum of 1000");
+        assert_eq!(check_size(&config, b"k", &[0; 3001]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
 }
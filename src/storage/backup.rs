@@ -0,0 +1,241 @@
+use crate::storage::btree::BTree;
+use crate::storage::bucket;
+use crate::storage::diskmanager::{DiskManager, Metadata};
+use crate::storage::history::CommitInfo;
+use crate::storage::node::Node;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// A page still to be copied
+struct PendingPage {
+    source_offset: u64,
+    height: usize,
+    // catalog leaves hold bucket root offsets that have to be rewritten too
+    catalog: bool,
+}
+
+/// An online copy of one commit into a new, compact database file
+/// The commit is pinned so its pages aren't reused while the copy runs, writers can keep
+/// committing between calls to step. Only the live trees are copied, history and snapshots are not
+pub struct Backup {
+    dest: DiskManager,
+    source: CommitInfo,
+    pending: Vec<PendingPage>,
+    // source page offset -> destination page offset, so pages reachable twice are copied once
+    remap: HashMap<u64, u64>,
+    next_offset: u64,
+    // the copy of the page placed at first_page_offset, held back until the end so the empty root
+    // the new file starts with stays in place while the backup is unfinished
+    first_page: Option<Node>,
+    finished: bool,
+}
+
+impl Backup {
+    pub(crate) fn new(btree: &mut BTree, path: &str) -> Result<Self> {
+        if Path::new(path).exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", path)));
+        }
+        let dest = DiskManager::new(path, btree.storage_config.clone())?;
+        let source = CommitInfo {
+            commit_id: btree.commit_id,
+            timestamp: 0,
+            root_offset: btree.root_offset,
+            catalog_offset: btree.catalog_offset,
+        };
+
+        let mut backup = Self {
+            next_offset: dest.config.first_page_offset,
+            dest,
+            source,
+            pending: vec![],
+            remap: HashMap::new(),
+            first_page: None,
+            finished: false,
+        };
+        backup.discover(btree, source.catalog_offset, true)?;
+        backup.discover(btree, source.root_offset, false)?;
+        btree.pin(source.commit_id);
+        Ok(backup)
+    }
+
+    /// Commit the backup is a copy of
+    pub fn commit_id(&self) -> u64 {
+        self.source.commit_id
+    }
+
+    pub fn pages_copied(&self) -> usize {
+        self.remap.len() - self.pending.len()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Copy up to max_pages pages, returns true once the backup is complete
+    /// The destination metadata and first page are only written at the end, so an unfinished backup holds an empty tree
+    pub fn step(&mut self, btree: &mut BTree, max_pages: usize) -> Result<bool> {
+        for _ in 0..max_pages {
+            let Some(page) = self.pending.pop() else { break };
+            let mut node = btree.disk_manager.load_node_from_disk(page.source_offset)?;
+            if page.height > 0 {
+                for child in node.children.iter_mut() {
+                    *child = self.discover_page(*child, page.height - 1, page.catalog);
+                }
+            } else if page.catalog {
+                for value in node.values.iter_mut() {
+                    let root_offset = bucket::decode_root(value)?;
+                    *value = bucket::encode_root(self.discover(btree, root_offset, false)?);
+                }
+            }
+            let dest_offset = self.remap[&page.source_offset];
+            if dest_offset == self.dest.config.first_page_offset {
+                self.first_page = Some(node);
+            } else {
+                self.dest.write_node(dest_offset, &node)?;
+            }
+        }
+
+        if self.pending.is_empty() && !self.finished {
+            if let Some(node) = self.first_page.take() {
+                self.dest.write_node(self.dest.config.first_page_offset, &node)?;
+            }
+            let metadata = Metadata {
+                root_offset: self.dest_offset(self.source.root_offset),
+                catalog_offset: self.dest_offset(self.source.catalog_offset),
+                commit_id: self.source.commit_id,
+                history_offset: 0,
+                timestamp: self.source.timestamp,
+            };
+            self.dest.write_metadata(&metadata)?;
+            self.finished = true;
+            btree.unpin(self.source.commit_id);
+        }
+        Ok(self.finished)
+    }
+
+    /// Stop an unfinished backup and release its pin, the partial file is left behind
+    pub fn abort(self, btree: &mut BTree) {
+        if !self.finished {
+            btree.unpin(self.source.commit_id);
+        }
+    }
+
+    /// Queue the tree rooted at source_offset, returns the offset its root will have in the copy
+    fn discover(&mut self, btree: &mut BTree, source_offset: u64, catalog: bool) -> Result<u64> {
+        if source_offset == 0 {
+            return Ok(0);
+        }
+        let height = btree.tree_height(source_offset)?;
+        Ok(self.discover_page(source_offset, height, catalog))
+    }
+
+    fn discover_page(&mut self, source_offset: u64, height: usize, catalog: bool) -> u64 {
+        if let Some(&dest_offset) = self.remap.get(&source_offset) {
+            return dest_offset;
+        }
+        let dest_offset = self.next_offset;
+        self.next_offset += self.dest.config.page_size as u64;
+        self.remap.insert(source_offset, dest_offset);
+        self.pending.push(PendingPage { source_offset, height, catalog });
+        dest_offset
+    }
+
+    fn dest_offset(&self, source_offset: u64) -> u64 {
+        match source_offset {
+            0 => 0,
+            offset => self.remap[&offset],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::BTree;
+    use crate::storage::testing::{key, small_page_config};
+    use tempfile::{NamedTempFile, TempDir};
+
+    #[test]
+    fn test_backup_to_is_compact_copy() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        btree.create_bucket("users").unwrap();
+        for i in 0..100 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+            btree.open_bucket("users").unwrap().insert(key(i), b"u".to_vec()).unwrap();
+        }
+        btree.delete_range(key(10)..key(90)).unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("backup.db");
+        let path = path.to_str().unwrap();
+        btree.backup_to(path).unwrap();
+
+        let mut copy = BTree::new(path, Some(small_page_config())).unwrap();
+        assert_eq!(copy.commit_id, btree.commit_id);
+        let original: Vec<_> = btree.range(..).map(|r| r.unwrap()).collect();
+        let copied: Vec<_> = copy.range(..).map(|r| r.unwrap()).collect();
+        assert_eq!(copied, original);
+        assert_eq!(copy.open_bucket("users").unwrap().get(&key(99)).unwrap(), Some(b"u".to_vec()));
+
+        // nothing but the live pages was written
+        assert_eq!(copy.disk_manager.free_page_count(), 0);
+        assert!(copy.disk_manager.page_offsets().unwrap().len() < btree.disk_manager.page_offsets().unwrap().len());
+    }
+
+    #[test]
+    fn test_backup_while_writing() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        for i in 0..100 {
+            btree.insert(key(i), b"before".to_vec()).unwrap();
+        }
+        let expected: Vec<_> = btree.range(..).map(|r| r.unwrap()).collect();
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("backup.db");
+        let path = path.to_str().unwrap();
+        let mut backup = btree.begin_backup(path).unwrap();
+        let mut i = 0;
+        while !backup.step(&mut btree, 2).unwrap() {
+            // rewrite everything between steps, freed pages would be reused without the pin
+            btree.delete(&key(i % 100)).unwrap();
+            btree.insert(key(i % 100), b"after".to_vec()).unwrap();
+            i += 1;
+        }
+        assert!(backup.pages_copied() > 2);
+
+        let mut copy = BTree::new(path, Some(small_page_config())).unwrap();
+        let copied: Vec<_> = copy.range(..).map(|r| r.unwrap()).collect();
+        assert_eq!(copied, expected);
+    }
+
+    #[test]
+    fn test_unfinished_backup_is_empty() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        for i in 0..100 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+        }
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("backup.db");
+        let path = path.to_str().unwrap();
+        let mut backup = btree.begin_backup(path).unwrap();
+        assert!(!backup.step(&mut btree, 3).unwrap());
+        backup.abort(&mut btree);
+
+        let mut copy = BTree::new(path, Some(small_page_config())).unwrap();
+        assert_eq!(copy.range(..).count(), 0);
+        assert_eq!(copy.get(&key(0)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_backup_refuses_existing_file() {
+        let tmp = NamedTempFile::new().unwrap();
+        let existing = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), None).unwrap();
+        let err = btree.backup_to(existing.path().to_str().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    }
+}
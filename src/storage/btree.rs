@@ -1,224 +1,1481 @@
 use crate::storage::node::Node;
-use crate::storage::diskmanager::{DiskManager, EncodeResult};
-use crate::storage::configs::{StorageConfig};
+use crate::storage::page::SlottedPage;
+use crate::storage::diskmanager::{DiskManager, Metadata};
+use crate::storage::configs::{StorageConfig, VAL_HAS_EXPIRY};
+use crate::storage::iterator::RangeIter;
+use crate::storage::transaction::Transaction;
+use crate::storage::backup::Backup;
+use crate::storage::bucket::{self, Bucket};
+use crate::storage::diff::DiffIter;
+use crate::storage::dot;
+use crate::storage::incremental::Incremental;
+use crate::storage::history::{CommitInfo, Retention, Snapshot};
+use crate::storage::merge::{self, MergeOperator};
+use crate::storage::stats::{self, TreeStats};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::{Bound, Range, RangeBounds};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub struct BTree {
     pub root: Node,
     pub root_offset: u64,
+    pub catalog_offset: u64,
+    pub commit_id: u64,
+    pub history_offset: u64,
+    // unix millis of the current commit, 0 if unknown
+    commit_timestamp: u64,
     pub storage_config: StorageConfig,
     pub disk_manager: DiskManager,
+    merge_operators: HashMap<String, MergeOperator>,
+    retention: Retention,
+    // commits held by running backups, not persisted
+    pins: Vec<u64>,
 }
 
-struct InsertResult {
-    new_offset: Option<u64>,
-    splits: Option<InsertSplit>
+/// Pages a node was split into, with the keys to promote between them
+struct InsertSplit {
+    promoted_keys: Vec<Vec<u8>>,
+    offsets: Vec<u64>,
 }
 
-struct InsertSplit {
-    promoted_key: Vec<u8>,
-    left_offset: u64,
-    right_offset: u64,
+/// What to do with a key once the descent has reached its leaf
+pub(crate) enum LeafUpdate {
+    Keep,
+    Put(Vec<u8>, u64), // value and its expiry, 0 if it never expires
+    Remove,
+}
+
+/// Outcome of applying a LeafUpdate to the bytes of a page
+enum PageUpdate {
+    Unchanged,
+    Applied,
+    // the page has no room for the update, which was already decided and must not be decided again
+    NoRoom(LeafUpdate),
+}
+
+/// Whether a put may create a new key, overwrite an existing one, or both
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum PutMode {
+    Upsert,
+    InsertNew,
+    Replace,
+}
+
+/// Returned by compare_and_swap when the key didn't hold the expected value
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompareAndSwapError {
+    pub current: Option<Vec<u8>>,
 }
 
+impl std::fmt::Display for CompareAndSwapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "compare and swap failed, current value is {:?}", self.current)
+    }
+}
+
+impl std::error::Error for CompareAndSwapError {}
+
 impl BTree {
     pub fn new(path: &str, storage_config: Option<StorageConfig>) -> std::io::Result<Self> {
         let storage_config = storage_config.unwrap_or_default();
+        // the top bit of a leaf entry's value length marks an expiry, so values must stay below it
+        if storage_config.max_val_size >= VAL_HAS_EXPIRY {
+            let message = format!("max_val_size is {}, larger than the maximum of {}", storage_config.max_val_size, VAL_HAS_EXPIRY - 1);
+            return Err(Error::new(ErrorKind::InvalidInput, message));
+        }
         let mut disk_manager = DiskManager::new(path, storage_config.clone())?;
         // load in root node
-        let root_offset = disk_manager.read_metadata()?;
-        let root = disk_manager.load_node_from_disk(root_offset)?;
+        let metadata = disk_manager.read_metadata()?;
+        let root = disk_manager.load_node_from_disk(metadata.root_offset)?;
 
-        Ok(Self {
+        let mut btree = Self {
             root,
-            root_offset,
+            root_offset: metadata.root_offset,
+            catalog_offset: metadata.catalog_offset,
+            commit_id: metadata.commit_id,
+            history_offset: metadata.history_offset,
+            commit_timestamp: metadata.timestamp,
             storage_config,
-            disk_manager
-        })
+            disk_manager,
+            merge_operators: HashMap::new(),
+            retention: Retention::default(),
+            pins: vec![],
+        };
+        for (name, operator) in merge::builtin_operators() {
+            btree.register_merge_operator(name, operator);
+        }
+        btree.rebuild_free_list()?;
+        Ok(btree)
+    }
+
+    /// Look up the value stored for key
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_from_tree(self.root_offset, key)
+    }
+
+    /// Iterate over the key/value pairs within range in key order
+    pub fn range<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> RangeIter<'_> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        RangeIter::new(&mut self.disk_manager, self.root.clone(), start, end)
+    }
+
+    /// Differences going from the tree rooted at root_a to the tree rooted at root_b, in key order
+    /// Roots can come from the history, a snapshot or a bucket, subtrees both share are skipped
+    pub fn diff(&mut self, root_a: u64, root_b: u64) -> Result<DiffIter<'_>> {
+        let height_a = self.tree_height(root_a)?;
+        let height_b = self.tree_height(root_b)?;
+        Ok(DiffIter::new(&mut self.disk_manager, (root_a, height_a), (root_b, height_b)))
+    }
+
+    /// Insert or overwrite key, returns the value it held before
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.put(&key, value, 0, PutMode::Upsert)
+    }
+
+    /// Insert or overwrite key so that it expires after ttl, returns the value it held before
+    /// Expired keys are hidden from reads and physically removed by sweep_expired
+    pub fn insert_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<Option<Vec<u8>>> {
+        // expiry 0 means never, so an expiry always lands at 1 or later
+        let expiry = now_millis().saturating_add(ttl.as_millis() as u64).max(1);
+        self.put(&key, value, expiry, PutMode::Upsert)
+    }
+
+    /// Insert key only if it isn't already in the tree, returns whether it was inserted
+    pub fn insert_new(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        Ok(self.put(&key, value, 0, PutMode::InsertNew)?.is_none())
+    }
+
+    /// Overwrite key only if it is already in the tree, returns the value it held
+    /// None means the key was absent and nothing was written
+    pub fn replace(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.put(&key, value, 0, PutMode::Replace)
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>, expiry: u64, mode: PutMode) -> Result<Option<Vec<u8>>> {
+        // modify copies of the nodes on the path so insert is durable
+        match self.put_in_tree(self.root_offset, key, value, expiry, mode) {
+            Ok((Some(new_root_offset), previous)) => {
+                // commit transaction by changing root pointer to new root offset
+                self.commit(new_root_offset, self.catalog_offset)?;
+                Ok(previous)
+            }
+            Ok((None, previous)) => Ok(previous),
+            Err(e) => {
+                self.disk_manager.rollback_pages();
+                Err(e)
+            }
+        }
+    }
+
+    /// Delete key from the tree, returns the value it held
+    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // modify copies of the nodes on the path so delete is durable
+        match self.delete_from_tree(self.root_offset, key) {
+            Ok(Some((new_root_offset, removed))) => {
+                self.commit(new_root_offset, self.catalog_offset)?;
+                Ok(Some(removed))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                self.disk_manager.rollback_pages();
+                Err(e)
+            }
+        }
+    }
+
+    /// Atomically replace the value of key, but only if it currently holds expected
+    /// expected None means the key must be absent, new None deletes the key
+    /// On mismatch nothing is written and the current value is returned in the error
+    pub fn compare_and_swap(&mut self, key: &[u8], expected: Option<&[u8]>, new: Option<Vec<u8>>) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        match self.compare_and_swap_in_tree(self.root_offset, key, expected, new) {
+            Ok((Some(new_root_offset), result)) => {
+                self.commit(new_root_offset, self.catalog_offset)?;
+                Ok(result)
+            }
+            Ok((None, result)) => Ok(result),
+            Err(e) => {
+                self.disk_manager.rollback_pages();
+                Err(e)
+            }
+        }
+    }
+
+    /// Register a merge operator under name, replacing any operator already registered with that name
+    pub fn register_merge_operator(&mut self, name: &str, operator: MergeOperator) {
+        self.merge_operators.insert(name.to_string(), operator);
+    }
+
+    /// Combine operand with the current value of key using the named merge operator
+    /// The operator runs on the value found in the leaf, so the read and the write are one descent
+    /// Returns the merged value
+    pub fn merge(&mut self, operator: &str, key: &[u8], operand: &[u8]) -> Result<Vec<u8>> {
+        // take the operator out while the tree is borrowed mutably
+        let merge_fn = self.merge_operators.remove(operator).ok_or_else(|| {
+            Error::new(ErrorKind::NotFound, format!("merge operator {} is not registered", operator))
+        })?;
+        let result = self.merge_in_tree(self.root_offset, key, operand, &merge_fn);
+        self.merge_operators.insert(operator.to_string(), merge_fn);
+
+        match result {
+            Ok((new_root_offset, merged)) => {
+                self.commit(new_root_offset, self.catalog_offset)?;
+                Ok(merged)
+            }
+            Err(e) => {
+                self.disk_manager.rollback_pages();
+                Err(e)
+            }
+        }
+    }
+
+    /// Delete every expired key through the copy-on-write delete path, returns how many were removed
+    /// All removals are committed together
+    pub fn sweep_expired(&mut self) -> Result<usize> {
+        let mut expired = vec![];
+        let root = self.root.clone();
+        let height = self.tree_height(self.root_offset)?;
+        self.collect_expired(root, height, now_millis(), &mut expired)?;
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        let mut root_offset = self.root_offset;
+        let mut removed = 0;
+        for key in &expired {
+            // only entries that exist but read as absent are removed, anything rewritten since stays
+            let result = self.update_tree(root_offset, key, &mut |current| match current {
+                None => LeafUpdate::Remove,
+                Some(_) => LeafUpdate::Keep,
+            });
+            match result {
+                Ok(Some(new_root_offset)) => {
+                    root_offset = new_root_offset;
+                    removed += 1;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    self.disk_manager.rollback_pages();
+                    return Err(e);
+                }
+            }
+        }
+        if removed > 0 {
+            self.commit(root_offset, self.catalog_offset)?;
+        }
+        Ok(removed)
+    }
+
+    /// Delete every key within range
+    /// Subtrees that lie entirely inside the range are unlinked whole and their pages freed,
+    /// only the leaves on the range boundaries are read and rewritten
+    pub fn delete_range<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> Result<()> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        match self.delete_range_from_tree(self.root_offset, &start, &end) {
+            Ok(Some(new_root_offset)) => self.commit(new_root_offset, self.catalog_offset),
+            Ok(None) => Ok(()),
+            Err(e) => {
+                self.disk_manager.rollback_pages();
+                Err(e)
+            }
+        }
+    }
+
+    /// Iterate over every key starting with prefix in key order
+    pub fn scan_prefix(&mut self, prefix: &[u8]) -> RangeIter<'_> {
+        let start = Bound::Included(prefix.to_vec());
+        RangeIter::new(&mut self.disk_manager, self.root.clone(), start, prefix_end(prefix))
+    }
+
+    /// Delete every key starting with prefix
+    /// Subtrees that only hold matching keys are unlinked whole without being read
+    pub fn delete_prefix(&mut self, prefix: &[u8]) -> Result<()> {
+        self.delete_range((Bound::Included(prefix.to_vec()), prefix_end(prefix)))
+    }
+
+    /// Start a transaction, its writes to buckets become visible together on commit
+    pub fn begin(&mut self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Create an empty bucket, fails if a bucket with that name already exists
+    pub fn create_bucket(&mut self, name: &str) -> Result<()> {
+        let mut tx = self.begin();
+        tx.create_bucket(name)?;
+        tx.commit()
+    }
+
+    /// Drop a bucket and everything in it
+    pub fn drop_bucket(&mut self, name: &str) -> Result<()> {
+        let mut tx = self.begin();
+        tx.drop_bucket(name)?;
+        tx.commit()
+    }
+
+    /// Open an existing bucket, every write through the bucket commits on its own
+    pub fn open_bucket(&mut self, name: &str) -> Result<Bucket<'_>> {
+        if self.bucket_root(name)?.is_none() {
+            return Err(bucket::not_found(name));
+        }
+        Ok(Bucket::new(self, name))
+    }
+
+    /// Names of all buckets in the file, in sorted order
+    pub fn list_buckets(&mut self) -> Result<Vec<String>> {
+        self.list_buckets_in(self.catalog_offset)
+    }
+
+    /// Set how many old commits stay readable, takes effect from the next commit
+    /// Retaining commits keeps the pages they refer to from being reused, so the file grows
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+    }
+
+    /// Commits that can still be read, oldest first
+    /// Empty if only the current commit is retained
+    pub fn history(&mut self) -> Result<Vec<CommitInfo>> {
+        self.commits_in(self.history_offset)
+    }
+
+    /// Pin the current commit under name, its pages are not reused until the snapshot is dropped
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        let key = CommitInfo::snapshot_key(name);
+        if self.get_from_tree(self.history_offset, &key)?.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("snapshot {} already exists", name)));
+        }
+        let info = match self.get_from_tree(self.history_offset, &CommitInfo::key(self.commit_id))? {
+            Some(value) => CommitInfo::decode(&CommitInfo::key(self.commit_id), &value)?,
+            None => CommitInfo { timestamp: now_millis(), ..self.current_commit() },
+        };
+
+        match self.insert_into_tree(self.history_offset, &key, &info.encode_snapshot()) {
+            Ok((history_offset, _)) => {
+                self.commit_with_history(self.root_offset, self.catalog_offset, history_offset)?;
+                self.update_snapshot_pages()
+            }
+            Err(e) => {
+                self.disk_manager.rollback_pages();
+                Err(e)
+            }
+        }
+    }
+
+    /// Named snapshots and the commits they pin, in name order
+    pub fn list_snapshots(&mut self) -> Result<Vec<(String, CommitInfo)>> {
+        let (start, end) = CommitInfo::snapshot_key_range();
+        let root = self.load_root(self.history_offset)?;
+        RangeIter::new(&mut self.disk_manager, root, start, end)
+            .map(|entry| entry.and_then(|(key, value)| CommitInfo::decode_snapshot(&key, &value)))
+            .collect()
+    }
+
+    /// Read-only view of the trees as of a named snapshot
+    pub fn open_snapshot(&mut self, name: &str) -> Result<Snapshot<'_>> {
+        let info = self.snapshot_info(name)?;
+        Ok(Snapshot::new(self, info))
+    }
+
+    /// Remove a named snapshot, pages only it referred to become reusable
+    pub fn drop_snapshot(&mut self, name: &str) -> Result<()> {
+        match self.delete_from_tree(self.history_offset, &CommitInfo::snapshot_key(name)) {
+            Ok(Some((history_offset, _))) => {
+                self.commit_with_history(self.root_offset, self.catalog_offset, history_offset)?;
+                self.update_snapshot_pages()
+            }
+            Ok(None) => Err(snapshot_not_found(name)),
+            Err(e) => {
+                self.disk_manager.rollback_pages();
+                Err(e)
+            }
+        }
+    }
+
+    /// Make a named snapshot the current state of every tree again
+    /// The snapshot itself is kept, pages only the replaced trees referred to are freed
+    pub fn restore_snapshot(&mut self, name: &str) -> Result<()> {
+        let info = self.snapshot_info(name)?;
+
+        let mut restored = HashSet::new();
+        self.collect_commit_pages(info.root_offset, info.catalog_offset, &mut restored)?;
+        let mut current = HashSet::new();
+        self.collect_commit_pages(self.root_offset, self.catalog_offset, &mut current)?;
+        for &page in current.difference(&restored) {
+            self.disk_manager.free_page(page);
+        }
+        // pages the restored trees share with unlinked ones are live again and must not be handed out
+        self.disk_manager.keep_pages(&restored);
+
+        self.commit(info.root_offset, info.catalog_offset)
+    }
+
+    fn snapshot_info(&mut self, name: &str) -> Result<CommitInfo> {
+        let key = CommitInfo::snapshot_key(name);
+        match self.get_from_tree(self.history_offset, &key)? {
+            Some(value) => Ok(CommitInfo::decode_snapshot(&key, &value)?.1),
+            None => Err(snapshot_not_found(name)),
+        }
+    }
+
+    /// Commits recorded in the history tree rooted at history_offset, oldest first
+    pub(crate) fn commits_in(&mut self, history_offset: u64) -> Result<Vec<CommitInfo>> {
+        let (start, end) = CommitInfo::key_range();
+        let root = self.load_root(history_offset)?;
+        RangeIter::new(&mut self.disk_manager, root, start, end)
+            .map(|entry| entry.and_then(|(key, value)| CommitInfo::decode(&key, &value)))
+            .collect()
+    }
+
+    /// Read-only view of the trees as of commit_id
+    pub fn read_at(&mut self, commit_id: u64) -> Result<Snapshot<'_>> {
+        let info = self.retained_commit(commit_id)?;
+        Ok(Snapshot::new(self, info))
+    }
+
+    /// Roots of a commit that is still readable, through the history, a named snapshot or as the current one
+    fn retained_commit(&mut self, commit_id: u64) -> Result<CommitInfo> {
+        if let Some(value) = self.get_from_tree(self.history_offset, &CommitInfo::key(commit_id))? {
+            return CommitInfo::decode(&CommitInfo::key(commit_id), &value);
+        }
+        if commit_id == self.commit_id {
+            return Ok(self.current_commit());
+        }
+        match self.list_snapshots()?.into_iter().find(|(_, info)| info.commit_id == commit_id) {
+            Some((_, info)) => Ok(info),
+            None => Err(Error::new(ErrorKind::NotFound, format!("commit {} is not retained", commit_id))),
+        }
+    }
+
+    /// Read-only view of the trees as of the last commit made at or before time
+    /// With no history retained the current commit is the only one that can be returned, as long
+    /// as it was made by then. Its time is kept in the metadata page when that has room for it,
+    /// on pages under 40 bytes it is unknown and the current commit is returned for any time
+    pub fn read_as_of(&mut self, time: SystemTime) -> Result<Snapshot<'_>> {
+        let millis = time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let history = self.history()?;
+        let info = match history.iter().rev().find(|info| info.timestamp <= millis) {
+            Some(info) => *info,
+            None if history.is_empty() && self.commit_timestamp <= millis => self.current_commit(),
+            None => {
+                return Err(Error::new(ErrorKind::NotFound, "no retained commit is that old"));
+            }
+        };
+        Ok(Snapshot::new(self, info))
+    }
+
+    /// Start copying the current commit into a new compact file at path
+    /// The copy advances with Backup::step, other writes can be committed in between
+    pub fn begin_backup(&mut self, path: &str) -> Result<Backup> {
+        Backup::new(self, path)
+    }
+
+    /// Copy the current commit into a new compact file at path in one go
+    pub fn backup_to(&mut self, path: &str) -> Result<()> {
+        let mut backup = self.begin_backup(path)?;
+        match backup.step(self, usize::MAX) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                backup.abort(self);
+                Err(e)
+            }
+        }
+    }
+
+    /// Write the pages committed since since_commit to a new file at path, returns how many were written
+    /// since_commit has to be retained, restoring needs a copy of it to apply the pages onto
+    pub fn incremental_backup(&mut self, since_commit: u64, path: &str) -> Result<usize> {
+        let since = self.retained_commit(since_commit)?;
+        let incremental = Incremental::capture(self, since, self.current_commit())?;
+        incremental.write(path, &self.disk_manager)?;
+        Ok(incremental.page_count())
+    }
+
+    /// Rebuild a database at path from a full backup and the incremental backups taken after it, oldest first
+    /// Each incremental has to start at the commit the one before it ended at
+    pub fn restore(base: &str, chain: &[&str], path: &str, storage_config: Option<StorageConfig>) -> Result<Self> {
+        if Path::new(path).exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", path)));
+        }
+        fs::copy(base, path)?;
+        let mut btree = Self::new(path, storage_config)?;
+        for incremental in chain {
+            let incremental = Incremental::read(incremental, btree.storage_config.page_size)?;
+            incremental.apply(&mut btree.disk_manager)?;
+            // reopened so the pages the incremental replaced are free for the next one
+            btree = Self::new(path, Some(btree.storage_config.clone()))?;
+        }
+        Ok(btree)
+    }
+
+    /// Fill the empty main tree from entries in increasing key order, returns how many were loaded
+    /// Pages are built bottom up and packed as full as they go, with a single commit at the end
+    pub fn bulk_load<I>(&mut self, entries: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    {
+        if !self.root.keys.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "bulk load needs an empty tree"));
+        }
+        match self.build_tree(&mut entries.into_iter()) {
+            Ok((new_root_offset, count)) => {
+                self.disk_manager.free_page(self.root_offset);
+                self.commit(new_root_offset, self.catalog_offset)?;
+                Ok(count)
+            }
+            Err(e) => {
+                self.disk_manager.rollback_pages();
+                Err(e)
+            }
+        }
+    }
+
+    /// Write a new tree holding entries, which have to be in increasing key order
+    /// returns the offset of its root and the number of entries, nothing is visible until it is committed
+    pub(crate) fn build_tree(&mut self, entries: &mut dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>) -> Result<(u64, usize)> {
+        let page_size = self.storage_config.page_size as usize;
+        // first key and offset of every page of the level being built
+        let mut level = vec![];
+        let mut leaf = empty_node();
+        let mut count = 0;
+        for entry in entries {
+            let (key, value) = entry?;
+            if leaf.keys.last().is_some_and(|last| *last >= key) {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("bulk load needs increasing keys, entry {} is out of order", count + 1)));
+            }
+            if key.len() > self.storage_config.max_key_size as usize {
+                let message = format!("entry {}: key is {} bytes, longer than the maximum of {}", count + 1, key.len(), self.storage_config.max_key_size);
+                return Err(Error::new(ErrorKind::InvalidInput, message));
+            }
+            if value.len() > self.storage_config.max_val_size as usize {
+                let message = format!("entry {}: value is {} bytes, longer than the maximum of {}", count + 1, value.len(), self.storage_config.max_val_size);
+                return Err(Error::new(ErrorKind::InvalidInput, message));
+            }
+            leaf.keys.push(key);
+            leaf.values.push(value);
+            if leaf.encoded_size() > page_size {
+                // the entry goes first on a new leaf, unless it doesn't fit a page on its own
+                let (key, value) = (leaf.keys.pop().unwrap(), leaf.values.pop().unwrap());
+                if leaf.keys.is_empty() {
+                    return Err(Error::new(ErrorKind::InvalidInput, format!("entry {} is too large for a page", count + 1)));
+                }
+                level.push(self.write_built(leaf)?);
+                leaf = empty_node();
+                leaf.keys.push(key);
+                leaf.values.push(value);
+            }
+            count += 1;
+        }
+        if !leaf.keys.is_empty() || level.is_empty() {
+            level.push(self.write_built(leaf)?);
+        }
+
+        while level.len() > 1 {
+            let mut parents: Vec<(Vec<u8>, Node)> = vec![];
+            for (first_key, offset) in level {
+                if let Some((_, parent)) = parents.last_mut() {
+                    parent.keys.push(first_key.clone());
+                    parent.children.push(offset);
+                    if parent.encoded_size() <= page_size {
+                        continue;
+                    }
+                    parent.keys.pop();
+                    parent.children.pop();
+                }
+                let mut parent = empty_node();
+                parent.children.push(offset);
+                parents.push((first_key, parent));
+            }
+            // an internal node needs two children, so the last one borrows from the one before it
+            if let [.., (_, previous), (last_key, last)] = parents.as_mut_slice()
+                && last.children.len() == 1
+            {
+                let separator = previous.keys.pop().unwrap();
+                last.children.insert(0, previous.children.pop().unwrap());
+                last.keys.insert(0, std::mem::replace(last_key, separator));
+            }
+            level = vec![];
+            for (first_key, parent) in parents {
+                level.push((first_key, self.write_built(parent)?.1));
+            }
+        }
+        Ok((level[0].1, count))
+    }
+
+    /// Write a node built by build_tree to a new page, returns its first key and offset
+    fn write_built(&mut self, node: Node) -> Result<(Vec<u8>, u64)> {
+        let offset = self.disk_manager.get_new_offset()?;
+        self.disk_manager.write_node(offset, &node)?;
+        Ok((node.keys.first().cloned().unwrap_or_default(), offset))
+    }
+
+    /// Height, page counts and fill factors of the main tree, along with the size of the file and
+    /// how many of its pages are dead, see TreeStats
+    pub fn stats(&mut self) -> Result<TreeStats> {
+        stats::collect(self)
+    }
+
+    /// Graphviz source for the main tree down to max_depth, the root is at depth 0
+    /// Each page is a box with its offset and its first keys, escaped and cut short
+    pub fn to_dot(&mut self, max_depth: usize) -> Result<String> {
+        dot::render(self, max_depth)
+    }
+
+    /// Names of the buckets in the catalog rooted at catalog_offset, in sorted order
+    pub(crate) fn list_buckets_in(&mut self, catalog_offset: u64) -> Result<Vec<String>> {
+        let root = self.load_root(catalog_offset)?;
+        RangeIter::new(&mut self.disk_manager, root, Bound::Unbounded, Bound::Unbounded)
+            .map(|entry| entry.and_then(|(name, _)| bucket::decode_name(&name)))
+            .collect()
+    }
+
+    fn current_commit(&self) -> CommitInfo {
+        CommitInfo {
+            commit_id: self.commit_id,
+            timestamp: self.commit_timestamp,
+            root_offset: self.root_offset,
+            catalog_offset: self.catalog_offset,
+        }
+    }
+
+    /// Root offset of a bucket as of the last commit
+    pub(crate) fn bucket_root(&mut self, name: &str) -> Result<Option<u64>> {
+        match self.get_from_tree(self.catalog_offset, name.as_bytes())? {
+            Some(value) => Ok(Some(bucket::decode_root(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Make new roots durable with a single metadata write
+    pub(crate) fn commit(&mut self, root_offset: u64, catalog_offset: u64) -> Result<()> {
+        self.commit_with_history(root_offset, catalog_offset, self.history_offset)
+    }
+
+    /// Commit with a history tree that was changed along with the other roots
+    fn commit_with_history(&mut self, root_offset: u64, catalog_offset: u64, history_offset: u64) -> Result<()> {
+        let commit_id = self.commit_id + 1;
+        let timestamp = now_millis();
+        let history_offset = self.record_commit(history_offset, CommitInfo {
+            commit_id,
+            timestamp,
+            root_offset,
+            catalog_offset,
+        })?;
+
+        let metadata = Metadata {
+            root_offset,
+            catalog_offset,
+            commit_id,
+            history_offset,
+            timestamp,
+        };
+        self.disk_manager.write_metadata(&metadata)?;
+        if root_offset != self.root_offset {
+            self.root = self.disk_manager.load_node_from_disk(root_offset)?;
+        }
+        self.root_offset = root_offset;
+        self.catalog_offset = catalog_offset;
+        self.commit_id = commit_id;
+        self.history_offset = history_offset;
+        self.commit_timestamp = timestamp;
+
+        let oldest_retained = self.oldest_retained()?;
+        self.disk_manager.commit_pages(commit_id, oldest_retained);
+        Ok(())
+    }
+
+    /// Add a commit to the history tree and drop the commits that fall out of the retention policy
+    /// named snapshots are left alone, returns the offset of the new history root
+    fn record_commit(&mut self, mut history_offset: u64, info: CommitInfo) -> Result<u64> {
+        let (start, end) = CommitInfo::key_range();
+        let end = match self.retention {
+            // nothing but the current commit is kept, so there is no history to record
+            Retention::KeepLast(n) if n <= 1 => end,
+            retention => {
+                let oldest_kept = match retention {
+                    Retention::KeepFor(duration) => {
+                        let cutoff = info.timestamp.saturating_sub(duration.as_millis() as u64);
+                        self.commits_in(history_offset)?
+                            .into_iter()
+                            .find(|commit| commit.timestamp >= cutoff)
+                            .map_or(info.commit_id, |commit| commit.commit_id)
+                    }
+                    Retention::KeepLast(n) => info.commit_id.saturating_sub(n - 1),
+                };
+                history_offset = self.insert_into_tree(history_offset, &CommitInfo::key(info.commit_id), &info.encode_value())?.0;
+                Bound::Excluded(CommitInfo::key(oldest_kept))
+            }
+        };
+
+        if let Some(new_history_offset) = self.delete_range_from_tree(history_offset, &start, &end)? {
+            history_offset = new_history_offset;
+        }
+
+        // an empty history tree is dropped rather than kept around as an empty page
+        if history_offset != 0 && self.load_root(history_offset)?.keys.is_empty() {
+            self.disk_manager.free_page(history_offset);
+            history_offset = 0;
+        }
+        Ok(history_offset)
+    }
+
+    /// Id of the oldest commit that can still be read through the history or a running backup
+    /// named snapshots don't count, only their own pages are kept, see update_snapshot_pages
+    fn oldest_retained(&mut self) -> Result<u64> {
+        let oldest_commit = self.history()?.first().map_or(self.commit_id, |info| info.commit_id);
+        let oldest_pin = self.pins.iter().copied().min();
+        Ok(oldest_pin.map_or(oldest_commit, |pin| pin.min(oldest_commit)))
+    }
+
+    /// Every page the commits of named snapshots refer to
+    fn snapshot_pages(&mut self) -> Result<HashSet<u64>> {
+        let mut pages = HashSet::new();
+        for (_, info) in self.list_snapshots()? {
+            self.collect_commit_pages(info.root_offset, info.catalog_offset, &mut pages)?;
+        }
+        Ok(pages)
+    }
+
+    /// Tell the disk manager which pages named snapshots pin, after a snapshot was created or dropped
+    fn update_snapshot_pages(&mut self) -> Result<()> {
+        let pages = self.snapshot_pages()?;
+        self.disk_manager.set_snapshot_pages(pages);
+        Ok(())
+    }
+
+    /// Keep the pages of a commit from being reused until it is unpinned
+    pub(crate) fn pin(&mut self, commit_id: u64) {
+        self.pins.push(commit_id);
+    }
+
+    pub(crate) fn unpin(&mut self, commit_id: u64) {
+        if let Some(pos) = self.pins.iter().position(|&pin| pin == commit_id) {
+            self.pins.swap_remove(pos);
+        }
+    }
+
+    /// Load the root node of a tree, offset 0 stands for a tree that hasn't been written yet
+    pub(crate) fn load_root(&mut self, root_offset: u64) -> Result<Node> {
+        if root_offset == self.root_offset {
+            return Ok(self.root.clone());
+        }
+        if root_offset == 0 {
+            return Ok(empty_node());
+        }
+        self.disk_manager.load_node_from_disk(root_offset)
+    }
+
+    /// Every page reachable from the current commit
+    pub(crate) fn reachable_pages(&mut self) -> Result<HashSet<u64>> {
+        let mut pages = HashSet::new();
+        self.collect_commit_pages(self.root_offset, self.catalog_offset, &mut pages)?;
+        self.collect_tree_pages(self.history_offset, &mut pages)?;
+        Ok(pages)
+    }
+
+    /// Every page slot in the file that isn't reachable from a retained commit or a named snapshot is free
+    /// pages only older commits refer to are retired until those commits fall out of the history,
+    /// pages only named snapshots refer to are held until the snapshots are dropped
+    fn rebuild_free_list(&mut self) -> Result<()> {
+        let mut reachable = self.reachable_pages()?;
+        let retained = self.history()?;
+
+        let mut retired = vec![];
+        // newest first, so each page is tagged after the last commit that refers to it
+        for info in retained.into_iter().rev() {
+            if info.commit_id == self.commit_id {
+                continue;
+            }
+            let mut pages = HashSet::new();
+            self.collect_commit_pages(info.root_offset, info.catalog_offset, &mut pages)?;
+            for page in pages {
+                if reachable.insert(page) {
+                    retired.push((page, info.commit_id + 1));
+                }
+            }
+        }
+
+        let snapshot_pages = self.snapshot_pages()?;
+        let held = snapshot_pages.iter().copied().filter(|offset| !reachable.contains(offset)).collect();
+        let free_pages = self.disk_manager.page_offsets()?
+            .into_iter()
+            .filter(|offset| !reachable.contains(offset) && !snapshot_pages.contains(offset))
+            .collect();
+        self.disk_manager.set_free_pages(free_pages, retired, held);
+        self.disk_manager.set_snapshot_pages(snapshot_pages);
+        Ok(())
+    }
+
+    /// Add the pages of the main tree, the catalog and every bucket of one commit to pages
+    fn collect_commit_pages(&mut self, root_offset: u64, catalog_offset: u64, pages: &mut HashSet<u64>) -> Result<()> {
+        self.collect_tree_pages(root_offset, pages)?;
+        self.collect_tree_pages(catalog_offset, pages)?;
+        for name in self.list_buckets_in(catalog_offset)? {
+            if let Some(value) = self.get_from_tree(catalog_offset, name.as_bytes())? {
+                self.collect_tree_pages(bucket::decode_root(&value)?, pages)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the pages of the tree rooted at root_offset to pages
+    /// leaves are identified by their depth so they never have to be read
+    fn collect_tree_pages(&mut self, root_offset: u64, pages: &mut HashSet<u64>) -> Result<()> {
+        if root_offset == 0 {
+            return Ok(());
+        }
+        let height = self.tree_height(root_offset)?;
+        self.collect_subtree_pages(root_offset, height, pages)
+    }
+
+    fn collect_subtree_pages(&mut self, offset: u64, height: usize, pages: &mut HashSet<u64>) -> Result<()> {
+        // a page shared between trees only needs to be walked once
+        if !pages.insert(offset) || height == 0 {
+            return Ok(());
+        }
+        let node = self.disk_manager.load_node_from_disk(offset)?;
+        for child_offset in node.children {
+            self.collect_subtree_pages(child_offset, height - 1, pages)?;
+        }
+        Ok(())
+    }
+
+    /// Number of levels below the root, 0 if the root is a leaf
+    pub(crate) fn tree_height(&mut self, root_offset: u64) -> Result<usize> {
+        let mut node = self.load_root(root_offset)?;
+        let mut height = 0;
+        while !node.children.is_empty() {
+            node = self.disk_manager.load_node_from_disk(node.children[0])?;
+            height += 1;
+        }
+        Ok(height)
+    }
+
+    /// Free every page of a subtree that is being unlinked, height is the subtree's height
+    pub(crate) fn free_subtree(&mut self, offset: u64, height: usize) -> Result<()> {
+        if height > 0 {
+            let node = self.disk_manager.load_node_from_disk(offset)?;
+            for child_offset in node.children {
+                self.free_subtree(child_offset, height - 1)?;
+            }
+        }
+        self.disk_manager.free_page(offset);
+        Ok(())
+    }
+
+    /// Look up key in the tree rooted at root_offset
+    pub(crate) fn get_from_tree(&mut self, root_offset: u64, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut node = self.load_root(root_offset)?;
+        while !node.children.is_empty() {
+            let child_offset = node.children[node.child_index(key)];
+            node = self.disk_manager.load_node_from_disk(child_offset)?;
+        }
+
+        match node.find_key(key) {
+            Ok(pos) if !node.is_expired(pos, now_millis()) => Ok(Some(node.values.swap_remove(pos))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Copy-on-write insert into the tree with the given root
+    /// returns the offset of the new root and the value key held before
+    /// nothing is visible until the new root is committed
+    pub(crate) fn insert_into_tree(&mut self, root_offset: u64, key: &[u8], value: &[u8]) -> Result<(u64, Option<Vec<u8>>)> {
+        let (new_root_offset, previous) = self.put_in_tree(root_offset, key, value.to_vec(), 0, PutMode::Upsert)?;
+        // an upsert always rewrites the path
+        Ok((new_root_offset.unwrap(), previous))
+    }
+
+    /// Copy-on-write put into the tree with the given root, restricted by mode
+    /// returns the offset of the new root if anything was written, and the value key held before
+    pub(crate) fn put_in_tree(&mut self, root_offset: u64, key: &[u8], value: Vec<u8>, expiry: u64, mode: PutMode) -> Result<(Option<u64>, Option<Vec<u8>>)> {
+        self.check_entry_size(key, &value)?;
+        let mut value = Some(value);
+        let mut previous = None;
+        let new_root_offset = self.update_tree(root_offset, key, &mut |current| {
+            previous = current.map(|v| v.to_vec());
+            match (mode, current) {
+                (PutMode::InsertNew, Some(_)) | (PutMode::Replace, None) => LeafUpdate::Keep,
+                _ => LeafUpdate::Put(value.take().unwrap(), expiry),
+            }
+        })?;
+        Ok((new_root_offset, previous))
+    }
+
+    /// Refuse an entry longer than the configured limits before any page is touched
+    fn check_entry_size(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.len() > self.storage_config.max_key_size as usize {
+            let message = format!("key is {} bytes, longer than the maximum of {}", key.len(), self.storage_config.max_key_size);
+            return Err(Error::new(ErrorKind::InvalidInput, message));
+        }
+        if value.len() > self.storage_config.max_val_size as usize {
+            let message = format!("value is {} bytes, longer than the maximum of {}", value.len(), self.storage_config.max_val_size);
+            return Err(Error::new(ErrorKind::InvalidInput, message));
+        }
+        Ok(())
+    }
+
+    /// Copy-on-write delete from the tree with the given root
+    /// returns the offset of the new root and the removed value, or None if key wasn't found
+    pub(crate) fn delete_from_tree(&mut self, root_offset: u64, key: &[u8]) -> Result<Option<(u64, Vec<u8>)>> {
+        let mut removed = None;
+        let new_root_offset = self.update_tree(root_offset, key, &mut |current| match current {
+            Some(value) => {
+                removed = Some(value.to_vec());
+                LeafUpdate::Remove
+            }
+            None => LeafUpdate::Keep,
+        })?;
+        Ok(new_root_offset.map(|offset| (offset, removed.unwrap())))
+    }
+
+    /// Copy-on-write compare and swap in the tree with the given root
+    /// returns the offset of the new root if anything was written, along with the outcome
+    pub(crate) fn compare_and_swap_in_tree(
+        &mut self,
+        root_offset: u64,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<(Option<u64>, std::result::Result<(), CompareAndSwapError>)> {
+        if let Some(value) = &new {
+            self.check_entry_size(key, value)?;
+        }
+        let mut new = new;
+        let mut outcome = Ok(());
+        let new_root_offset = self.update_tree(root_offset, key, &mut |current| {
+            if current != expected {
+                outcome = Err(CompareAndSwapError { current: current.map(|v| v.to_vec()) });
+                return LeafUpdate::Keep;
+            }
+            match new.take() {
+                Some(value) => LeafUpdate::Put(value, 0),
+                None if current.is_some() => LeafUpdate::Remove,
+                None => LeafUpdate::Keep,
+            }
+        })?;
+        Ok((new_root_offset, outcome))
+    }
+
+    /// Copy-on-write merge in the tree with the given root
+    /// returns the offset of the new root and the merged value
+    fn merge_in_tree(&mut self, root_offset: u64, key: &[u8], operand: &[u8], merge_fn: &MergeOperator) -> Result<(u64, Vec<u8>)> {
+        let mut merged = None;
+        let max_val_size = self.storage_config.max_val_size as usize;
+        let new_root_offset = self.update_tree(root_offset, key, &mut |current| {
+            let result = merge_fn(current, operand).and_then(|value| match value.len() > max_val_size {
+                true => Err(Error::new(ErrorKind::InvalidInput, format!("merged value is {} bytes, longer than the maximum of {}", value.len(), max_val_size))),
+                false => Ok(value),
+            });
+            let update = match &result {
+                Ok(value) => LeafUpdate::Put(value.clone(), 0),
+                Err(_) => LeafUpdate::Keep,
+            };
+            merged = Some(result);
+            update
+        })?;
+        // the descent always reaches a leaf
+        let merged = merged.unwrap()?;
+        // Put always rewrites the path, so there is always a new root
+        Ok((new_root_offset.unwrap(), merged))
+    }
+
+    /// Gather the keys of expired entries in the subtree rooted at node
+    /// height is the number of levels below node
+    fn collect_expired(&mut self, node: Node, height: usize, now: u64, expired: &mut Vec<Vec<u8>>) -> Result<()> {
+        if height == 0 {
+            for (pos, key) in node.keys.iter().enumerate() {
+                if node.is_expired(pos, now) {
+                    expired.push(key.clone());
+                }
+            }
+            return Ok(());
+        }
+        for child_offset in node.children {
+            let child = self.disk_manager.load_node_from_disk(child_offset)?;
+            self.collect_expired(child, height - 1, now, expired)?;
+        }
+        Ok(())
+    }
+
+    /// Copy-on-write update of a single key in the tree with the given root
+    /// decide is called with the key's current value once the descent reaches its leaf,
+    /// so the check and the write happen in the same pass
+    /// returns the offset of the new root, or None if decide chose to keep the tree as is
+    pub(crate) fn update_tree(&mut self, root_offset: u64, key: &[u8], decide: &mut dyn FnMut(Option<&[u8]>) -> LeafUpdate) -> Result<Option<u64>> {
+        let mut root = self.load_root(root_offset)?;
+        if !self.update_recursive(&mut root, key, decide)? {
+            return Ok(None);
+        }
+        self.disk_manager.free_page(root_offset);
+
+        if root.encoded_size() > self.storage_config.page_size as usize {
+            // create new root case
+            // increases height of tree
+            let splits = self.split_node(&root)?;
+            let new_root = Node {
+                keys: splits.promoted_keys,
+                children: splits.offsets,
+                values: vec![],
+                expiries: vec![],
+            };
+
+            let new_root_offset = self.disk_manager.get_new_offset()?;
+            self.disk_manager.write_node(new_root_offset, &new_root)?;
+            return Ok(Some(new_root_offset));
+        }
+
+        if root.keys.is_empty() && root.children.len() == 1 {
+            // the root lost its last separator, its only child becomes the new root
+            // decreases height of tree
+            return Ok(Some(root.children[0]));
+        }
+
+        let new_root_offset = self.disk_manager.get_new_offset()?;
+        self.disk_manager.write_node(new_root_offset, &root)?;
+        Ok(Some(new_root_offset))
+    }
+
+    /// Copy-on-write delete of every key between start and end from the tree with the given root
+    /// returns the offset of the new root, or None if no key was in range
+    pub(crate) fn delete_range_from_tree(&mut self, root_offset: u64, start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> Result<Option<u64>> {
+        let mut root = self.load_root(root_offset)?;
+        let height = self.tree_height(root_offset)?;
+        if !self.delete_range_recursive(&mut root, height, start, end)? {
+            return Ok(None);
+        }
+        self.disk_manager.free_page(root_offset);
+
+        // whole subtrees may have been unlinked, so the root can lose several levels
+        let mut collapsed_offset = None;
+        while root.keys.is_empty() && root.children.len() == 1 {
+            let child_offset = root.children[0];
+            root = self.disk_manager.load_node_from_disk(child_offset)?;
+            if let Some(skipped_offset) = collapsed_offset.replace(child_offset) {
+                self.disk_manager.free_page(skipped_offset);
+            }
+        }
+        if let Some(offset) = collapsed_offset {
+            return Ok(Some(offset));
+        }
+
+        let new_root_offset = self.disk_manager.get_new_offset()?;
+        self.disk_manager.write_node(new_root_offset, &root)?;
+        Ok(Some(new_root_offset))
+    }
+
+    /// Apply decide to key in the subtree rooted at node
+    /// node is only updated in memory, the caller is responsible for writing it out
+    /// returns whether anything changed
+    fn update_recursive(&mut self, node: &mut Node, key: &[u8], decide: &mut dyn FnMut(Option<&[u8]>) -> LeafUpdate) -> Result<bool> {
+        if node.children.is_empty() {
+            // leaf node
+            return Ok(Self::update_leaf(node, key, decide));
+        }
+
+        // internal node
+        let pos = node.child_index(key);
+        let child_offset = node.children[pos];
+        let bytes = self.disk_manager.read_page(child_offset)?;
+        let mut child_node = match SlottedPage::from_bytes(bytes.clone()) {
+            Ok(mut page) if page.is_leaf() => {
+                let child_node = match Self::update_leaf_page(&mut page, key, decide) {
+                    PageUpdate::Unchanged => return Ok(false),
+                    PageUpdate::Applied => {
+                        // only pages that need no split, merge or drop are written back as they are
+                        let page_size = self.storage_config.page_size as usize;
+                        if page.key_count() > 0 && (page.used_size() >= page_size / 4 || node.children.len() == 1) {
+                            let page_offset = self.disk_manager.get_new_offset()?;
+                            self.disk_manager.write_page(page_offset, page.as_bytes())?;
+                            self.disk_manager.free_page(child_offset);
+                            node.children[pos] = page_offset;
+                            return Ok(true);
+                        }
+                        page.to_node()
+                    }
+                    PageUpdate::NoRoom(update) => {
+                        let mut child_node = page.to_node();
+                        let mut update = Some(update);
+                        Self::update_leaf(&mut child_node, key, &mut |_| update.take().unwrap());
+                        child_node
+                    }
+                };
+                self.disk_manager.free_page(child_offset);
+                self.write_child(node, pos, child_node)?;
+                return Ok(true);
+            }
+            _ => Node::try_decode_node(&bytes)?,
+        };
+        if !self.update_recursive(&mut child_node, key, decide)? {
+            return Ok(false);
+        }
+        self.disk_manager.free_page(child_offset);
+        self.write_child(node, pos, child_node)?;
+        Ok(true)
     }
 
-    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        // modify clone of root so insert is durable
-        let mut root_clone = self.root.clone();
-        let result = self.insert_recursive(&mut root_clone, &key, &value);
+    /// update_leaf on the bytes of a slotted leaf page, without decoding the rest of the page
+    fn update_leaf_page(page: &mut SlottedPage, key: &[u8], decide: &mut dyn FnMut(Option<&[u8]>) -> LeafUpdate) -> PageUpdate {
+        let found = page.find_key(key);
+        let now = now_millis();
+        let current = found.ok().filter(|&slot| {
+            let expiry = page.expiry(slot);
+            expiry == 0 || expiry > now
+        });
+        let update = decide(current.map(|slot| page.value(slot)));
+        let applied = match (&update, found) {
+            (LeafUpdate::Keep, _) | (LeafUpdate::Remove, Err(_)) => return PageUpdate::Unchanged,
+            (LeafUpdate::Put(value, expiry), Ok(slot)) => page.replace_value(slot, value, *expiry),
+            (LeafUpdate::Put(value, expiry), Err(slot)) => page.insert_entry(slot, key, value, *expiry),
+            (LeafUpdate::Remove, Ok(slot)) => {
+                page.remove_entry(slot);
+                true
+            }
+        };
+        if applied { PageUpdate::Applied } else { PageUpdate::NoRoom(update) }
+    }
 
-        match result.splits {
-            None => {
-                // commit transaction by changing root pointer to new root offset
-                self.root_offset = result.new_offset.unwrap();
-                self.disk_manager.write_metadata(self.root_offset).unwrap();
-                self.root = self.disk_manager.load_node_from_disk(self.root_offset).unwrap();
-            }
-            Some(splits) => {
-                // create new root case
-                // increases height of tree
-                let promoted_key = splits.promoted_key;
-                let new_root = Node {
-                    keys: vec![promoted_key],
-                    children: vec![splits.left_offset, splits.right_offset],
-                    values: vec![],
-                };
+    fn update_leaf(node: &mut Node, key: &[u8], decide: &mut dyn FnMut(Option<&[u8]>) -> LeafUpdate) -> bool {
+        let found = node.find_key(key);
+        // expired entries look absent to decide, but can still be removed
+        let now = now_millis();
+        let current = found
+            .ok()
+            .filter(|&pos| !node.is_expired(pos, now))
+            .map(|pos| node.values[pos].as_slice());
 
-                let new_root_offset = self.disk_manager.get_new_offset().unwrap();
-                self.root_offset = new_root_offset;
-                self.disk_manager.append_node_to_disk(self.root_offset, &new_root);
-                self.disk_manager.write_metadata(self.root_offset).unwrap();
-                self.root = self.disk_manager.load_node_from_disk(self.root_offset).unwrap();
+        match (decide(current), found) {
+            (LeafUpdate::Keep, _) | (LeafUpdate::Remove, Err(_)) => return false,
+            (LeafUpdate::Put(value, expiry), Ok(pos)) => {
+                // key already exists, update value
+                node.values[pos] = value;
+                node.expiries[pos] = expiry;
+            }
+            (LeafUpdate::Put(value, expiry), Err(pos)) => {
+                node.keys.insert(pos, key.to_vec());
+                node.values.insert(pos, value);
+                node.expiries.insert(pos, expiry);
+            }
+            (LeafUpdate::Remove, Ok(pos)) => {
+                node.keys.remove(pos);
+                node.values.remove(pos);
+                node.expiries.remove(pos);
             }
         }
+        true
     }
 
-    fn insert_recursive(&mut self, node: &mut Node, key: &Vec<u8>, value: &Vec<u8>) -> InsertResult {
-        if node.children.len() == 0 {
-            // leaf node
-            self.insert_into_leaf(node, &key, &value)
-        } else {
-            // internal node
-            let pos = node.keys.binary_search(&key).unwrap_or_else(|pos| pos);
-            let child_offset = node.children[pos];
-            let mut child_node = self.disk_manager.load_node_from_disk(child_offset).unwrap();
-            let result = self.insert_recursive(&mut child_node, key, value);
-
-            match result.splits {
-                None => {
-                    let new_child_offset = result.new_offset;
-                    node.children[pos] = new_child_offset.unwrap();
-
-                    let new_internal_offset = self.disk_manager.get_new_offset().unwrap();
-                    self.disk_manager.append_node_to_disk(new_internal_offset, &node);
+    /// Write back the child at pos after it changed
+    /// children that no longer fit in a page are split and the keys between the parts are promoted into node
+    fn write_child(&mut self, node: &mut Node, pos: usize, child: Node) -> Result<()> {
+        if child.encoded_size() > self.storage_config.page_size as usize {
+            // The node has overflowed its page size
+            // Split it into nodes that fit and promote the keys between them to the current node
+            let splits = self.split_node(&child)?;
+            node.keys.splice(pos..pos, splits.promoted_keys);
+            node.children.splice(pos..=pos, splits.offsets);
+            return Ok(());
+        }
+        self.rebalance_child(node, pos, child)
+    }
 
-                    InsertResult {
-                        new_offset: Some(new_internal_offset),
-                        splits: None
-                    }
-                }
-                Some(splits) => {
-                    // The node has overflowed its page size
-                    // Split into two nodes and promote the middle key to the current node
-                    let promoted_key = splits.promoted_key;
-                    let left_child_offset = splits.left_offset;
-                    let right_child_offset = splits.right_offset;
-
-                    node.keys.insert(pos, promoted_key);
-
-                    node.children.remove(pos);
-                    node.children.insert(pos, left_child_offset);
-                    node.children.insert(pos+1, right_child_offset);
-
-                    let new_offset = self.disk_manager.get_new_offset().unwrap();
-                    match self.disk_manager.append_node_to_disk(new_offset, &node) {
-                        EncodeResult::Encoded => {
-                            InsertResult {
-                                new_offset: Some(new_offset),
-                                splits: None
-                            }
-                        }
-                        EncodeResult::NeedSplit => {
-                            self.propagate_internal_split(node, new_offset)
-                        }
-                    }
-                }
+    /// Write a node that overflowed its page as several pages
+    fn split_node(&mut self, node: &Node) -> Result<InsertSplit> {
+        let is_leaf = node.children.is_empty();
+        let mut splits = InsertSplit { promoted_keys: vec![], offsets: vec![] };
+        for (i, piece) in split_points(node, self.storage_config.page_size as usize).into_iter().enumerate() {
+            if i > 0 {
+                let promoted_key = match is_leaf {
+                    // anything that sorts between the two leaves will do, the shorter the more fits in the parent
+                    true => shortest_separator(&node.keys[piece.start - 1], &node.keys[piece.start]),
+                    // for internal nodes, the key between the two parts moves up
+                    false => node.keys[piece.start - 1].clone(),
+                };
+                splits.promoted_keys.push(promoted_key);
             }
+            let offset = self.disk_manager.get_new_offset()?;
+            self.disk_manager.write_node(offset, &node_part(node, piece))?;
+            splits.offsets.push(offset);
         }
+        Ok(splits)
     }
 
-    fn insert_into_leaf(&mut self, node: &mut Node, key: &Vec<u8>, value: &Vec<u8>) -> InsertResult {
-        match node.keys.binary_search(&key) {
-            Ok(pos) => {
-                // key already exists, update value
-                node.values[pos] = value.clone();
+    /// Write back the child at pos once it fits in a page
+    /// Empty children are dropped, and children under a quarter of a page are merged
+    /// with a sibling when the merged node still fits in a page
+    fn rebalance_child(&mut self, node: &mut Node, pos: usize, child: Node) -> Result<()> {
+        if child.keys.is_empty() && child.children.is_empty() {
+            // drop the child along with the separator next to it
+            node.children.remove(pos);
+            if !node.keys.is_empty() {
+                node.keys.remove(pos.saturating_sub(1));
             }
-            Err(pos) => {
-                node.keys.insert(pos, key.clone());
-                node.values.insert(pos, value.clone());
+            return Ok(());
+        }
+
+        let page_size = self.storage_config.page_size as usize;
+        if child.encoded_size() < page_size / 4 && node.children.len() > 1 {
+            // merge with the left sibling, or the right one if this is the first child
+            let left_pos = if pos > 0 { pos - 1 } else { pos };
+            let (left, right) = if pos > 0 {
+                (self.disk_manager.load_node_from_disk(node.children[left_pos])?, child.clone())
+            } else {
+                (child.clone(), self.disk_manager.load_node_from_disk(node.children[pos + 1])?)
+            };
+
+            let merged = Self::merge_nodes(left, node.keys[left_pos].clone(), right);
+            if merged.encoded_size() <= page_size {
+                // the sibling is rewritten as part of the merged node
+                let sibling_pos = if pos > 0 { pos - 1 } else { pos + 1 };
+                self.disk_manager.free_page(node.children[sibling_pos]);
+                let merged_offset = self.disk_manager.get_new_offset()?;
+                self.disk_manager.write_node(merged_offset, &merged)?;
+                node.children[left_pos] = merged_offset;
+                node.children.remove(left_pos + 1);
+                node.keys.remove(left_pos);
+                return Ok(());
             }
         }
 
-        let new_offset = self.disk_manager.get_new_offset().unwrap();
-        match self.disk_manager.append_node_to_disk(new_offset, node) {
-            EncodeResult::Encoded => {
-                InsertResult {
-                    new_offset: Some(new_offset),
-                    splits: None
+        let child_offset = self.disk_manager.get_new_offset()?;
+        self.disk_manager.write_node(child_offset, &child)?;
+        node.children[pos] = child_offset;
+        Ok(())
+    }
+
+    /// Concatenate two sibling nodes, separator is the key between them in the parent
+    fn merge_nodes(mut left: Node, separator: Vec<u8>, right: Node) -> Node {
+        if left.children.is_empty() {
+            // a leaf separator only routes lookups, it need not be a stored key, so it is dropped
+            left.keys.extend(right.keys);
+            left.values.extend(right.values);
+            left.expiries.extend(right.expiries);
+        } else {
+            // for internal nodes, the separator comes back down between the two halves
+            left.keys.push(separator);
+            left.keys.extend(right.keys);
+            left.children.extend(right.children);
+        }
+        left
+    }
+
+    /// Remove every key between start and end from the subtree rooted at node
+    /// node is only updated in memory, the caller is responsible for writing it out
+    /// returns whether anything was removed
+    fn delete_range_recursive(&mut self, node: &mut Node, height: usize, start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> Result<bool> {
+        if node.children.is_empty() {
+            // leaf node
+            let before = node.keys.len();
+            let mut pos = 0;
+            while pos < node.keys.len() {
+                if in_range(&node.keys[pos], start, end) {
+                    node.keys.remove(pos);
+                    node.values.remove(pos);
+                    node.expiries.remove(pos);
+                } else {
+                    pos += 1;
                 }
             }
-            EncodeResult::NeedSplit => {
-                self.propagate_leaf_split(node, new_offset)
-            }
+            return Ok(node.keys.len() != before);
         }
-    }
 
-    fn propagate_internal_split(&mut self, node: &mut Node, new_offset: u64) -> InsertResult {
-        // adding the promoted key to the current node resulted in another split
-        // the promoted key should be present in either left or right sub nodes' keys
-        // as this would be redundant like this sentence
-        let mid = node.keys.len() / 2;
-        // for internal nodes, dont include mid
-        let left_node = Node {
-            keys: node.keys[..mid].to_vec(),
-            values: vec![],
-            children: node.children[..=mid].to_vec(),
+        // internal node
+        // child i holds the keys from keys[i - 1] up to but excluding keys[i]
+        let first = match start {
+            Bound::Included(key) | Bound::Excluded(key) => node.child_index(key),
+            Bound::Unbounded => 0,
         };
-        self.disk_manager.append_node_to_disk(new_offset, &left_node);
-
-        let right_node = Node {
-            keys: node.keys[mid+1..].to_vec(),
-            values: vec![],
-            children: node.children[mid+1..].to_vec(),
+        let last = match end {
+            Bound::Included(key) => node.child_index(key),
+            Bound::Excluded(key) => node.find_key(key).unwrap_or_else(|pos| pos),
+            Bound::Unbounded => node.children.len() - 1,
         };
+        if first > last {
+            return Ok(false);
+        }
 
-        let right_offset = self.disk_manager.get_new_offset().unwrap();
-        self.disk_manager.append_node_to_disk(right_offset, &right_node);
-
-        let promoted_key = node.keys[mid].clone();
-        let splits = InsertSplit {
-            promoted_key,
-            left_offset: new_offset,
-            right_offset
+        // unlink the children that lie entirely inside the range
+        let covered = |i: usize| {
+            let lower_covered = match (i, start) {
+                (_, Bound::Unbounded) => true,
+                (0, _) => false,
+                (_, Bound::Included(key)) => node.keys[i - 1] >= *key,
+                (_, Bound::Excluded(key)) => node.keys[i - 1] > *key,
+            };
+            let upper_covered = match end {
+                Bound::Unbounded => true,
+                _ if i == node.children.len() - 1 => false,
+                Bound::Included(key) | Bound::Excluded(key) => node.keys[i] <= *key,
+            };
+            lower_covered && upper_covered
         };
+        let dropped: Vec<bool> = (0..node.children.len()).map(|i| i >= first && i <= last && covered(i)).collect();
+
+        let mut keys = vec![];
+        let mut children = vec![];
+        let mut partial = vec![];
+        let mut unlinked = vec![];
+        for (i, &child_offset) in node.children.iter().enumerate() {
+            if dropped[i] {
+                unlinked.push(child_offset);
+                continue;
+            }
+            // the lower bound of a kept child still separates it from the previous kept child
+            if !children.is_empty() {
+                keys.push(node.keys[i - 1].clone());
+            }
+            if i >= first && i <= last {
+                partial.push(children.len());
+            }
+            children.push(child_offset);
+        }
+        let mut changed = !unlinked.is_empty();
+        node.keys = keys;
+        node.children = children;
+        for child_offset in unlinked {
+            self.free_subtree(child_offset, height - 1)?;
+        }
 
-        InsertResult {
-            new_offset: None,
-            splits: Some(splits)
+        // trim the boundary children, right one first so the left position stays valid
+        for &pos in partial.iter().rev() {
+            let child_offset = node.children[pos];
+            let mut child_node = self.disk_manager.load_node_from_disk(child_offset)?;
+            if self.delete_range_recursive(&mut child_node, height - 1, start, end)? {
+                self.disk_manager.free_page(child_offset);
+                self.rebalance_child(node, pos, child_node)?;
+                changed = true;
+            }
         }
+        Ok(changed)
     }
+}
 
-    fn propagate_leaf_split(&mut self, node: &mut Node, left_offset: u64) -> InsertResult {
-        let mid = node.keys.len() / 2;
+fn snapshot_not_found(name: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("snapshot {} does not exist", name))
+}
 
-        // for leaf nodes, include mid in keys
-        let left_node = Node {
-            keys: node.keys[..mid].to_vec(),
-            values: node.values[..mid].to_vec(),
-            children: vec![]
-        };
-        self.disk_manager.append_node_to_disk(left_offset, &left_node);
+/// Key ranges of the parts a node that doesn't fit in a page is split into, each fitting in a page
+/// Halves with the same number of keys when both fit, then two parts of about the same encoded
+/// size, otherwise as many parts as it takes. For internal nodes the key after each part is
+/// promoted rather than kept
+fn split_points(node: &Node, page_size: usize) -> Vec<Range<usize>> {
+    let count = node.keys.len();
+    let gap = usize::from(!node.children.is_empty());
+    let size = |start: usize, end: usize| node_part(node, start..end).encoded_size();
 
-        let right_node = Node {
-            keys: node.keys[mid..].to_vec(),
-            values: node.values[mid..].to_vec(),
-            children: vec![]
-        };
-        let right_offset = self.disk_manager.get_new_offset().unwrap();
-        self.disk_manager.append_node_to_disk(right_offset, &right_node);
+    let mid = count / 2;
+    if mid > 0 && size(0, mid) <= page_size && size(mid + gap, count) <= page_size {
+        return vec![0..mid, mid + gap..count];
+    }
+    // first split point where the left part is at least as big as the right one
+    let (mut low, mut high) = (1, count.saturating_sub(1));
+    while low < high {
+        let mid = (low + high) / 2;
+        if size(0, mid) >= size(mid + gap, count) { high = mid } else { low = mid + 1 }
+    }
+    let best = [low, low - 1]
+        .into_iter()
+        .filter(|&mid| mid > 0 && mid < count)
+        .map(|mid| (size(0, mid).max(size(mid + gap, count)), mid))
+        .filter(|&(largest, _)| largest <= page_size)
+        .min();
+    if let Some((_, mid)) = best {
+        return vec![0..mid, mid + gap..count];
+    }
 
-        let promoted_key = node.keys[mid].clone();
+    // fill pages from the left, leaves need an entry and internal nodes a child in every part
+    let mut parts = vec![];
+    let mut start = 0;
+    loop {
+        let mut end = start + 1 - gap;
+        while end < count && size(start, end + 1) <= page_size {
+            end += 1;
+        }
+        parts.push(start..end);
+        if end >= count {
+            return parts;
+        }
+        start = end + gap;
+    }
+}
 
-        let split = InsertSplit {
-            promoted_key,
-            left_offset,
-            right_offset
-        };
+/// The keys of node in range, with their values or the children around them
+fn node_part(node: &Node, range: Range<usize>) -> Node {
+    match node.children.is_empty() {
+        true => Node {
+            keys: node.keys[range.clone()].to_vec(),
+            values: node.values[range.clone()].to_vec(),
+            children: vec![],
+            expiries: range.map(|i| node.expiry(i)).collect(),
+        },
+        false => Node {
+            keys: node.keys[range.clone()].to_vec(),
+            values: vec![],
+            children: node.children[range.start..=range.end].to_vec(),
+            expiries: vec![],
+        },
+    }
+}
 
-        InsertResult {
-            new_offset: None,
-            splits: Some(split)
+/// Shortest key above left and no higher than right, left must sort before right
+/// The first byte where they differ is the last one kept from right
+fn shortest_separator(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let common = left.iter().zip(right).take_while(|(a, b)| a == b).count();
+    right[..common + 1].to_vec()
+}
+
+/// A leaf without entries, the root of an empty tree
+fn empty_node() -> Node {
+    Node {
+        keys: vec![],
+        values: vec![],
+        children: vec![],
+        expiries: vec![],
+    }
+}
+
+/// Current time in unix millis, the unit expiries are stored in
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Exclusive upper bound for the keys starting with prefix
+pub(crate) fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    // increment the last byte that can be incremented, dropping trailing 0xFF bytes
+    while let Some(last) = end.pop() {
+        if last < 0xFF {
+            end.push(last + 1);
+            return Bound::Excluded(end);
         }
     }
+    Bound::Unbounded
+}
+
+pub(crate) fn in_range(key: &[u8], start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    let after_start = match start {
+        Bound::Included(start) => key >= start.as_slice(),
+        Bound::Excluded(start) => key > start.as_slice(),
+        Bound::Unbounded => true,
+    };
+    let before_end = match end {
+        Bound::Included(end) => key <= end.as_slice(),
+        Bound::Excluded(end) => key < end.as_slice(),
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::storage::{check_with_config, read_dump, write_dump, DumpFormat};
+    use crate::storage::testing::{key, small_page_config};
     use tempfile::NamedTempFile;
 
     fn get_temp_btree() -> BTree {
@@ -242,10 +1499,15 @@ mod test {
         BTree::new(tmp.path().to_str().unwrap(), Some(storage_config)).unwrap()
     }
 
+    fn get_temp_btree_small_pages() -> BTree {
+        let tmp = NamedTempFile::new().unwrap();
+        BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap()
+    }
+
     #[test]
     fn test_insert_single_val_into_root() {
         let mut btree = get_temp_btree();
-        btree.insert(b"key1".to_vec(), b"value1".to_vec());
+        btree.insert(b"key1".to_vec(), b"value1".to_vec()).unwrap();
 
         let root = btree.disk_manager.load_node_from_disk(btree.root_offset).unwrap();
         assert_eq!(root.keys.len(), 1);
@@ -256,8 +1518,8 @@ mod test {
     #[test]
     fn test_insert_multiple_val_into_root() {
         let mut btree = get_temp_btree_new_configs();
-        btree.insert(b"key1".to_vec(), b"value1".to_vec());
-        btree.insert(b"key2".to_vec(), b"val".to_vec());
+        btree.insert(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+        btree.insert(b"key2".to_vec(), b"val".to_vec()).unwrap();
 
         let root = btree.disk_manager.load_node_from_disk(btree.root_offset).unwrap();
         assert_eq!(root.keys.len(), 2);
@@ -267,16 +1529,33 @@ mod test {
         assert_eq!(root.values[1], b"val");
     }
 
+    #[test]
+    fn test_insert_multiple_val_into_root_page() {
+        let mut btree = get_temp_btree_new_configs();
+        btree.insert(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+        btree.insert(b"key2".to_vec(), b"val".to_vec()).unwrap();
+
+        // the root is laid out again with the prefix both keys share stored once, one byte is left
+        let page = SlottedPage::from_bytes(btree.disk_manager.read_page(btree.root_offset).unwrap()).unwrap();
+        assert!(page.is_leaf());
+        assert_eq!(page.key_count(), 2);
+        assert_eq!(page.prefix(), b"key");
+        assert_eq!(page.free_space(), 32 - (3 + 2 + 3) - 2 * 2 - (4 + 1 + 6) - (4 + 1 + 3));
+        assert_eq!(page.find_key(b"key2"), Ok(1));
+        assert_eq!(page.value(1), b"val");
+        assert_eq!(page.find_key(b"key3"), Err(2));
+    }
+
     #[test]
     fn test_root_node_split_sorted() {
         let mut btree = get_temp_btree_new_configs();
-        btree.insert(b"alpha".to_vec(), b"1".to_vec());
-        btree.insert(b"beta".to_vec(), b"1".to_vec());
-        btree.insert(b"charlie".to_vec(), b"1".to_vec());
+        btree.insert(b"alpha".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"beta".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"charlie".to_vec(), b"1".to_vec()).unwrap();
 
         let root = btree.disk_manager.load_node_from_disk(btree.root_offset).unwrap();
         assert_eq!(root.keys.len(), 1);
-        assert_eq!(root.keys[0], b"beta");
+        assert_eq!(root.keys[0], b"b");
 
         assert_eq!(root.children.len(), 2);
         let left_offset = root.children[0];
@@ -298,13 +1577,13 @@ mod test {
     #[test]
     fn test_root_node_split_unsorted() {
         let mut btree = get_temp_btree_new_configs();
-        btree.insert(b"charlie".to_vec(), b"1".to_vec());
-        btree.insert(b"alpha".to_vec(), b"1".to_vec());
-        btree.insert(b"beta".to_vec(), b"1".to_vec());
+        btree.insert(b"charlie".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"alpha".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"beta".to_vec(), b"1".to_vec()).unwrap();
 
         let root = btree.disk_manager.load_node_from_disk(btree.root_offset).unwrap();
         assert_eq!(root.keys.len(), 1);
-        assert_eq!(root.keys[0], b"beta");
+        assert_eq!(root.keys[0], b"b");
 
         assert_eq!(root.children.len(), 2);
         let left_offset = root.children[0];
@@ -323,17 +1602,69 @@ mod test {
         assert_eq!(right_node.values, vec![b"1".to_vec(), b"1".to_vec()]);
     }
 
+    #[test]
+    fn test_leaf_splits_promote_short_separators() {
+        assert_eq!(shortest_separator(b"apple", b"apricot"), b"apr");
+        assert_eq!(shortest_separator(b"ab", b"abc"), b"abc");
+        assert_eq!(shortest_separator(b"b", b"cat"), b"c");
+
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let mut btree = BTree::new(path, Some(small_page_config())).unwrap();
+        let long_key = |i: usize| format!("{:03}-{}", i, "x".repeat(20)).into_bytes();
+        for i in (0..100).rev() {
+            btree.insert(long_key(i), vec![]).unwrap();
+        }
+        assert!(!btree.root.keys.is_empty());
+        assert!(btree.root.keys.iter().all(|key| key.len() <= 3));
+        for i in 0..100 {
+            assert_eq!(btree.get(&long_key(i)).unwrap(), Some(vec![]));
+        }
+        // a key between the halves of a split goes by the short separator as well
+        btree.insert(b"0505".to_vec(), vec![]).unwrap();
+        assert_eq!(btree.get(b"0505").unwrap(), Some(vec![]));
+        drop(btree);
+        let report = check_with_config(path, small_page_config()).unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+    }
+
+    #[test]
+    fn test_split_uneven_values() {
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let mut btree = BTree::new(path, None).unwrap();
+        btree.insert(b"a".to_vec(), vec![]).unwrap();
+        btree.insert(b"b".to_vec(), vec![]).unwrap();
+        btree.insert(b"c".to_vec(), vec![1; 2100]).unwrap();
+        btree.insert(b"d".to_vec(), vec![2; 2100]).unwrap();
+        // splitting by key count would leave c and d together, too big for a page
+        assert_eq!(btree.get(b"d").unwrap(), Some(vec![2; 2100]));
+
+        drop(btree);
+        let report = check_with_config(path, StorageConfig::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+
+        // a and c share a page, but the big b fits with neither, so the leaf becomes three leaves
+        let mut btree = get_temp_btree_small_pages();
+        btree.insert(vec![b'a'; 20], vec![1; 32]).unwrap();
+        btree.insert(vec![b'c'; 20], vec![3; 32]).unwrap();
+        btree.insert(vec![b'b'; 30], vec![2; 32]).unwrap();
+        assert_eq!(btree.root.children.len(), 3);
+        let keys: Vec<_> = btree.range(..).map(|r| r.unwrap().0).collect();
+        assert_eq!(keys, vec![vec![b'a'; 20], vec![b'b'; 30], vec![b'c'; 20]]);
+    }
+
     #[test]
     fn test_insert_into_leaf_node_with_internal_node() {
         let mut btree = get_temp_btree_new_configs();
-        btree.insert(b"charlie".to_vec(), b"1".to_vec());
-        btree.insert(b"alpha".to_vec(), b"1".to_vec());
-        btree.insert(b"beta".to_vec(), b"1".to_vec());
-        btree.insert(b"a".to_vec(), b"1".to_vec());
+        btree.insert(b"charlie".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"alpha".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"beta".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"a".to_vec(), b"1".to_vec()).unwrap();
 
         let root = btree.disk_manager.load_node_from_disk(btree.root_offset).unwrap();
         assert_eq!(root.keys.len(), 1);
-        assert_eq!(root.keys[0], b"beta");
+        assert_eq!(root.keys[0], b"b");
 
         assert_eq!(root.children.len(), 2);
         let left_offset = root.children[0];
@@ -352,15 +1683,508 @@ mod test {
         assert_eq!(right_node.values, vec![b"1".to_vec(), b"1".to_vec()]);
     }
 
+    #[test]
+    fn test_insert_into_leaf_page_with_internal_node() {
+        let mut btree = get_temp_btree_new_configs();
+        btree.insert(b"charlie".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"alpha".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"beta".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"a".to_vec(), b"1".to_vec()).unwrap();
+
+        // a went into the bytes of the left leaf, its cell below the one alpha got from the split
+        let left_offset = btree.root.children[0];
+        let page = SlottedPage::from_bytes(btree.disk_manager.read_page(left_offset).unwrap()).unwrap();
+        assert_eq!(page.free_pointer(), 32 - (4 + 5 + 1) - (4 + 1 + 1));
+        assert_eq!(page.fragmented(), 0);
+        assert_eq!(page.find_key(b"a"), Ok(0));
+        assert_eq!(page.find_key(b"alpha"), Ok(1));
+        assert_eq!(page.value(0), b"1");
+    }
+
     #[test]
     fn test_duplicate_key_inserts() {
         let mut btree = get_temp_btree();
-        btree.insert(b"a".to_vec(), b"1".to_vec());
-        btree.insert(b"a".to_vec(), b"2".to_vec());
+        btree.insert(b"a".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"a".to_vec(), b"2".to_vec()).unwrap();
 
         let root = btree.disk_manager.load_node_from_disk(btree.root_offset).unwrap();
         assert_eq!(root.keys.len(), 1);
         assert_eq!(root.keys[0], b"a".to_vec());
         assert_eq!(root.values[0], b"2".to_vec());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_get_across_splits() {
+        let mut btree = get_temp_btree_small_pages();
+        for i in (0..200).rev() {
+            btree.insert(key(i), format!("val{}", i).into_bytes()).unwrap();
+        }
+
+        assert!(!btree.root.children.is_empty());
+        for i in 0..200 {
+            assert_eq!(btree.get(&key(i)).unwrap(), Some(format!("val{}", i).into_bytes()));
+        }
+        assert_eq!(btree.get(b"missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_leaf_updates_edit_page_bytes() {
+        let mut btree = get_temp_btree_small_pages();
+        for i in 0..100 {
+            btree.insert(key(i), key(i)).unwrap();
+        }
+        // the new cell of key 51 is the lowest on the page, so the one of key 50 leaves a gap above it
+        btree.insert(key(51), b"v".to_vec()).unwrap();
+        btree.delete(&key(50)).unwrap();
+
+        let mut node = btree.root.clone();
+        let mut offset = btree.root_offset;
+        while !node.children.is_empty() {
+            offset = node.children[node.child_index(&key(51))];
+            node = btree.disk_manager.load_node_from_disk(offset).unwrap();
+        }
+        // the removed cells were left in place rather than the whole leaf being re-encoded
+        let page = SlottedPage::from_bytes(btree.disk_manager.read_page(offset).unwrap()).unwrap();
+        assert!(page.fragmented() > 0);
+        assert_eq!(page.find_key(&key(50)), Err(page.find_key(&key(51)).unwrap()));
+        assert_eq!(page.value(page.find_key(&key(51)).unwrap()), b"v");
+        assert_eq!(btree.get(&key(49)).unwrap(), Some(key(49)));
+    }
+
+    #[test]
+    fn test_get_separator_key_after_update() {
+        let mut btree = get_temp_btree_new_configs();
+        btree.insert(b"alpha".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"beta".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"charlie".to_vec(), b"1".to_vec()).unwrap();
+        // beta is the first key of the right leaf, the update must land there
+        btree.insert(b"beta".to_vec(), b"2".to_vec()).unwrap();
+
+        assert_eq!(btree.get(b"beta").unwrap(), Some(b"2".to_vec()));
+        let pairs: Vec<_> = btree.range(..).map(|r| r.unwrap()).collect();
+        assert_eq!(pairs.len(), 3);
+    }
+
+    #[test]
+    fn test_range() {
+        let mut btree = get_temp_btree_small_pages();
+        for i in 0..200 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+        }
+
+        let all: Vec<Vec<u8>> = btree.range(..).map(|r| r.unwrap().0).collect();
+        assert_eq!(all, (0..200).map(key).collect::<Vec<_>>());
+
+        let some: Vec<Vec<u8>> = btree.range(key(50)..key(60)).map(|r| r.unwrap().0).collect();
+        assert_eq!(some, (50..60).map(key).collect::<Vec<_>>());
+
+        let inclusive: Vec<Vec<u8>> = btree.range(key(195)..=key(199)).map(|r| r.unwrap().0).collect();
+        assert_eq!(inclusive, (195..200).map(key).collect::<Vec<_>>());
+
+        let empty: Vec<_> = btree.range(b"zzz".to_vec()..).collect();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut btree = get_temp_btree_small_pages();
+        for i in 0..200 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+        }
+
+        assert_eq!(btree.delete(&key(10)).unwrap(), Some(b"v".to_vec()));
+        assert_eq!(btree.delete(&key(10)).unwrap(), None);
+        assert_eq!(btree.get(&key(10)).unwrap(), None);
+        assert_eq!(btree.get(&key(11)).unwrap(), Some(b"v".to_vec()));
+
+        // deleting everything shrinks the tree back to a single empty leaf
+        for i in 0..200 {
+            btree.delete(&key(i)).unwrap();
+            if i % 37 == 0 {
+                let remaining: Vec<Vec<u8>> = btree.range(..).map(|r| r.unwrap().0).collect();
+                let expected: Vec<Vec<u8>> = (i + 1..200).filter(|&j| j != 10).map(key).collect();
+                assert_eq!(remaining, expected);
+            }
+        }
+        assert!(btree.root.keys.is_empty());
+        assert!(btree.root.children.is_empty());
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let mut btree = get_temp_btree_small_pages();
+        for tenant in [b"a", b"b", b"c"] {
+            for i in 0..50 {
+                let mut k = tenant.to_vec();
+                k.extend(key(i));
+                btree.insert(k, b"v".to_vec()).unwrap();
+            }
+        }
+        btree.insert(vec![b'b', 0xFF], b"v".to_vec()).unwrap();
+
+        let keys: Vec<Vec<u8>> = btree.scan_prefix(b"b").map(|r| r.unwrap().0).collect();
+        assert_eq!(keys.len(), 51);
+        assert!(keys.iter().all(|k| k[0] == b'b'));
+
+        assert_eq!(btree.scan_prefix(b"bkey001").count(), 10);
+        assert_eq!(btree.scan_prefix(b"").count(), 151);
+        assert_eq!(btree.scan_prefix(b"d").count(), 0);
+    }
+
+    #[test]
+    fn test_delete_prefix() {
+        let mut btree = get_temp_btree_small_pages();
+        for tenant in [b"a", b"b", b"c"] {
+            for i in 0..100 {
+                let mut k = tenant.to_vec();
+                k.extend(key(i));
+                btree.insert(k, b"v".to_vec()).unwrap();
+            }
+        }
+
+        btree.delete_prefix(b"b").unwrap();
+        assert_eq!(btree.scan_prefix(b"b").count(), 0);
+        assert_eq!(btree.scan_prefix(b"a").count(), 100);
+        assert_eq!(btree.scan_prefix(b"c").count(), 100);
+        assert_eq!(btree.get(b"akey0099").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(btree.get(b"ckey0000").unwrap(), Some(b"v".to_vec()));
+
+        // the tree keeps working after whole subtrees were unlinked
+        btree.insert(b"bkey0005".to_vec(), b"w".to_vec()).unwrap();
+        assert_eq!(btree.get(b"bkey0005").unwrap(), Some(b"w".to_vec()));
+
+        btree.delete_prefix(b"").unwrap();
+        assert_eq!(btree.range(..).count(), 0);
+        assert!(btree.root.children.is_empty());
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"ab"), Bound::Excluded(b"ac".to_vec()));
+        assert_eq!(prefix_end(&[b'a', 0xFF]), Bound::Excluded(b"b".to_vec()));
+        assert_eq!(prefix_end(&[0xFF, 0xFF]), Bound::Unbounded);
+        assert_eq!(prefix_end(b""), Bound::Unbounded);
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let mut btree = get_temp_btree_small_pages();
+        for i in 0..300 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+        }
+
+        btree.delete_range(key(20)..key(250)).unwrap();
+        let keys: Vec<Vec<u8>> = btree.range(..).map(|r| r.unwrap().0).collect();
+        let expected: Vec<Vec<u8>> = (0..20).chain(250..300).map(key).collect();
+        assert_eq!(keys, expected);
+
+        btree.delete_range(..=key(5)).unwrap();
+        btree.delete_range(key(290)..).unwrap();
+        let keys: Vec<Vec<u8>> = btree.range(..).map(|r| r.unwrap().0).collect();
+        let expected: Vec<Vec<u8>> = (6..20).chain(250..290).map(key).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_delete_range_frees_pages() {
+        let mut btree = get_temp_btree_small_pages();
+        for i in 0..300 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+        }
+        let pages = btree.disk_manager.page_offsets().unwrap().len();
+
+        btree.delete_range(key(0)..key(300)).unwrap();
+        // every page except the new empty root is free again
+        assert_eq!(btree.disk_manager.free_page_count(), pages - 1);
+
+        // refilling the tree reuses the freed pages instead of growing the file
+        for i in 0..100 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+        }
+        assert_eq!(btree.disk_manager.page_offsets().unwrap().len(), pages);
+    }
+
+    #[test]
+    fn test_rollback_drops_unwritten_offsets() {
+        // nothing is free yet, so both offsets are taken at the end of the file
+        let mut btree = get_temp_btree_small_pages();
+        let written = btree.disk_manager.get_new_offset().unwrap();
+        btree.disk_manager.write_node(written, &btree.root.clone()).unwrap();
+        let unwritten = btree.disk_manager.get_new_offset().unwrap();
+        btree.disk_manager.rollback_pages();
+
+        // the end of the file would hand out the unwritten offset a second time
+        assert_eq!(btree.disk_manager.free_page_count(), 1);
+        assert_eq!(btree.disk_manager.get_new_offset().unwrap(), written);
+        assert_eq!(btree.disk_manager.get_new_offset().unwrap(), unwritten);
+    }
+
+    #[test]
+    fn test_free_list_rebuilt_on_open() {
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let mut btree = BTree::new(path, None).unwrap();
+        for i in 0..20 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+        }
+        let free_pages = btree.disk_manager.free_page_count();
+        assert!(free_pages > 0);
+        drop(btree);
+
+        let btree = BTree::new(path, None).unwrap();
+        assert_eq!(btree.disk_manager.free_page_count(), free_pages);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let mut btree = get_temp_btree();
+        btree.insert(b"lease".to_vec(), b"node1".to_vec()).unwrap();
+
+        // mismatch leaves the value untouched and reports what is there
+        let result = btree.compare_and_swap(b"lease", Some(b"node2"), Some(b"node3".to_vec())).unwrap();
+        assert_eq!(result, Err(CompareAndSwapError { current: Some(b"node1".to_vec()) }));
+        assert_eq!(btree.get(b"lease").unwrap(), Some(b"node1".to_vec()));
+
+        btree.compare_and_swap(b"lease", Some(b"node1"), Some(b"node2".to_vec())).unwrap().unwrap();
+        assert_eq!(btree.get(b"lease").unwrap(), Some(b"node2".to_vec()));
+
+        // None as new deletes the key
+        btree.compare_and_swap(b"lease", Some(b"node2"), None).unwrap().unwrap();
+        assert_eq!(btree.get(b"lease").unwrap(), None);
+    }
+
+    #[test]
+    fn test_compare_and_swap_absent_key() {
+        let mut btree = get_temp_btree_small_pages();
+        for i in 0..50 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+        }
+
+        btree.compare_and_swap(b"key0100", None, Some(b"new".to_vec())).unwrap().unwrap();
+        assert_eq!(btree.get(b"key0100").unwrap(), Some(b"new".to_vec()));
+
+        let result = btree.compare_and_swap(b"key0100", None, Some(b"other".to_vec())).unwrap();
+        assert_eq!(result, Err(CompareAndSwapError { current: Some(b"new".to_vec()) }));
+
+        let result = btree.compare_and_swap(b"key0200", Some(b"v"), None).unwrap();
+        assert_eq!(result, Err(CompareAndSwapError { current: None }));
+        for i in 0..50 {
+            assert_eq!(btree.get(&key(i)).unwrap(), Some(b"v".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_merge_builtin_operators() {
+        let mut btree = get_temp_btree_small_pages();
+        for _ in 0..3 {
+            btree.merge("u64_add", b"counter", &5u64.to_le_bytes()).unwrap();
+        }
+        assert_eq!(btree.get(b"counter").unwrap(), Some(15u64.to_le_bytes().to_vec()));
+
+        btree.merge("u64_max", b"max", &7u64.to_le_bytes()).unwrap();
+        btree.merge("u64_max", b"max", &3u64.to_le_bytes()).unwrap();
+        assert_eq!(btree.get(b"max").unwrap(), Some(7u64.to_le_bytes().to_vec()));
+
+        for i in 0..20 {
+            btree.merge("append", b"list", &[i]).unwrap();
+        }
+        assert_eq!(btree.get(b"list").unwrap(), Some((0..20).collect()));
+
+        // small pages allow values of up to 32 bytes, a merge past that is refused and writes nothing
+        for i in 20..32 {
+            btree.merge("append", b"list", &[i]).unwrap();
+        }
+        let root_offset = btree.root_offset;
+        assert_eq!(btree.merge("append", b"list", &[32]).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(btree.root_offset, root_offset);
+        assert_eq!(btree.get(b"list").unwrap(), Some((0..32).collect()));
+    }
+
+    #[test]
+    fn test_merge_custom_operator_and_errors() {
+        let mut btree = get_temp_btree();
+        btree.register_merge_operator("concat_with_comma", Box::new(|existing, operand| {
+            let mut value = existing.map(|v| [v, b","].concat()).unwrap_or_default();
+            value.extend_from_slice(operand);
+            Ok(value)
+        }));
+        btree.merge("concat_with_comma", b"tags", b"a").unwrap();
+        assert_eq!(btree.merge("concat_with_comma", b"tags", b"b").unwrap(), b"a,b".to_vec());
+
+        assert_eq!(btree.merge("missing", b"tags", b"c").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        // a failing operator leaves the value as it was
+        btree.insert(b"counter".to_vec(), b"not a number".to_vec()).unwrap();
+        assert!(btree.merge("u64_add", b"counter", &1u64.to_le_bytes()).is_err());
+        assert_eq!(btree.get(b"counter").unwrap(), Some(b"not a number".to_vec()));
+    }
+
+    #[test]
+    fn test_insert_returns_previous_value() {
+        let mut btree = get_temp_btree();
+        assert_eq!(btree.insert(b"a".to_vec(), b"1".to_vec()).unwrap(), None);
+        assert_eq!(btree.insert(b"a".to_vec(), b"2".to_vec()).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(btree.get(b"a").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_insert_new_and_replace() {
+        let mut btree = get_temp_btree_small_pages();
+        for i in 0..40 {
+            assert!(btree.insert_new(key(i), b"first".to_vec()).unwrap());
+        }
+        // existing keys are never overwritten by insert_new
+        assert!(!btree.insert_new(key(7), b"second".to_vec()).unwrap());
+        assert_eq!(btree.get(&key(7)).unwrap(), Some(b"first".to_vec()));
+
+        assert_eq!(btree.replace(key(7), b"second".to_vec()).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(btree.get(&key(7)).unwrap(), Some(b"second".to_vec()));
+
+        // missing keys are never created by replace
+        assert_eq!(btree.replace(key(100), b"v".to_vec()).unwrap(), None);
+        assert_eq!(btree.get(&key(100)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_ttl_hides_expired_entries() {
+        let mut btree = get_temp_btree();
+        btree.insert_with_ttl(b"fresh".to_vec(), b"1".to_vec(), Duration::from_secs(3600)).unwrap();
+        btree.insert_with_ttl(b"stale".to_vec(), b"2".to_vec(), Duration::ZERO).unwrap();
+        btree.insert(b"plain".to_vec(), b"3".to_vec()).unwrap();
+
+        assert_eq!(btree.get(b"fresh").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(btree.get(b"stale").unwrap(), None);
+        let keys: Vec<Vec<u8>> = btree.range(..).map(|r| r.unwrap().0).collect();
+        assert_eq!(keys, vec![b"fresh".to_vec(), b"plain".to_vec()]);
+
+        // an expired key reads as absent for writes too, and a plain insert clears the ttl
+        assert!(btree.insert_new(b"stale".to_vec(), b"4".to_vec()).unwrap());
+        assert_eq!(btree.get(b"stale").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn test_sweep_expired() {
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let mut btree = BTree::new(path, Some(small_page_config())).unwrap();
+        for i in 0..60 {
+            let ttl = if i % 3 == 0 { Duration::ZERO } else { Duration::from_secs(3600) };
+            btree.insert_with_ttl(key(i), b"v".to_vec(), ttl).unwrap();
+        }
+        assert_eq!(btree.sweep_expired().unwrap(), 20);
+        assert_eq!(btree.sweep_expired().unwrap(), 0);
+        assert_eq!(btree.range(..).count(), 40);
+        drop(btree);
+
+        // expiries survive a reopen
+        let mut btree = BTree::new(path, Some(small_page_config())).unwrap();
+        for i in 0..60 {
+            let expected = if i % 3 == 0 { None } else { Some(b"v".to_vec()) };
+            assert_eq!(btree.get(&key(i)).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let mut btree = BTree::new(path, Some(small_page_config())).unwrap();
+        let entries: Vec<_> = (0..500).map(|i| (key(i), key(i * 7))).collect();
+        assert_eq!(btree.bulk_load(entries.iter().cloned().map(Ok)).unwrap(), 500);
+        assert!(btree.tree_height(btree.root_offset).unwrap() > 2);
+        assert!(crate::storage::check_with_config(path, small_page_config()).unwrap().is_ok());
+        assert_eq!(btree.range(..).map(|r| r.unwrap()).collect::<Vec<_>>(), entries);
+
+        // the loaded tree takes ordinary writes
+        for i in 0..500 {
+            if i % 2 == 0 {
+                btree.delete(&key(i)).unwrap();
+            }
+        }
+        btree.insert(b"key0001a".to_vec(), b"new".to_vec()).unwrap();
+        assert_eq!(btree.range(..).count(), 251);
+        drop(btree);
+        assert!(crate::storage::check_with_config(path, small_page_config()).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_put_rejects_oversized_entries() {
+        let mut btree = get_temp_btree_small_pages();
+        // the root is still a leaf, so it would be encoded as a whole node
+        let err = btree.insert(key(1), vec![0; 33]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "value is 33 bytes, longer than the maximum of 32");
+        assert_eq!(btree.commit_id, 0);
+
+        for i in 0..100 {
+            btree.insert(key(i), vec![i as u8]).unwrap();
+        }
+        // leaves below the root are updated in place on their page bytes
+        let err = btree.insert(vec![b'k'; 33], vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "key is 33 bytes, longer than the maximum of 32");
+        assert_eq!(btree.replace(key(5), vec![0; 33]).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(btree.insert_with_ttl(key(5), vec![0; 33], Duration::from_secs(60)).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(btree.compare_and_swap(&key(5), Some(&[5]), Some(vec![0; 33])).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(btree.get(&key(5)).unwrap(), Some(vec![5]));
+        assert_eq!(btree.range(..).count(), 100);
+    }
+
+    #[test]
+    fn test_new_rejects_max_val_size_over_expiry_bit() {
+        let tmp = NamedTempFile::new().unwrap();
+        let storage_config = StorageConfig {
+            page_size: u16::MAX,
+            max_key_size: 32,
+            max_val_size: 0x8000,
+            metadata_offset: 0,
+            first_page_offset: u16::MAX as u64,
+        };
+        let err = BTree::new(tmp.path().to_str().unwrap(), Some(storage_config)).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "max_val_size is 32768, larger than the maximum of 32767");
+    }
+
+    #[test]
+    fn test_bulk_load_rejects_bad_input() {
+        let mut btree = get_temp_btree_small_pages();
+        let unsorted = vec![Ok((key(2), vec![])), Ok((key(1), vec![]))];
+        assert_eq!(btree.bulk_load(unsorted).unwrap_err().kind(), ErrorKind::InvalidInput);
+        let too_large = vec![Ok((key(1), vec![0; 200]))];
+        assert_eq!(btree.bulk_load(too_large).unwrap_err().kind(), ErrorKind::InvalidInput);
+        // fits a page but not the configured limits, the error names the entry
+        let over_limit = vec![Ok((key(1), vec![])), Ok((key(2), vec![0; 40]))];
+        let err = btree.bulk_load(over_limit).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "entry 2: value is 40 bytes, longer than the maximum of 32");
+        let long_key = vec![Ok((vec![1; 33], vec![]))];
+        assert_eq!(btree.bulk_load(long_key).unwrap_err().to_string(), "entry 1: key is 33 bytes, longer than the maximum of 32");
+        let failing = vec![Ok((key(1), vec![])), Err(Error::other("read failed"))];
+        assert!(btree.bulk_load(failing).is_err());
+        // nothing was committed and the pages written along the way are free again
+        assert_eq!(btree.commit_id, 0);
+        assert_eq!(btree.range(..).count(), 0);
+
+        btree.insert(key(1), vec![]).unwrap();
+        assert_eq!(btree.bulk_load(vec![Ok((key(2), vec![]))]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_dump_and_load_across_page_sizes() {
+        let mut small = get_temp_btree_small_pages();
+        small.create_bucket("users").unwrap();
+        let mut users = small.open_bucket("users").unwrap();
+        for i in 0..200 {
+            users.insert(key(i), vec![i as u8]).unwrap();
+        }
+        let mut dump = vec![];
+        write_dump(&mut dump, DumpFormat::JsonLines, users.range(..).unwrap()).unwrap();
+
+        let mut btree = get_temp_btree();
+        btree.create_bucket("users").unwrap();
+        let mut users = btree.open_bucket("users").unwrap();
+        assert_eq!(users.bulk_load(read_dump(dump.as_slice(), DumpFormat::JsonLines)).unwrap(), 200);
+        let entries: Vec<_> = users.range(..).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(entries, (0..200).map(|i| (key(i), vec![i as u8])).collect::<Vec<_>>());
+        assert_eq!(users.bulk_load(vec![]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::storage::btree::BTree;
+use crate::storage::iterator::RangeIter;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::RangeBounds;
+
+/// A named tree stored in the same file as the default tree
+/// The catalog tree maps each bucket name to the root offset of its tree
+pub struct Bucket<'a> {
+    btree: &'a mut BTree,
+    name: String,
+}
+
+impl<'a> Bucket<'a> {
+    pub(crate) fn new(btree: &'a mut BTree, name: &str) -> Self {
+        Self {
+            btree,
+            name: name.to_string(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut tx = self.btree.begin();
+        tx.get(&self.name, key)
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let mut tx = self.btree.begin();
+        let previous = tx.insert(&self.name, key, value)?;
+        tx.commit()?;
+        Ok(previous)
+    }
+
+    /// Delete key from the bucket, returns the value it held
+    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut tx = self.btree.begin();
+        let removed = tx.delete(&self.name, key)?;
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    /// Fill the empty bucket from entries in increasing key order, returns how many were loaded
+    /// see BTree::bulk_load
+    pub fn bulk_load<I>(&mut self, entries: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    {
+        let root_offset = self.btree.bucket_root(&self.name)?.ok_or_else(|| not_found(&self.name))?;
+        if !self.btree.load_root(root_offset)?.keys.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "bulk load needs an empty bucket"));
+        }
+        let loaded = self.btree.build_tree(&mut entries.into_iter()).and_then(|(new_root_offset, count)| {
+            let catalog_offset = self.btree.catalog_offset;
+            let (catalog_offset, _) = self.btree.insert_into_tree(catalog_offset, self.name.as_bytes(), &encode_root(new_root_offset))?;
+            Ok((catalog_offset, count))
+        });
+        match loaded {
+            Ok((catalog_offset, count)) => {
+                if root_offset != 0 {
+                    self.btree.disk_manager.free_page(root_offset);
+                }
+                let root_offset = self.btree.root_offset;
+                self.btree.commit(root_offset, catalog_offset)?;
+                Ok(count)
+            }
+            Err(e) => {
+                self.btree.disk_manager.rollback_pages();
+                Err(e)
+            }
+        }
+    }
+
+    /// Iterate over the key/value pairs within range in key order
+    pub fn range<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> Result<RangeIter<'_>> {
+        let root_offset = self.btree.bucket_root(&self.name)?.ok_or_else(|| not_found(&self.name))?;
+        let root = self.btree.load_root(root_offset)?;
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        Ok(RangeIter::new(&mut self.btree.disk_manager, root, start, end))
+    }
+}
+
+pub(crate) fn not_found(name: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("bucket {} does not exist", name))
+}
+
+/// Catalog values are the bucket's root offset, 0 for a bucket that has never been written to
+pub(crate) fn encode_root(root_offset: u64) -> Vec<u8> {
+    root_offset.to_le_bytes().to_vec()
+}
+
+pub(crate) fn decode_root(value: &[u8]) -> Result<u64> {
+    let bytes = value
+        .try_into()
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "catalog entry is not a root offset"))?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn decode_name(key: &[u8]) -> Result<String> {
+    String::from_utf8(key.to_vec()).map_err(|_| Error::new(ErrorKind::InvalidData, "bucket name is not valid utf8"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_create_open_and_list_buckets() {
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let mut btree = BTree::new(path, None).unwrap();
+
+        btree.create_bucket("users").unwrap();
+        btree.create_bucket("emails").unwrap();
+        assert_eq!(btree.create_bucket("users").unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(btree.list_buckets().unwrap(), vec!["emails".to_string(), "users".to_string()]);
+
+        let mut users = btree.open_bucket("users").unwrap();
+        users.insert(b"alice".to_vec(), b"1".to_vec()).unwrap();
+        users.insert(b"bob".to_vec(), b"2".to_vec()).unwrap();
+        assert_eq!(users.get(b"alice").unwrap(), Some(b"1".to_vec()));
+
+        // buckets don't see each other's keys, nor the default tree's
+        btree.insert(b"alice".to_vec(), b"default".to_vec()).unwrap();
+        assert_eq!(btree.open_bucket("emails").unwrap().get(b"alice").unwrap(), None);
+        assert_eq!(btree.get(b"alice").unwrap(), Some(b"default".to_vec()));
+
+        // reopen the file and read the bucket back
+        drop(btree);
+        let mut btree = BTree::new(path, None).unwrap();
+        let mut users = btree.open_bucket("users").unwrap();
+        let keys: Vec<Vec<u8>> = users.range(..).unwrap().map(|r| r.unwrap().0).collect();
+        assert_eq!(keys, vec![b"alice".to_vec(), b"bob".to_vec()]);
+    }
+
+    #[test]
+    fn test_drop_bucket() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), None).unwrap();
+
+        btree.create_bucket("sessions").unwrap();
+        btree.open_bucket("sessions").unwrap().insert(b"a".to_vec(), b"1".to_vec()).unwrap();
+        btree.drop_bucket("sessions").unwrap();
+
+        assert!(btree.list_buckets().unwrap().is_empty());
+        assert_eq!(btree.open_bucket("sessions").err().unwrap().kind(), ErrorKind::NotFound);
+        assert_eq!(btree.drop_bucket("sessions").unwrap_err().kind(), ErrorKind::NotFound);
+
+        // a recreated bucket starts out empty
+        btree.create_bucket("sessions").unwrap();
+        assert_eq!(btree.open_bucket("sessions").unwrap().get(b"a").unwrap(), None);
+    }
+}
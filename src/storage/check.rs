@@ -0,0 +1,344 @@
+use crate::storage::bucket;
+use crate::storage::configs::StorageConfig;
+use crate::storage::diskmanager::DiskManager;
+use crate::storage::node::Node;
+use crate::text::json_quote;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// The kind of damage a check found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViolationKind {
+    /// A pointer to somewhere that isn't the start of a page in the file
+    BadOffset,
+    /// The page doesn't decode as a node
+    Undecodable,
+    /// An internal node without exactly one more child than keys
+    ChildCount,
+    /// Keys within a node that aren't strictly increasing
+    UnsortedKeys,
+    /// A key outside the range the separators of its parents allow
+    OutOfBounds,
+    /// A leaf at a different depth than the first leaf of its tree
+    UnevenDepth,
+    KeyTooLong,
+    ValueTooLong,
+    /// A page more than one pointer leads to
+    ReachedTwice,
+    /// A catalog entry that isn't a bucket name and root offset
+    BadCatalogEntry,
+}
+
+impl ViolationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ViolationKind::BadOffset => "bad_offset",
+            ViolationKind::Undecodable => "undecodable",
+            ViolationKind::ChildCount => "child_count",
+            ViolationKind::UnsortedKeys => "unsorted_keys",
+            ViolationKind::OutOfBounds => "out_of_bounds",
+            ViolationKind::UnevenDepth => "uneven_depth",
+            ViolationKind::KeyTooLong => "key_too_long",
+            ViolationKind::ValueTooLong => "value_too_long",
+            ViolationKind::ReachedTwice => "reached_twice",
+            ViolationKind::BadCatalogEntry => "bad_catalog_entry",
+        }
+    }
+}
+
+/// One problem found by a check, offset is the page it was found on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub offset: u64,
+    pub tree: String, // "main", "catalog", "history" or "bucket <name>"
+    pub kind: ViolationKind,
+    pub detail: String,
+}
+
+/// Result of checking a database file
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckReport {
+    pub pages_checked: usize,
+    pub violations: Vec<Violation>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// The report as a single JSON object
+    pub fn to_json(&self) -> String {
+        let mut json = format!("{{\"ok\":{},\"pages_checked\":{},\"violations\":[", self.is_ok(), self.pages_checked);
+        for (i, violation) in self.violations.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "{{\"offset\":{},\"tree\":{},\"kind\":\"{}\",\"detail\":{}}}",
+                violation.offset,
+                json_quote(&violation.tree),
+                violation.kind.as_str(),
+                json_quote(&violation.detail),
+            ).unwrap();
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// Check the database file at path, written with the default storage config
+pub fn check(path: &str) -> Result<CheckReport> {
+    check_with_config(path, StorageConfig::default())
+}
+
+/// Walk every tree of the current commit from the metadata page and report the damage found
+/// The file is only read, an error means it couldn't be read at all
+pub fn check_with_config(path: &str, storage_config: StorageConfig) -> Result<CheckReport> {
+    if !Path::new(path).is_file() {
+        return Err(Error::new(ErrorKind::NotFound, format!("{} does not exist", path)));
+    }
+    let mut disk_manager = DiskManager::open_read_only(path, storage_config)?;
+    let metadata = disk_manager.read_metadata()?;
+    let file_len = disk_manager.file.metadata()?.len();
+
+    let mut checker = Checker {
+        disk_manager,
+        file_len,
+        seen: HashSet::new(),
+        report: CheckReport { pages_checked: 0, violations: vec![] },
+    };
+    checker.check_tree("main", metadata.root_offset, false);
+    checker.check_tree("catalog", metadata.catalog_offset, true);
+    checker.check_tree("history", metadata.history_offset, false);
+    Ok(checker.report)
+}
+
+struct Checker {
+    disk_manager: DiskManager,
+    file_len: u64,
+    // pages already reached, from any tree
+    seen: HashSet<u64>,
+    report: CheckReport,
+}
+
+/// Where a page sits in the tree being checked
+struct Position<'a> {
+    depth: usize,
+    // keys of the page have to be >= low and < high
+    low: Option<&'a [u8]>,
+    high: Option<&'a [u8]>,
+}
+
+impl Checker {
+    /// Check the tree rooted at root_offset, catalog trees have their buckets checked too
+    fn check_tree(&mut self, tree: &str, root_offset: u64, catalog: bool) {
+        // offset 0 is a tree that has never been written
+        if root_offset == 0 {
+            return;
+        }
+        let mut leaf_depth = None;
+        let root = Position { depth: 0, low: None, high: None };
+        self.check_page(tree, root_offset, root, catalog, &mut leaf_depth);
+    }
+
+    fn check_page(&mut self, tree: &str, offset: u64, position: Position, catalog: bool, leaf_depth: &mut Option<usize>) {
+        let config = &self.disk_manager.config;
+        let page_size = config.page_size as u64;
+        if offset < config.first_page_offset || !(offset - config.first_page_offset).is_multiple_of(page_size) || offset + page_size > self.file_len {
+            self.violation(tree, offset, ViolationKind::BadOffset, format!("file is {} bytes long", self.file_len));
+            return;
+        }
+        if !self.seen.insert(offset) {
+            self.violation(tree, offset, ViolationKind::ReachedTwice, "page was already reached from another pointer".to_string());
+            return;
+        }
+        let node = match self.disk_manager.read_page(offset).and_then(|buf| Node::try_decode_node(&buf)) {
+            Ok(node) => node,
+            Err(e) => {
+                self.violation(tree, offset, ViolationKind::Undecodable, e.to_string());
+                return;
+            }
+        };
+        self.report.pages_checked += 1;
+        self.check_keys(tree, offset, &node, &position);
+
+        if node.children.is_empty() {
+            match *leaf_depth {
+                None => *leaf_depth = Some(position.depth),
+                Some(depth) if depth != position.depth => {
+                    let detail = format!("leaf is at depth {}, the first leaf of the tree is at depth {}", position.depth, depth);
+                    self.violation(tree, offset, ViolationKind::UnevenDepth, detail);
+                }
+                Some(_) => {}
+            }
+            if catalog {
+                self.check_buckets(offset, &node);
+            }
+            return;
+        }
+
+        if node.children.len() != node.keys.len() + 1 {
+            let detail = format!("{} children for {} keys", node.children.len(), node.keys.len());
+            self.violation(tree, offset, ViolationKind::ChildCount, detail);
+            return;
+        }
+        for (pos, &child) in node.children.iter().enumerate() {
+            // keys equal to a separator live in the right child
+            let child_position = Position {
+                depth: position.depth + 1,
+                low: if pos == 0 { position.low } else { Some(&node.keys[pos - 1]) },
+                high: node.keys.get(pos).map(|key| key.as_slice()).or(position.high),
+            };
+            self.check_page(tree, child, child_position, catalog, leaf_depth);
+        }
+    }
+
+    fn check_keys(&mut self, tree: &str, offset: u64, node: &Node, position: &Position) {
+        let max_key_size = self.disk_manager.config.max_key_size as usize;
+        let max_val_size = self.disk_manager.config.max_val_size as usize;
+        for (pos, key) in node.keys.iter().enumerate() {
+            if pos > 0 && node.keys[pos - 1] >= *key {
+                self.violation(tree, offset, ViolationKind::UnsortedKeys, format!("key {} is not greater than key {}", pos, pos - 1));
+            }
+            if position.low.is_some_and(|low| key.as_slice() < low) {
+                self.violation(tree, offset, ViolationKind::OutOfBounds, format!("key {} is below the separator before this page", pos));
+            }
+            if position.high.is_some_and(|high| key.as_slice() >= high) {
+                self.violation(tree, offset, ViolationKind::OutOfBounds, format!("key {} is not below the separator after this page", pos));
+            }
+            if key.len() > max_key_size {
+                self.violation(tree, offset, ViolationKind::KeyTooLong, format!("key {} is {} bytes long", pos, key.len()));
+            }
+        }
+        for (pos, value) in node.values.iter().enumerate() {
+            if value.len() > max_val_size {
+                self.violation(tree, offset, ViolationKind::ValueTooLong, format!("value {} is {} bytes long", pos, value.len()));
+            }
+        }
+    }
+
+    /// Check the tree of every bucket listed in a catalog leaf
+    fn check_buckets(&mut self, offset: u64, node: &Node) {
+        for (pos, (key, value)) in node.keys.iter().zip(&node.values).enumerate() {
+            match (bucket::decode_name(key), bucket::decode_root(value)) {
+                (Ok(name), Ok(root_offset)) => self.check_tree(&format!("bucket {}", name), root_offset, false),
+                (Err(e), _) | (_, Err(e)) => {
+                    self.violation("catalog", offset, ViolationKind::BadCatalogEntry, format!("entry {}: {}", pos, e));
+                }
+            }
+        }
+    }
+
+    fn violation(&mut self, tree: &str, offset: u64, kind: ViolationKind, detail: String) {
+        self.report.violations.push(Violation {
+            offset,
+            tree: tree.to_string(),
+            kind,
+            detail,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::{BTree, Retention};
+    use crate::storage::testing::{key, small_page_config};
+    use tempfile::NamedTempFile;
+
+    fn populated_btree(tmp: &NamedTempFile) -> BTree {
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        btree.set_retention(Retention::KeepLast(5));
+        btree.create_bucket("users").unwrap();
+        for i in 0..100 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+            btree.open_bucket("users").unwrap().insert(key(i), b"u".to_vec()).unwrap();
+        }
+        btree
+    }
+
+    /// Offset of a leaf of the main tree and of the internal node pointing at it
+    fn leaf_and_parent(btree: &mut BTree) -> (u64, u64) {
+        let mut parent = btree.root_offset;
+        let mut node = btree.root.clone();
+        loop {
+            let child = node.children[1];
+            let child_node = btree.disk_manager.load_node_from_disk(child).unwrap();
+            if child_node.children.is_empty() {
+                return (child, parent);
+            }
+            parent = child;
+            node = child_node;
+        }
+    }
+
+    #[test]
+    fn test_check_healthy_file() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = populated_btree(&tmp);
+        let report = check_with_config(tmp.path().to_str().unwrap(), small_page_config()).unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        assert_eq!(report.pages_checked, btree.reachable_pages().unwrap().len());
+        assert!(report.to_json().starts_with("{\"ok\":true,"));
+
+        assert_eq!(check("/nonexistent/boxer.db").unwrap_err().kind(), ErrorKind::NotFound);
+
+        // an empty file is reported, not turned into a database
+        let empty = NamedTempFile::new().unwrap();
+        assert_eq!(check(empty.path().to_str().unwrap()).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(std::fs::metadata(empty.path()).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_check_reports_bad_keys() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = populated_btree(&tmp);
+        let (leaf_offset, _) = leaf_and_parent(&mut btree);
+        let mut leaf = btree.disk_manager.load_node_from_disk(leaf_offset).unwrap();
+        leaf.keys.swap(0, 1);
+        // sorts before every key, so below the separator that leads to the leaf
+        leaf.keys[1] = b"a".to_vec();
+        btree.disk_manager.append_node_to_disk(leaf_offset, &leaf);
+
+        let report = check_with_config(tmp.path().to_str().unwrap(), small_page_config()).unwrap();
+        let kinds: Vec<_> = report.violations.iter().map(|v| (v.offset, v.tree.as_str(), v.kind)).collect();
+        assert_eq!(kinds, vec![
+            (leaf_offset, "main", ViolationKind::UnsortedKeys),
+            (leaf_offset, "main", ViolationKind::OutOfBounds),
+        ]);
+    }
+
+    #[test]
+    fn test_check_reports_damaged_pages() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = populated_btree(&tmp);
+        let (leaf_offset, parent_offset) = leaf_and_parent(&mut btree);
+        let mut parent = btree.disk_manager.load_node_from_disk(parent_offset).unwrap();
+        let garbage_offset = parent.children[0];
+        parent.children[2] = leaf_offset;
+        parent.children[3] = 7;
+        btree.disk_manager.append_node_to_disk(parent_offset, &parent);
+        let mut garbage = vec![0xff; 128];
+        garbage[0] = 1;
+        std::fs::write(tmp.path(), {
+            let mut file = std::fs::read(tmp.path()).unwrap();
+            file[garbage_offset as usize..garbage_offset as usize + 128].copy_from_slice(&garbage);
+            file
+        }).unwrap();
+
+        let report = check_with_config(tmp.path().to_str().unwrap(), small_page_config()).unwrap();
+        let kinds: Vec<_> = report.violations.iter().map(|v| (v.offset, v.kind)).collect();
+        assert_eq!(kinds, vec![
+            (garbage_offset, ViolationKind::Undecodable),
+            (leaf_offset, ViolationKind::ReachedTwice),
+            (7, ViolationKind::BadOffset),
+        ]);
+        let json = report.to_json();
+        assert!(json.starts_with("{\"ok\":false,"));
+        assert!(json.contains("{\"offset\":7,\"tree\":\"main\",\"kind\":\"bad_offset\","));
+    }
+}
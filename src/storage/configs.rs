@@ -1,10 +1,16 @@
 pub(crate) const BNODE_INTERNAL: u8 = 0;
 pub(crate) const BNODE_LEAF: u8 = 1;
+// set in the node type byte of pages in the slotted format, pages without it use the legacy layout
+pub(crate) const PAGE_SLOTTED: u8 = 0x80;
+// set in the node type byte of slotted pages that store the prefix shared by all their keys once
+pub(crate) const PAGE_PREFIX: u8 = 0x40;
+// set in a leaf entry's val_len when an expiry timestamp precedes the value
+pub(crate) const VAL_HAS_EXPIRY: u16 = 0x8000;
 
 /// Users should be able to change these configs
 #[derive(Debug, Clone)]
 pub struct StorageConfig {
-    pub page_size: u16, // minimum of 8 bytes to fit the root offset
+    pub page_size: u16, // minimum of 32 bytes to fit the metadata, 40 to keep the time of the last commit too
     pub max_key_size: u16,
     pub max_val_size: u16,
     pub metadata_offset: u64,
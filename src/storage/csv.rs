@@ -0,0 +1,496 @@
+use crate::storage::btree::{BTree, PutMode};
+use crate::storage::bucket;
+use crate::tuple::{self, Element};
+use std::io::{BufRead, Error, ErrorKind, Result};
+
+// rows written per commit
+const BATCH_SIZE: usize = 512;
+// rejected rows whose reason is kept in the summary, the rest are only counted
+const MAX_REJECTIONS: usize = 100;
+
+// fields of a record, or why it was rejected
+type Record = std::result::Result<Vec<String>, String>;
+
+/// How a key column's text is turned into a tuple element
+/// Typed columns sort by value, so 9 comes before 10 in a uint column but not in a string column
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    String,
+    Int,
+    UInt,
+    Float,
+}
+
+impl ColumnType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ColumnType::String => "string",
+            ColumnType::Int => "int",
+            ColumnType::UInt => "uint",
+            ColumnType::Float => "float",
+        }
+    }
+}
+
+/// A column the key is built from
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyColumn {
+    pub name: String,
+    pub column_type: ColumnType,
+}
+
+impl KeyColumn {
+    /// Parse name or name:type, with type one of string, int, uint or float
+    pub fn parse(spec: &str) -> Result<Self> {
+        let types = [ColumnType::String, ColumnType::Int, ColumnType::UInt, ColumnType::Float];
+        let (name, column_type) = match spec.rsplit_once(':') {
+            Some((name, type_name)) => match types.into_iter().find(|t| t.as_str() == type_name) {
+                Some(column_type) => (name, column_type),
+                None => return Err(invalid_input(format!("unknown column type {}, expected string, int, uint or float", type_name))),
+            },
+            None => (spec, ColumnType::String),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            column_type,
+        })
+    }
+
+    fn element(&self, field: &str) -> std::result::Result<Element, String> {
+        let bad = || format!("{} is not a valid {} for column {}", field, self.column_type.as_str(), self.name);
+        match self.column_type {
+            ColumnType::String => Ok(Element::String(field.to_string())),
+            ColumnType::Int => field.trim().parse().map(Element::Int).map_err(|_| bad()),
+            ColumnType::UInt => field.trim().parse().map(Element::UInt).map_err(|_| bad()),
+            ColumnType::Float => field.trim().parse().map(Element::Float).map_err(|_| bad()),
+        }
+    }
+}
+
+/// What happens to the entries already in the target tree
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportMode {
+    /// Keep them, a row whose key is already present is rejected
+    Append,
+    /// Drop them, the imported rows take their place with the last commit
+    Replace,
+}
+
+/// Where and how import_csv writes the rows of a CSV file
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    /// The columns the key is packed from, in order
+    pub key_columns: Vec<KeyColumn>,
+    pub mode: ImportMode,
+    pub delimiter: char,
+    /// Import into this bucket instead of the main tree, it is created if missing
+    pub bucket: Option<String>,
+}
+
+impl CsvOptions {
+    /// Append into the main tree from a comma separated file
+    pub fn new(key_columns: Vec<KeyColumn>) -> Self {
+        Self {
+            key_columns,
+            mode: ImportMode::Append,
+            delimiter: ',',
+            bucket: None,
+        }
+    }
+}
+
+/// A row that wasn't imported and why
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    /// Line the row starts on, the header is line 1
+    pub line: usize,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportSummary {
+    pub rows_loaded: usize,
+    pub rows_rejected: usize,
+    /// At most 100 of the rejections, ordered by line
+    pub rejections: Vec<Rejection>,
+}
+
+impl ImportSummary {
+    /// Duplicates are only found when their batch is written, after later lines may have been rejected,
+    /// so each rejection is inserted by line and the highest line is dropped past the limit
+    fn reject(&mut self, line: usize, reason: String) {
+        self.rows_rejected += 1;
+        let pos = self.rejections.partition_point(|rejection| rejection.line <= line);
+        if pos < MAX_REJECTIONS {
+            self.rejections.insert(pos, Rejection { line, reason });
+            self.rejections.truncate(MAX_REJECTIONS);
+        }
+    }
+}
+
+/// Import a CSV file with a header row into btree
+/// Each key is the tuple of the key columns, packed with tuple::pack so keys sort column by column,
+/// and each value is the tuple of the remaining columns as strings, in header order, so
+/// tuple::unpack gives the record back. Rows that don't parse, don't fit a page or repeat an existing
+/// key are rejected and counted, the rest are committed in batches. In replace mode the rows go
+/// into a new tree that is only swapped in by the last commit, so an import that fails keeps the old entries
+pub fn import_csv<R: BufRead>(btree: &mut BTree, input: R, options: &CsvOptions) -> Result<ImportSummary> {
+    let mut records = CsvReader {
+        input,
+        delimiter: options.delimiter,
+        line: 0,
+    };
+    let header = match records.next_record()? {
+        Some((_, Ok(header))) => header,
+        Some((_, Err(reason))) => return Err(invalid_input(format!("bad header: {}", reason))),
+        None => return Err(invalid_input("the file has no header row".to_string())),
+    };
+    if options.key_columns.is_empty() {
+        return Err(invalid_input("at least one key column is needed".to_string()));
+    }
+    let mut key_positions = vec![];
+    for column in &options.key_columns {
+        let pos = header.iter().position(|name| *name == column.name).ok_or_else(|| invalid_input(format!("no column named {}", column.name)))?;
+        key_positions.push(pos);
+    }
+
+    let bucket = options.bucket.as_deref();
+    if let Some(name) = bucket
+        && options.mode == ImportMode::Append
+        && btree.bucket_root(name)?.is_none()
+    {
+        btree.create_bucket(name)?;
+    }
+    let old_root_offset = match bucket {
+        Some(name) => btree.bucket_root(name)?,
+        None => Some(btree.root_offset),
+    };
+    let mut target = Target {
+        bucket,
+        // the replacement starts out as an empty tree, which has no pages yet
+        root_offset: match options.mode {
+            ImportMode::Append => old_root_offset.unwrap_or(0),
+            ImportMode::Replace => 0,
+        },
+        commit_batches: options.mode == ImportMode::Append,
+    };
+    let imported = import_rows(btree, &mut records, &header, &key_positions, options, &mut target).and_then(|summary| {
+        if options.mode == ImportMode::Replace {
+            swap_in(btree, &mut target, old_root_offset)?;
+        }
+        Ok(summary)
+    });
+    match imported {
+        Ok(summary) => Ok(summary),
+        Err(e) => {
+            btree.disk_manager.rollback_pages();
+            Err(e)
+        }
+    }
+}
+
+/// The tree import_csv writes into and where its root is so far
+struct Target<'a> {
+    /// The bucket the tree belongs to, None for the main tree
+    bucket: Option<&'a str>,
+    root_offset: u64,
+    /// Append commits after every batch, replace only once the whole file is in
+    commit_batches: bool,
+}
+
+/// Parse the rows after the header and write them into target, returns what was loaded and rejected
+fn import_rows<R: BufRead>(
+    btree: &mut BTree,
+    records: &mut CsvReader<R>,
+    header: &[String],
+    key_positions: &[usize],
+    options: &CsvOptions,
+    target: &mut Target,
+) -> Result<ImportSummary> {
+    let value_positions: Vec<usize> = (0..header.len()).filter(|pos| !key_positions.contains(pos)).collect();
+    let config = btree.storage_config.clone();
+    let mut summary = ImportSummary {
+        rows_loaded: 0,
+        rows_rejected: 0,
+        rejections: vec![],
+    };
+    let mut batch = vec![];
+    while let Some((line, record)) = records.next_record()? {
+        let row = record.and_then(|fields| {
+            if fields.len() != header.len() {
+                return Err(format!("expected {} fields, found {}", header.len(), fields.len()));
+            }
+            let key: Vec<Element> = options
+                .key_columns
+                .iter()
+                .zip(key_positions)
+                .map(|(column, &pos)| column.element(&fields[pos]))
+                .collect::<std::result::Result<_, _>>()?;
+            let value: Vec<Element> = value_positions.iter().map(|&pos| Element::String(fields[pos].clone())).collect();
+            let (key, value) = (tuple::pack(&key), tuple::pack(&value));
+            if key.len() > config.max_key_size as usize {
+                return Err(format!("key is {} bytes, longer than the maximum of {}", key.len(), config.max_key_size));
+            }
+            if value.len() > config.max_val_size as usize {
+                return Err(format!("value is {} bytes, longer than the maximum of {}", value.len(), config.max_val_size));
+            }
+            Ok((key, value))
+        });
+        match row {
+            Ok((key, value)) => batch.push((line, key, value)),
+            Err(reason) => summary.reject(line, reason),
+        }
+        if batch.len() == BATCH_SIZE {
+            write_batch(btree, target, &mut batch, &mut summary)?;
+        }
+    }
+    write_batch(btree, target, &mut batch, &mut summary)?;
+    Ok(summary)
+}
+
+/// Insert the rows of batch whose key isn't taken yet, with a single commit unless the target
+/// is only committed at the end
+fn write_batch(btree: &mut BTree, target: &mut Target, batch: &mut Vec<(usize, Vec<u8>, Vec<u8>)>, summary: &mut ImportSummary) -> Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    for (line, key, value) in batch.drain(..) {
+        match btree.put_in_tree(target.root_offset, &key, value, 0, PutMode::InsertNew)? {
+            (Some(new_root_offset), _) => {
+                target.root_offset = new_root_offset;
+                summary.rows_loaded += 1;
+            }
+            (None, _) => summary.reject(line, "duplicate key".to_string()),
+        }
+    }
+    if target.commit_batches {
+        commit_target(btree, target)?;
+    }
+    Ok(())
+}
+
+/// Free the tree rooted at old_root_offset and commit the replacement in its place
+fn swap_in(btree: &mut BTree, target: &mut Target, old_root_offset: Option<u64>) -> Result<()> {
+    if let Some(old_root_offset) = old_root_offset
+        && old_root_offset != 0
+    {
+        let height = btree.tree_height(old_root_offset)?;
+        btree.free_subtree(old_root_offset, height)?;
+    }
+    // the main tree always has a root page, even when it is empty
+    if target.bucket.is_none() && target.root_offset == 0 {
+        target.root_offset = btree.build_tree(&mut std::iter::empty())?.0;
+    }
+    commit_target(btree, target)
+}
+
+/// Commit the target's root, through the catalog for a bucket
+fn commit_target(btree: &mut BTree, target: &Target) -> Result<()> {
+    let (root_offset, mut catalog_offset) = (btree.root_offset, btree.catalog_offset);
+    match target.bucket {
+        Some(name) => {
+            catalog_offset = btree.insert_into_tree(catalog_offset, name.as_bytes(), &bucket::encode_root(target.root_offset))?.0;
+            btree.commit(root_offset, catalog_offset)
+        }
+        None => btree.commit(target.root_offset, catalog_offset),
+    }
+}
+
+/// Reads records of RFC 4180 CSV, fields may be quoted with "" standing for a quote inside them
+/// and quoted fields may span lines
+struct CsvReader<R> {
+    input: R,
+    delimiter: char,
+    line: usize,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    /// The next record and the line it starts on, skipping blank lines
+    /// a malformed record is returned as the reason it was rejected, reading goes on after it
+    fn next_record(&mut self) -> Result<Option<(usize, Record)>> {
+        let mut text = String::new();
+        loop {
+            text.clear();
+            if self.input.read_line(&mut text)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            if !text.trim_end_matches(['\r', '\n']).is_empty() {
+                break;
+            }
+        }
+        let start = self.line;
+
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut in_quotes = false;
+        let mut was_quoted = false;
+        let mut error = None;
+        loop {
+            let mut chars = text.trim_end_matches(['\r', '\n']).chars().peekable();
+            while let Some(c) = chars.next() {
+                if in_quotes {
+                    match c {
+                        '"' if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        '"' => in_quotes = false,
+                        c => field.push(c),
+                    }
+                } else if c == self.delimiter {
+                    fields.push(std::mem::take(&mut field));
+                    was_quoted = false;
+                } else if c == '"' && field.is_empty() && !was_quoted {
+                    in_quotes = true;
+                    was_quoted = true;
+                } else if was_quoted {
+                    error.get_or_insert_with(|| "text after a closing quote".to_string());
+                } else if c == '"' {
+                    error.get_or_insert_with(|| "quote inside an unquoted field".to_string());
+                } else {
+                    field.push(c);
+                }
+            }
+            if !in_quotes {
+                break;
+            }
+            // the newline belongs to the quoted field
+            field.push('\n');
+            text.clear();
+            if self.input.read_line(&mut text)? == 0 {
+                error.get_or_insert_with(|| "unterminated quoted field".to_string());
+                break;
+            }
+            self.line += 1;
+        }
+        fields.push(field);
+        Ok(Some((start, error.map_or(Ok(fields), Err))))
+    }
+}
+
+fn invalid_input(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn key(columns: &[Element]) -> Vec<u8> {
+        tuple::pack(columns)
+    }
+
+    fn record(fields: &[&str]) -> Vec<u8> {
+        tuple::pack(&fields.iter().map(|field| Element::from(*field)).collect::<Vec<_>>())
+    }
+
+    fn options(specs: &[&str]) -> CsvOptions {
+        CsvOptions::new(specs.iter().map(|spec| KeyColumn::parse(spec).unwrap()).collect())
+    }
+
+    #[test]
+    fn test_import_csv() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), None).unwrap();
+        let csv = "region,id,name,note\r\neu,10,\"Smith, Jo\",\"said \"\"hi\"\"\"\r\n\neu,9,Ann,\"two\nlines\"\nus,1,Bob,\n";
+        let summary = import_csv(&mut btree, csv.as_bytes(), &options(&["region", "id:uint"])).unwrap();
+        assert_eq!(summary.rows_loaded, 3);
+        assert_eq!(summary.rows_rejected, 0);
+
+        // typed key columns sort by value, 9 before 10
+        let entries: Vec<_> = btree.range(..).map(|r| r.unwrap()).collect();
+        assert_eq!(entries, vec![
+            (key(&["eu".into(), 9u64.into()]), record(&["Ann", "two\nlines"])),
+            (key(&["eu".into(), 10u64.into()]), record(&["Smith, Jo", "said \"hi\""])),
+            (key(&["us".into(), 1u64.into()]), record(&["Bob", ""])),
+        ]);
+        assert_eq!(tuple::unpack(&entries[0].1).unwrap(), vec![Element::from("Ann"), Element::from("two\nlines")]);
+    }
+
+    #[test]
+    fn test_import_csv_rejects_rows() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), None).unwrap();
+        let csv = "id;value\n1;a\nx;b\n2;c;extra\n1;again\n3;bad\"quote\n\"4\";\"open\n";
+        let mut options = options(&["id:int"]);
+        options.delimiter = ';';
+        let summary = import_csv(&mut btree, csv.as_bytes(), &options).unwrap();
+        assert_eq!(summary.rows_loaded, 1);
+        assert_eq!(summary.rows_rejected, 5);
+        let lines: Vec<_> = summary.rejections.iter().map(|rejection| rejection.line).collect();
+        assert_eq!(lines, vec![3, 4, 5, 6, 7]);
+        assert_eq!(summary.rejections[0].reason, "x is not a valid int for column id");
+        assert_eq!(summary.rejections[1].reason, "expected 2 fields, found 3");
+        assert_eq!(summary.rejections[2].reason, "duplicate key");
+        assert_eq!(summary.rejections[4].reason, "unterminated quoted field");
+
+        // the duplicate on line 3 is only found once its batch is written, after 150 later rows were rejected
+        let mut csv = "id;value\n10;a\n10;b\n".to_string();
+        for _ in 0..150 {
+            csv.push_str("x;bad\n");
+        }
+        let summary = import_csv(&mut btree, csv.as_bytes(), &options).unwrap();
+        assert_eq!(summary.rows_rejected, 151);
+        let lines: Vec<_> = summary.rejections.iter().map(|rejection| rejection.line).collect();
+        assert_eq!(lines, (3..103).collect::<Vec<_>>());
+        assert_eq!(summary.rejections[0].reason, "duplicate key");
+
+        assert_eq!(import_csv(&mut btree, csv.as_bytes(), &CsvOptions::new(vec![KeyColumn::parse("missing").unwrap()])).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(KeyColumn::parse("id:date").is_err());
+    }
+
+    #[test]
+    fn test_import_csv_modes() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), None).unwrap();
+        let mut options = options(&["id"]);
+        options.bucket = Some("people".to_string());
+        import_csv(&mut btree, "id,name\na,Ann\nb,Bob\n".as_bytes(), &options).unwrap();
+
+        // append keeps what is there and rejects keys that are taken
+        let summary = import_csv(&mut btree, "id,name\nb,Bea\nc,Cal\n".as_bytes(), &options).unwrap();
+        assert_eq!((summary.rows_loaded, summary.rows_rejected), (1, 1));
+        assert_eq!(btree.open_bucket("people").unwrap().range(..).unwrap().count(), 3);
+
+        options.mode = ImportMode::Replace;
+        let summary = import_csv(&mut btree, "id,name\nb,Bea\n".as_bytes(), &options).unwrap();
+        assert_eq!((summary.rows_loaded, summary.rows_rejected), (1, 0));
+        let mut people = btree.open_bucket("people").unwrap();
+        assert_eq!(people.range(..).unwrap().count(), 1);
+        assert_eq!(people.get(&key(&["b".into()])).unwrap(), Some(record(&["Bea"])));
+    }
+
+    #[test]
+    fn test_import_csv_replace_is_one_commit() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), None).unwrap();
+        import_csv(&mut btree, "id,name\na,Ann\nb,Bob\n".as_bytes(), &options(&["id"])).unwrap();
+        let before: Vec<_> = btree.range(..).map(|r| r.unwrap()).collect();
+        let commit_id = btree.commit_id;
+
+        // more than a batch of rows, then a line that isn't utf8 makes the read fail
+        let mut options = options(&["id:uint"]);
+        options.mode = ImportMode::Replace;
+        let mut csv = b"id,name\n".to_vec();
+        for i in 0..BATCH_SIZE + 10 {
+            csv.extend(format!("{},row\n", i).bytes());
+        }
+        csv.extend(b"1,\xff\xfe\n");
+        assert_eq!(import_csv(&mut btree, csv.as_slice(), &options).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(btree.commit_id, commit_id);
+        assert_eq!(btree.range(..).map(|r| r.unwrap()).collect::<Vec<_>>(), before);
+
+        let summary = import_csv(&mut btree, "id,name\n7,Gil\n".as_bytes(), &options).unwrap();
+        assert_eq!((summary.rows_loaded, summary.rows_rejected), (1, 0));
+        assert_eq!(btree.commit_id, commit_id + 1);
+        let entries: Vec<_> = btree.range(..).map(|r| r.unwrap()).collect();
+        assert_eq!(entries, vec![(key(&[7u64.into()]), record(&["Gil"]))]);
+
+        // a file with no rows leaves an empty tree
+        import_csv(&mut btree, "id,name\n".as_bytes(), &options).unwrap();
+        assert_eq!(btree.range(..).count(), 0);
+        btree.insert(b"x".to_vec(), b"1".to_vec()).unwrap();
+    }
+}
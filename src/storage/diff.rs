@@ -0,0 +1,224 @@
+use crate::storage::btree::now_millis;
+use crate::storage::diskmanager::DiskManager;
+use std::cmp::Ordering;
+use std::io::Result;
+
+/// A difference between two trees, going from the first tree to the second
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffEvent {
+    Added(Vec<u8>, Vec<u8>),
+    Removed(Vec<u8>, Vec<u8>),
+    Changed(Vec<u8>, Vec<u8>, Vec<u8>), // key, old value, new value
+}
+
+/// Something still to be compared on one side of the diff
+enum Item {
+    // a subtree that hasn't been read yet, with the lowest key it can hold
+    Page {
+        offset: u64,
+        height: usize,
+        low: Option<Vec<u8>>,
+    },
+    Entry(Vec<u8>, Vec<u8>),
+}
+
+/// Iterator over the differences between two trees in key order
+/// Subtrees both trees point at are skipped without being read, so the cost follows the size of
+/// the change rather than the size of the trees
+pub struct DiffIter<'a> {
+    disk_manager: &'a mut DiskManager,
+    // items still to visit on each side, the next one is at the end
+    a: Vec<Item>,
+    b: Vec<Item>,
+    now: u64,
+}
+
+impl<'a> DiffIter<'a> {
+    /// roots are (offset, height) pairs, offset 0 is an empty tree
+    pub(crate) fn new(disk_manager: &'a mut DiskManager, root_a: (u64, usize), root_b: (u64, usize)) -> Self {
+        let side = |(offset, height): (u64, usize)| {
+            if offset == 0 {
+                vec![]
+            } else {
+                vec![Item::Page { offset, height, low: None }]
+            }
+        };
+        Self {
+            disk_manager,
+            a: side(root_a),
+            b: side(root_b),
+            now: now_millis(),
+        }
+    }
+
+    /// Replace the page at the end of a side with its children or entries
+    fn expand(disk_manager: &mut DiskManager, side: &mut Vec<Item>, now: u64) -> Result<()> {
+        let Some(Item::Page { offset, height, low }) = side.pop() else {
+            return Ok(());
+        };
+        let node = disk_manager.load_node_from_disk(offset)?;
+        if height == 0 {
+            // leaf, pushed in reverse so the smallest key ends up last
+            for pos in (0..node.keys.len()).rev() {
+                if !node.is_expired(pos, now) {
+                    side.push(Item::Entry(node.keys[pos].clone(), node.values[pos].clone()));
+                }
+            }
+        } else {
+            for pos in (0..node.children.len()).rev() {
+                let low = if pos == 0 { low.clone() } else { Some(node.keys[pos - 1].clone()) };
+                side.push(Item::Page {
+                    offset: node.children[pos],
+                    height: height - 1,
+                    low,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn advance(&mut self) -> Result<Option<DiffEvent>> {
+        loop {
+            let event = match (self.a.last(), self.b.last()) {
+                (None, None) => return Ok(None),
+                (Some(Item::Page { .. }), None) => {
+                    Self::expand(self.disk_manager, &mut self.a, self.now)?;
+                    continue;
+                }
+                (None, Some(Item::Page { .. })) => {
+                    Self::expand(self.disk_manager, &mut self.b, self.now)?;
+                    continue;
+                }
+                (Some(Item::Entry(..)), None) => {
+                    let Some(Item::Entry(key, value)) = self.a.pop() else { unreachable!() };
+                    DiffEvent::Removed(key, value)
+                }
+                (None, Some(Item::Entry(..))) => {
+                    let Some(Item::Entry(key, value)) = self.b.pop() else { unreachable!() };
+                    DiffEvent::Added(key, value)
+                }
+                (Some(Item::Page { offset: offset_a, height: height_a, .. }), Some(Item::Page { offset: offset_b, height: height_b, .. })) => {
+                    if offset_a == offset_b {
+                        // the same page covers the same keys on both sides
+                        self.a.pop();
+                        self.b.pop();
+                    } else {
+                        // bring the taller side down first so equal subtrees line up
+                        let (height_a, height_b) = (*height_a, *height_b);
+                        if height_a >= height_b {
+                            Self::expand(self.disk_manager, &mut self.a, self.now)?;
+                        }
+                        if height_b >= height_a {
+                            Self::expand(self.disk_manager, &mut self.b, self.now)?;
+                        }
+                    }
+                    continue;
+                }
+                (Some(Item::Entry(key, _)), Some(Item::Page { low, .. })) => {
+                    // the page only holds keys from low onwards, anything before it is gone from b
+                    if low.as_ref().is_some_and(|low| key < low) {
+                        let Some(Item::Entry(key, value)) = self.a.pop() else { unreachable!() };
+                        DiffEvent::Removed(key, value)
+                    } else {
+                        Self::expand(self.disk_manager, &mut self.b, self.now)?;
+                        continue;
+                    }
+                }
+                (Some(Item::Page { low, .. }), Some(Item::Entry(key, _))) => {
+                    if low.as_ref().is_some_and(|low| key < low) {
+                        let Some(Item::Entry(key, value)) = self.b.pop() else { unreachable!() };
+                        DiffEvent::Added(key, value)
+                    } else {
+                        Self::expand(self.disk_manager, &mut self.a, self.now)?;
+                        continue;
+                    }
+                }
+                (Some(Item::Entry(key_a, value_a)), Some(Item::Entry(key_b, value_b))) => match key_a.cmp(key_b) {
+                    Ordering::Less => {
+                        let Some(Item::Entry(key, value)) = self.a.pop() else { unreachable!() };
+                        DiffEvent::Removed(key, value)
+                    }
+                    Ordering::Greater => {
+                        let Some(Item::Entry(key, value)) = self.b.pop() else { unreachable!() };
+                        DiffEvent::Added(key, value)
+                    }
+                    Ordering::Equal => {
+                        let changed = value_a != value_b;
+                        let Some(Item::Entry(key, old)) = self.a.pop() else { unreachable!() };
+                        let Some(Item::Entry(_, new)) = self.b.pop() else { unreachable!() };
+                        if !changed {
+                            continue;
+                        }
+                        DiffEvent::Changed(key, old, new)
+                    }
+                },
+            };
+            return Ok(Some(event));
+        }
+    }
+}
+
+impl Iterator for DiffIter<'_> {
+    type Item = Result<DiffEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.advance() {
+            Ok(event) => event.map(Ok),
+            Err(e) => {
+                // stop after the first error
+                self.a.clear();
+                self.b.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::BTree;
+    use crate::storage::testing::{key, small_page_config};
+    use tempfile::NamedTempFile;
+
+    fn get_temp_btree() -> BTree {
+        let tmp = NamedTempFile::new().unwrap();
+        BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap()
+    }
+
+    #[test]
+    fn test_diff_between_snapshots() {
+        let mut btree = get_temp_btree();
+        for i in 0..200 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+        }
+        btree.create_snapshot("before").unwrap();
+        let before = btree.list_snapshots().unwrap()[0].1.root_offset;
+
+        btree.insert(key(50), b"w".to_vec()).unwrap();
+        btree.delete(&key(120)).unwrap();
+        btree.insert(b"zzz".to_vec(), b"new".to_vec()).unwrap();
+
+        let events: Vec<DiffEvent> = btree.diff(before, btree.root_offset).unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(events, vec![
+            DiffEvent::Changed(key(50), b"v".to_vec(), b"w".to_vec()),
+            DiffEvent::Removed(key(120), b"v".to_vec()),
+            DiffEvent::Added(b"zzz".to_vec(), b"new".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_diff_identical_and_empty_trees() {
+        let mut btree = get_temp_btree();
+        for i in 0..50 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+        }
+        let root = btree.root_offset;
+        assert_eq!(btree.diff(root, root).unwrap().count(), 0);
+
+        // against an empty tree every key shows up
+        let added: Vec<DiffEvent> = btree.diff(0, root).unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(added, (0..50).map(|i| DiffEvent::Added(key(i), b"v".to_vec())).collect::<Vec<_>>());
+        assert_eq!(btree.diff(root, 0).unwrap().count(), 50);
+    }
+}
@@ -1,9 +1,21 @@
 use crate::storage::configs::{StorageConfig};
+use crate::storage::inspect::{self, PageLayout};
 use crate::storage::node::{Node};
+use std::collections::HashSet;
 use std::fs::{File, OpenOptions};
-use std::io::{ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
 use std::path::Path;
 
+/// Contents of the metadata page
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metadata {
+    pub root_offset: u64,
+    pub catalog_offset: u64, // root of the bucket catalog, 0 if no bucket was ever created
+    pub commit_id: u64, // incremented by every commit
+    pub history_offset: u64, // root of the commit history, 0 if no history is retained
+    pub timestamp: u64, // unix millis of the last commit, 0 if unknown or the page has no room for it
+}
+
 #[derive(Debug)]
 pub enum EncodeResult {
     Encoded,
@@ -14,6 +26,19 @@ pub enum EncodeResult {
 pub struct DiskManager {
     pub file: File,
     pub config: StorageConfig,
+    // pages no committed tree refers to, handed out again by get_new_offset
+    free_pages: Vec<u64>,
+    // pages unlinked since the last commit, the committed trees may still refer to them
+    pending_free: Vec<u64>,
+    // pages unlinked by an earlier commit that retained history may still refer to,
+    // paired with the id of the commit that unlinked them
+    retired: Vec<(u64, u64)>,
+    // pages handed out since the last commit
+    allocated: Vec<u64>,
+    // pages of the commits named snapshots pin
+    snapshot_pages: HashSet<u64>,
+    // pages no retained commit refers to that are kept for a named snapshot
+    held: Vec<u64>,
 }
 
 impl DiskManager {
@@ -28,21 +53,39 @@ impl DiskManager {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(path)?;
 
         let metadata = file.metadata()?;
         let is_new_file = metadata.len() == 0;
 
-        let mut disk_manager = Self { file, config };
+        let mut disk_manager = Self {
+            file,
+            config,
+            free_pages: vec![],
+            pending_free: vec![],
+            retired: vec![],
+            allocated: vec![],
+            snapshot_pages: HashSet::new(),
+            held: vec![],
+        };
 
         if is_new_file {
             // write root_offset to metadata block
-            disk_manager.write_metadata(disk_manager.config.first_page_offset)?;
+            let metadata = Metadata {
+                root_offset: disk_manager.config.first_page_offset,
+                catalog_offset: 0,
+                commit_id: 0,
+                history_offset: 0,
+                timestamp: 0,
+            };
+            disk_manager.write_metadata(&metadata)?;
 
             let root = Node {
                 keys: vec![],
                 values: vec![],
                 children: vec![],
+                expiries: vec![],
             };
 
             // write empty root to root_offset
@@ -52,24 +95,64 @@ impl DiskManager {
         Ok(disk_manager)
     }
 
+    /// Open an existing file for reading only, nothing is created or written
+    /// fails if the file is too short to hold the metadata page
+    pub fn open_read_only(path: &str, config: StorageConfig) -> Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        if file.metadata()?.len() < config.metadata_offset + 32 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} is too short to hold the metadata page", path)));
+        }
+        Ok(Self {
+            file,
+            config,
+            free_pages: vec![],
+            pending_free: vec![],
+            retired: vec![],
+            allocated: vec![],
+            snapshot_pages: HashSet::new(),
+            held: vec![],
+        })
+    }
+
     /// read page 0 which is the metadata page
-    /// first 8 bytes are the root offset, rest is currently unused
-    /// returns the root offset
-    pub fn read_metadata(&mut self) -> Result<u64> {
-        let mut buf = [0u8; 8];
+    /// root offset, catalog offset, commit id and history offset, 8 bytes each, then the commit
+    /// timestamp on pages of at least 40 bytes, rest is currently unused
+    pub fn read_metadata(&mut self) -> Result<Metadata> {
+        let mut buf = vec![0u8; if self.config.page_size >= 40 { 40 } else { 32 }];
         self.file.seek(SeekFrom::Start(self.config.metadata_offset))?;
         match self.file.read_exact(&mut buf) {
-            Ok(_) => Ok(u64::from_le_bytes(buf)),
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
+            Ok(_) => {
+                let read = |at: usize| u64::from_le_bytes(buf[at..at + 8].try_into().unwrap());
+                Ok(Metadata {
+                    root_offset: read(0),
+                    catalog_offset: read(8),
+                    commit_id: read(16),
+                    history_offset: read(24),
+                    timestamp: if buf.len() >= 40 { read(32) } else { 0 },
+                })
+            }
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(Metadata {
+                root_offset: 0,
+                catalog_offset: 0,
+                commit_id: 0,
+                history_offset: 0,
+                timestamp: 0,
+            }),
             Err(e) => Err(e),
         }
     }
 
-    /// write a new root_offset to metadata page
-    /// root_offset is where the root page is located on disk
-    pub fn write_metadata(&mut self, root_offset: u64) -> Result<()> {
+    /// write new root offsets to metadata page
+    /// the whole page is written at once so every root switches over together
+    pub fn write_metadata(&mut self, metadata: &Metadata) -> Result<()> {
         let mut block = vec![0u8; self.config.page_size as usize];
-        block[..8].copy_from_slice(&root_offset.to_le_bytes());
+        block[..8].copy_from_slice(&metadata.root_offset.to_le_bytes());
+        block[8..16].copy_from_slice(&metadata.catalog_offset.to_le_bytes());
+        block[16..24].copy_from_slice(&metadata.commit_id.to_le_bytes());
+        block[24..32].copy_from_slice(&metadata.history_offset.to_le_bytes());
+        if block.len() >= 40 {
+            block[32..40].copy_from_slice(&metadata.timestamp.to_le_bytes());
+        }
         self.file.seek(SeekFrom::Start(self.config.metadata_offset))?;
         self.file.write_all(&block)?;
         self.file.sync_all()?;
@@ -78,19 +161,36 @@ impl DiskManager {
 
     /// Load a node from disk into memory, given the page offset
     pub fn load_node_from_disk(&mut self, offset: u64) -> Result<Node> {
+        let buf = self.read_page(offset)?;
+        Node::try_decode_node(&buf)
+    }
+
+    /// Header, child pointers, slots and cells of the page at offset with the byte range of each
+    /// flags overlapping or out of bounds parts instead of failing like load_node_from_disk
+    pub fn inspect_page(&mut self, offset: u64) -> Result<PageLayout> {
+        let bytes = self.read_page(offset)?;
+        let mut layout = inspect::inspect(offset, bytes);
+        if offset < self.config.first_page_offset {
+            layout.problems.insert(0, "offset is before the first page".to_string());
+        } else if !(offset - self.config.first_page_offset).is_multiple_of(self.config.page_size as u64) {
+            layout.problems.insert(0, "offset is not at a page boundary".to_string());
+        }
+        Ok(layout)
+    }
+
+    /// Raw bytes of the page at offset
+    pub fn read_page(&mut self, offset: u64) -> Result<Vec<u8>> {
         let mut buf = vec![0u8; self.config.page_size as usize];
         self.file.seek(SeekFrom::Start(offset))?;
         self.file.read_exact(&mut buf)?;
-        Ok(Node::decode_node(buf))
+        Ok(buf)
     }
 
     /// Write the node from memory to disk
     pub fn append_node_to_disk(&mut self, offset: u64, node: &Node) -> EncodeResult {
         match Node::encode_node(node, self.config.clone()) {
             Some(encoded) => {
-                self.file.seek(SeekFrom::Start(offset)).unwrap();
-                self.file.write_all(&encoded).unwrap();
-                self.file.sync_all().unwrap();
+                self.write_page(offset, &encoded).unwrap();
                 EncodeResult::Encoded
             }
             None => {
@@ -99,8 +199,109 @@ impl DiskManager {
         }
     }
 
+    /// Write the node from memory to disk, failing if it doesn't fit in a page
+    pub fn write_node(&mut self, offset: u64, node: &Node) -> Result<()> {
+        match self.append_node_to_disk(offset, node) {
+            EncodeResult::Encoded => Ok(()),
+            EncodeResult::NeedSplit => Err(Error::new(ErrorKind::InvalidInput, format!("node with {} keys does not fit in a page", node.keys.len()))),
+        }
+    }
+
+    /// Write the raw bytes of a page, used for pages edited in place as a SlottedPage
+    pub fn write_page(&mut self, offset: u64, page: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(page)?;
+        self.file.sync_all()
+    }
+
     /// Get a new offset
+    /// reuses a free page if there is one, otherwise the file grows by a page
     pub fn get_new_offset(&mut self) -> Result<u64> {
-        self.file.seek(SeekFrom::End(0))
+        let offset = match self.free_pages.pop() {
+            Some(offset) => offset,
+            None => self.file.seek(SeekFrom::End(0))?,
+        };
+        self.allocated.push(offset);
+        Ok(offset)
+    }
+
+    /// Mark a page as no longer used by the trees being written
+    /// the page only becomes reusable once the change that unlinked it is committed
+    pub fn free_page(&mut self, offset: u64) {
+        if offset != 0 {
+            self.pending_free.push(offset);
+        }
+    }
+
+    /// Called after the metadata page was written for commit_id
+    /// pages unlinked by a commit can be reused once no retained commit older than it is left,
+    /// oldest_retained is the id of the oldest commit that can still be read
+    pub fn commit_pages(&mut self, commit_id: u64, oldest_retained: u64) {
+        self.retired.extend(self.pending_free.drain(..).map(|offset| (offset, commit_id)));
+        self.allocated.clear();
+
+        let (reusable, retired): (Vec<_>, Vec<_>) = self.retired
+            .drain(..)
+            .partition(|&(_, freed_at)| freed_at <= oldest_retained);
+        self.retired = retired;
+        for (offset, _) in reusable {
+            match self.snapshot_pages.contains(&offset) {
+                true => self.held.push(offset),
+                false => self.free_pages.push(offset),
+            }
+        }
+    }
+
+    /// Replace the pages named snapshots pin, held pages no snapshot refers to anymore become reusable
+    pub fn set_snapshot_pages(&mut self, pages: HashSet<u64>) {
+        let (held, released): (Vec<_>, Vec<_>) = self.held.drain(..).partition(|offset| pages.contains(offset));
+        self.held = held;
+        self.free_pages.extend(released);
+        self.snapshot_pages = pages;
+    }
+
+    /// Called when uncommitted changes are thrown away
+    /// pages they unlinked are still in use, pages they wrote never will be
+    /// offsets taken at the end of the file but never written are dropped, the end hands them out again
+    pub fn rollback_pages(&mut self) {
+        self.pending_free.clear();
+        let file_len = self.file_size().unwrap_or(0);
+        let allocated = std::mem::take(&mut self.allocated);
+        self.free_pages.extend(allocated.into_iter().filter(|&offset| offset < file_len));
+    }
+
+    /// Take pages that are in use again off the free list and the retired pages
+    pub fn keep_pages(&mut self, pages: &HashSet<u64>) {
+        self.free_pages.retain(|offset| !pages.contains(offset));
+        self.retired.retain(|(offset, _)| !pages.contains(offset));
+        self.pending_free.retain(|offset| !pages.contains(offset));
+        self.held.retain(|offset| !pages.contains(offset));
+    }
+
+    /// Replace the free list, the retired pages and the pages held for named snapshots, used when opening a file
+    pub fn set_free_pages(&mut self, free_pages: Vec<u64>, retired: Vec<(u64, u64)>, held: Vec<u64>) {
+        self.free_pages = free_pages;
+        self.retired = retired;
+        self.held = held;
+    }
+
+    pub fn free_page_count(&self) -> usize {
+        self.free_pages.len()
+    }
+
+    /// Number of unlinked pages kept because retained commits or named snapshots may still refer to them
+    pub fn retired_page_count(&self) -> usize {
+        self.retired.len() + self.held.len()
+    }
+
+    /// Length of the file in bytes
+    pub fn file_size(&mut self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Offsets of every page slot in the file after the metadata page
+    pub fn page_offsets(&mut self) -> Result<Vec<u64>> {
+        let file_len = self.file.seek(SeekFrom::End(0))?;
+        Ok((self.config.first_page_offset..file_len).step_by(self.config.page_size as usize).collect())
     }
 }
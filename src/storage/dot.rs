@@ -0,0 +1,97 @@
+use crate::storage::btree::BTree;
+use crate::text;
+use std::fmt::Write;
+use std::io::Result;
+
+// keys listed per node, the rest are only counted
+const MAX_KEYS_SHOWN: usize = 8;
+// characters of an escaped key shown before it is cut off
+const MAX_KEY_CHARS: usize = 16;
+
+/// Render the main tree as a Graphviz digraph, one box per page labelled with its offset and keys
+/// Nodes deeper than max_depth are left out, the root is at depth 0
+pub(crate) fn render(btree: &mut BTree, max_depth: usize) -> Result<String> {
+    let mut dot = String::from("digraph btree {\n    node [shape=box, fontname=\"monospace\"];\n");
+    // offset and depth of the pages still to be drawn
+    let mut pending = vec![(btree.root_offset, 0)];
+    while let Some((offset, depth)) = pending.pop() {
+        let node = btree.load_root(offset)?;
+        let mut label = match node.children.is_empty() {
+            true => format!("@{} leaf, {} keys", offset, node.keys.len()),
+            false => format!("@{} internal, {} children", offset, node.children.len()),
+        };
+        for key in node.keys.iter().take(MAX_KEYS_SHOWN) {
+            label.push('\n');
+            label.push_str(&shorten(&text::escape(key)));
+        }
+        if node.keys.len() > MAX_KEYS_SHOWN {
+            write!(label, "\n{} more", node.keys.len() - MAX_KEYS_SHOWN).unwrap();
+        }
+        if !node.children.is_empty() && depth == max_depth {
+            label.push_str("\nchildren not shown");
+        }
+        writeln!(dot, "    n{} [label=\"{}\"];", offset, dot_escape(&label)).unwrap();
+
+        if depth < max_depth {
+            for child_offset in &node.children {
+                writeln!(dot, "    n{} -> n{};", offset, child_offset).unwrap();
+            }
+            // reversed so children come off the stack left to right
+            pending.extend(node.children.iter().rev().map(|&child_offset| (child_offset, depth + 1)));
+        }
+    }
+    dot.push_str("}\n");
+    Ok(dot)
+}
+
+fn shorten(key: &str) -> String {
+    match key.char_indices().nth(MAX_KEY_CHARS) {
+        Some((end, _)) => format!("{}..", &key[..end]),
+        None => key.to_string(),
+    }
+}
+
+/// Escape a label for a double quoted DOT string, newlines become centered line breaks
+fn dot_escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::testing::{key, small_page_config};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_to_dot() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        for i in 0..100 {
+            btree.insert(key(i), vec![]).unwrap();
+        }
+        let stats = btree.stats().unwrap();
+        let dot = btree.to_dot(usize::MAX).unwrap();
+        assert!(dot.starts_with("digraph btree {\n"));
+        assert!(dot.ends_with("}\n"));
+        let pages = stats.internal_pages + stats.leaf_pages;
+        assert_eq!(dot.matches(" [label=").count(), pages);
+        assert_eq!(dot.matches(" -> ").count(), pages - 1);
+        assert!(dot.contains(&format!("n{} [label=\"@{} internal", btree.root_offset, btree.root_offset)));
+
+        // only the root and its children
+        let dot = btree.to_dot(1).unwrap();
+        assert_eq!(dot.matches(" [label=").count(), 1 + btree.root.children.len());
+        assert_eq!(dot.matches("children not shown").count(), usize::from(stats.height > 2) * btree.root.children.len());
+    }
+
+    #[test]
+    fn test_to_dot_escapes_keys() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        btree.insert(b"say \"hi\"\n".to_vec(), vec![]).unwrap();
+        btree.insert(b"a very long key that gets cut".to_vec(), vec![]).unwrap();
+        let dot = btree.to_dot(0).unwrap();
+        assert!(dot.contains("\\na very long key ..\\n"));
+        assert!(dot.contains("\\nsay \\\"hi\\\"\\\\x0a\"];"));
+    }
+}
@@ -0,0 +1,300 @@
+use crate::text::{self, json_quote, Format};
+use std::io::{BufRead, Error, ErrorKind, Lines, Result, Write};
+
+// version written in the header line of a JSON Lines dump
+const DUMP_VERSION: u64 = 1;
+
+/// Line oriented text formats for the entries of a tree
+/// Neither depends on the page size or the file format, so a dump can be loaded into any database
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+    /// A {"boxerdb_dump":1} header, then one {"key":..,"value":..} object per line
+    /// Keys and values that aren't valid UTF-8 are written as key_base64 or value_base64 instead
+    JsonLines,
+    /// key<TAB>value lines without a header, both fields written in the given format
+    Tsv(Format),
+}
+
+/// Write entries to out in format, returns how many were written
+pub fn write_dump<I>(out: &mut dyn Write, format: DumpFormat, entries: I) -> Result<usize>
+where
+    I: IntoIterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+{
+    if format == DumpFormat::JsonLines {
+        writeln!(out, "{{\"boxerdb_dump\":{}}}", DUMP_VERSION)?;
+    }
+    let mut count = 0;
+    for entry in entries {
+        let (key, value) = entry?;
+        match format {
+            DumpFormat::JsonLines => writeln!(out, "{{{},{}}}", json_field("key", &key), json_field("value", &value))?,
+            DumpFormat::Tsv(fields) => writeln!(out, "{}\t{}", fields.encode(&key), fields.encode(&value))?,
+        }
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// Read the entries of a dump back in the order they were written
+pub fn read_dump<R: BufRead>(input: R, format: DumpFormat) -> DumpReader<R> {
+    DumpReader {
+        lines: input.lines(),
+        format,
+        line: 0,
+    }
+}
+
+/// Iterator over the entries of a dump, see read_dump
+pub struct DumpReader<R> {
+    lines: Lines<R>,
+    format: DumpFormat,
+    line: usize,
+}
+
+impl<R: BufRead> DumpReader<R> {
+    fn parse(&self, line: &str) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match self.format {
+            DumpFormat::JsonLines => {
+                let fields = parse_object(line)?;
+                if let Some(version) = fields.iter().find(|(name, _)| name == "boxerdb_dump").map(|(_, value)| value) {
+                    if self.line != 1 || *version != Json::Number(DUMP_VERSION) {
+                        return Err(invalid_data(format!("unsupported dump header {}", line)));
+                    }
+                    return Ok(None);
+                }
+                Ok(Some((bytes_field(&fields, "key")?, bytes_field(&fields, "value")?)))
+            }
+            DumpFormat::Tsv(fields) => {
+                let (key, value) = line.split_once('\t').ok_or_else(|| invalid_data("not key<TAB>value".to_string()))?;
+                Ok(Some((fields.decode(key)?, fields.decode(value)?)))
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for DumpReader<R> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match self.parse(&line) {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(Error::new(e.kind(), format!("line {}: {}", self.line, e)))),
+            }
+        }
+    }
+}
+
+/// "name":"text" for UTF-8, "name_base64":"..." for anything else
+fn json_field(name: &str, bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => format!("\"{}\":{}", name, json_quote(s)),
+        Err(_) => format!("\"{}_base64\":\"{}\"", name, text::to_base64(bytes)),
+    }
+}
+
+fn bytes_field(fields: &[(String, Json)], name: &str) -> Result<Vec<u8>> {
+    let base64_name = format!("{}_base64", name);
+    for (field, value) in fields {
+        match value {
+            Json::String(s) if *field == name => return Ok(s.clone().into_bytes()),
+            Json::String(s) if *field == base64_name => return text::from_base64(s),
+            _ => {}
+        }
+    }
+    Err(invalid_data(format!("missing string field {} or {}", name, base64_name)))
+}
+
+/// The JSON values a dump line can hold, anything else is rejected
+#[derive(Debug, PartialEq)]
+enum Json {
+    String(String),
+    Number(u64),
+}
+
+/// Parse a flat JSON object whose values are strings or unsigned integers
+fn parse_object(line: &str) -> Result<Vec<(String, Json)>> {
+    let mut input = JsonInput { rest: line.trim() };
+    let mut fields = vec![];
+    input.expect('{')?;
+    if !input.eat('}') {
+        loop {
+            let name = input.string()?;
+            input.expect(':')?;
+            let value = match input.peek() {
+                Some('"') => Json::String(input.string()?),
+                _ => Json::Number(input.number()?),
+            };
+            fields.push((name, value));
+            if input.eat('}') {
+                break;
+            }
+            input.expect(',')?;
+        }
+    }
+    if !input.rest.is_empty() {
+        return Err(invalid_data("trailing characters after object".to_string()));
+    }
+    Ok(fields)
+}
+
+struct JsonInput<'a> {
+    rest: &'a str,
+}
+
+impl JsonInput<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.rest = self.rest.trim_start();
+        self.rest.chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.rest = &self.rest[1..];
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.eat(c) {
+            return Ok(());
+        }
+        Err(invalid_data(format!("expected {}", c)))
+    }
+
+    fn number(&mut self) -> Result<u64> {
+        self.peek();
+        let end = self.rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(self.rest.len());
+        let number = self.rest[..end].parse().map_err(|_| invalid_data("expected a string or an unsigned integer".to_string()))?;
+        self.rest = &self.rest[end..];
+        Ok(number)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        let mut chars = self.rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 1..];
+                    return Ok(s);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some(c @ ('"' | '\\' | '/')) => c,
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let high = hex4(&mut chars)?;
+                            // characters outside the basic plane are written as a surrogate pair
+                            let code = if (0xd800..0xdc00).contains(&high) {
+                                if chars.next().map(|(_, c)| c) != Some('\\') || chars.next().map(|(_, c)| c) != Some('u') {
+                                    return Err(invalid_data("unpaired surrogate in string".to_string()));
+                                }
+                                let low = hex4(&mut chars)?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(invalid_data("unpaired surrogate in string".to_string()));
+                                }
+                                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                            } else {
+                                high
+                            };
+                            char::from_u32(code).ok_or_else(|| invalid_data("bad \\u escape in string".to_string()))?
+                        }
+                        _ => return Err(invalid_data("bad escape in string".to_string())),
+                    };
+                    s.push(escaped);
+                }
+                c => s.push(c),
+            }
+        }
+        Err(invalid_data("unterminated string".to_string()))
+    }
+}
+
+fn hex4(chars: &mut std::str::CharIndices) -> Result<u32> {
+    let mut code = 0;
+    for _ in 0..4 {
+        let digit = chars.next().and_then(|(_, c)| c.to_digit(16)).ok_or_else(|| invalid_data("bad \\u escape in string".to_string()))?;
+        code = code << 4 | digit;
+    }
+    Ok(code)
+}
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries() -> Vec<(Vec<u8>, Vec<u8>)> {
+        vec![
+            (b"alice".to_vec(), b"line\none \"quoted\"".to_vec()),
+            (b"bin\xff".to_vec(), b"\x00\x01".to_vec()),
+            ("caf\u{e9} \u{1f600}".as_bytes().to_vec(), b"tab\there".to_vec()),
+        ]
+    }
+
+    fn roundtrip(format: DumpFormat) -> String {
+        let mut out = vec![];
+        let count = write_dump(&mut out, format, entries().into_iter().map(Ok)).unwrap();
+        assert_eq!(count, 3);
+        let read: Vec<_> = read_dump(out.as_slice(), format).map(|r| r.unwrap()).collect();
+        assert_eq!(read, entries());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_json_lines_roundtrip() {
+        let dump = roundtrip(DumpFormat::JsonLines);
+        let lines: Vec<_> = dump.lines().collect();
+        assert_eq!(lines[0], "{\"boxerdb_dump\":1}");
+        assert_eq!(lines[1], "{\"key\":\"alice\",\"value\":\"line\\none \\\"quoted\\\"\"}");
+        assert_eq!(lines[2], format!("{{\"key_base64\":\"{}\",\"value\":\"\\u0000\\u0001\"}}", text::to_base64(b"bin\xff")));
+
+        // escapes other writers use, in any field order, with unknown fields ignored
+        let input = "{\"value\" : \"\\ud83d\\ude00\\t\", \"extra\": 7, \"key\":\"\\u00e9\"}\n\n";
+        let read: Vec<_> = read_dump(input.as_bytes(), DumpFormat::JsonLines).map(|r| r.unwrap()).collect();
+        assert_eq!(read, vec![("\u{e9}".as_bytes().to_vec(), "\u{1f600}\t".as_bytes().to_vec())]);
+    }
+
+    #[test]
+    fn test_tsv_roundtrip() {
+        let dump = roundtrip(DumpFormat::Tsv(Format::Utf8));
+        assert!(dump.starts_with("alice\tline\\x0aone \"quoted\"\n"));
+        roundtrip(DumpFormat::Tsv(Format::Hex));
+    }
+
+    #[test]
+    fn test_read_dump_rejects_bad_lines() {
+        for input in [
+            "{\"boxerdb_dump\":2}\n",
+            "{\"key\":\"a\"}\n",
+            "{\"key\":\"a\",\"value\":\"b\"} x\n",
+            "{\"key\":\"a\",\"value\":\"\\ud83d\"}\n",
+            "{\"key\":\"a\",\"value\":\"b\"}\n{\"boxerdb_dump\":1}\n",
+        ] {
+            let err = read_dump(input.as_bytes(), DumpFormat::JsonLines).find_map(|r| r.err()).unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", input);
+        }
+        let err = read_dump("a b\n".as_bytes(), DumpFormat::Tsv(Format::Utf8)).next().unwrap().unwrap_err();
+        assert!(err.to_string().starts_with("line 1:"));
+    }
+}
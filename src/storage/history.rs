@@ -0,0 +1,327 @@
+use crate::storage::btree::{prefix_end, BTree};
+use crate::storage::bucket;
+use crate::storage::iterator::RangeIter;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// history tree keys are a one byte namespace followed by the entry's own key
+pub(crate) const COMMIT_PREFIX: u8 = b'c';
+pub(crate) const SNAPSHOT_PREFIX: u8 = b's';
+
+/// How many old commits stay readable, pages they refer to are not reused until they fall out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retention {
+    /// Keep the last n commits, counting the current one
+    KeepLast(u64),
+    /// Keep every commit made within the duration, along with the current one
+    KeepFor(Duration),
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        // only the current commit, old pages are reused straight away
+        Retention::KeepLast(1)
+    }
+}
+
+/// A commit recorded in the history tree, either as part of the history or pinned by a named snapshot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommitInfo {
+    pub commit_id: u64,
+    pub timestamp: u64, // unix millis
+    pub root_offset: u64,
+    pub catalog_offset: u64,
+}
+
+impl CommitInfo {
+    pub(crate) fn key(commit_id: u64) -> Vec<u8> {
+        let mut key = vec![COMMIT_PREFIX];
+        key.extend_from_slice(&commit_id.to_be_bytes());
+        key
+    }
+
+    /// timestamp (u64) + root offset (u64) + catalog offset (u64)
+    pub(crate) fn encode_value(&self) -> Vec<u8> {
+        let mut value = Vec::with_capacity(24);
+        value.extend_from_slice(&self.timestamp.to_le_bytes());
+        value.extend_from_slice(&self.root_offset.to_le_bytes());
+        value.extend_from_slice(&self.catalog_offset.to_le_bytes());
+        value
+    }
+
+    pub(crate) fn decode(key: &[u8], value: &[u8]) -> Result<Self> {
+        if key.len() != 9 || key[0] != COMMIT_PREFIX || value.len() != 24 {
+            return Err(Error::new(ErrorKind::InvalidData, "malformed commit history entry"));
+        }
+        let read = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+        Ok(Self {
+            commit_id: u64::from_be_bytes(key[1..].try_into().unwrap()),
+            timestamp: read(&value[..8]),
+            root_offset: read(&value[8..16]),
+            catalog_offset: read(&value[16..24]),
+        })
+    }
+
+    /// Range of history tree keys holding commits
+    pub(crate) fn key_range() -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        (Bound::Included(vec![COMMIT_PREFIX]), prefix_end(&[COMMIT_PREFIX]))
+    }
+
+    pub(crate) fn snapshot_key(name: &str) -> Vec<u8> {
+        let mut key = vec![SNAPSHOT_PREFIX];
+        key.extend_from_slice(name.as_bytes());
+        key
+    }
+
+    /// commit id (u64) followed by the same fields as a history entry
+    pub(crate) fn encode_snapshot(&self) -> Vec<u8> {
+        let mut value = self.commit_id.to_le_bytes().to_vec();
+        value.extend(self.encode_value());
+        value
+    }
+
+    pub(crate) fn decode_snapshot(key: &[u8], value: &[u8]) -> Result<(String, Self)> {
+        if key.first() != Some(&SNAPSHOT_PREFIX) || value.len() != 32 {
+            return Err(Error::new(ErrorKind::InvalidData, "malformed snapshot entry"));
+        }
+        let name = String::from_utf8(key[1..].to_vec())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "snapshot name is not valid utf8"))?;
+        let commit_id = u64::from_le_bytes(value[..8].try_into().unwrap());
+        let info = Self::decode(&Self::key(commit_id), &value[8..])?;
+        Ok((name, info))
+    }
+
+    /// Range of history tree keys holding named snapshots
+    pub(crate) fn snapshot_key_range() -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        (Bound::Included(vec![SNAPSHOT_PREFIX]), prefix_end(&[SNAPSHOT_PREFIX]))
+    }
+}
+
+/// Read-only view of the trees as of an earlier commit or a named snapshot
+/// Holds the tree borrowed so nothing can be committed, and no page reused, while it is open
+pub struct Snapshot<'a> {
+    btree: &'a mut BTree,
+    info: CommitInfo,
+}
+
+impl<'a> Snapshot<'a> {
+    pub(crate) fn new(btree: &'a mut BTree, info: CommitInfo) -> Self {
+        Self { btree, info }
+    }
+
+    pub fn commit_id(&self) -> u64 {
+        self.info.commit_id
+    }
+
+    /// When the commit was made, None if it wasn't recorded
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        match self.info.timestamp {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        }
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.btree.get_from_tree(self.info.root_offset, key)
+    }
+
+    /// Iterate over the key/value pairs within range in key order
+    pub fn range<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> Result<RangeIter<'_>> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let root = self.btree.load_root(self.info.root_offset)?;
+        Ok(RangeIter::new(&mut self.btree.disk_manager, root, start, end))
+    }
+
+    /// Names of the buckets that existed at this commit
+    pub fn list_buckets(&mut self) -> Result<Vec<String>> {
+        self.btree.list_buckets_in(self.info.catalog_offset)
+    }
+
+    /// Look up key in a bucket as it was at this commit
+    pub fn bucket_get(&mut self, name: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let root_offset = match self.btree.get_from_tree(self.info.catalog_offset, name.as_bytes())? {
+            Some(value) => bucket::decode_root(&value)?,
+            None => return Err(bucket::not_found(name)),
+        };
+        self.btree.get_from_tree(root_offset, key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn get_temp_btree(retention: Retention) -> (NamedTempFile, BTree) {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), None).unwrap();
+        btree.set_retention(retention);
+        (tmp, btree)
+    }
+
+    #[test]
+    fn test_read_at_retained_commits() {
+        let (_tmp, mut btree) = get_temp_btree(Retention::KeepLast(3));
+        btree.insert(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let first = btree.commit_id;
+        btree.insert(b"a".to_vec(), b"2".to_vec()).unwrap();
+        btree.insert(b"b".to_vec(), b"3".to_vec()).unwrap();
+
+        let mut snapshot = btree.read_at(first).unwrap();
+        assert_eq!(snapshot.commit_id(), first);
+        assert!(snapshot.timestamp().is_some());
+        assert_eq!(snapshot.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snapshot.get(b"b").unwrap(), None);
+
+        // one more commit pushes the first one out of the window
+        btree.delete(b"a").unwrap();
+        assert_eq!(btree.read_at(first).err().unwrap().kind(), ErrorKind::NotFound);
+        let commits: Vec<u64> = btree.history().unwrap().iter().map(|info| info.commit_id).collect();
+        assert_eq!(commits, vec![first + 1, first + 2, first + 3]);
+    }
+
+    #[test]
+    fn test_read_as_of() {
+        let (_tmp, mut btree) = get_temp_btree(Retention::KeepFor(Duration::from_secs(3600)));
+        btree.insert(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let after_first = SystemTime::now();
+        std::thread::sleep(Duration::from_millis(5));
+        btree.insert(b"a".to_vec(), b"2".to_vec()).unwrap();
+
+        assert_eq!(btree.read_as_of(after_first).unwrap().get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(btree.read_as_of(SystemTime::now()).unwrap().get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert!(btree.read_as_of(UNIX_EPOCH).is_err());
+    }
+
+    #[test]
+    fn test_read_as_of_without_history() {
+        let (tmp, mut btree) = get_temp_btree(Retention::KeepLast(1));
+        let before_first = SystemTime::now() - Duration::from_secs(60);
+        btree.insert(b"a".to_vec(), b"1".to_vec()).unwrap();
+        assert!(btree.history().unwrap().is_empty());
+
+        assert_eq!(btree.read_as_of(SystemTime::now()).unwrap().get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(btree.read_as_of(before_first).err().unwrap().kind(), ErrorKind::NotFound);
+        // the time of the current commit is kept across a reopen
+        drop(btree);
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), None).unwrap();
+        assert_eq!(btree.read_as_of(before_first).err().unwrap().kind(), ErrorKind::NotFound);
+        assert!(btree.read_as_of(SystemTime::now()).is_ok());
+    }
+
+    #[test]
+    fn test_history_survives_reopen() {
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let mut btree = BTree::new(path, None).unwrap();
+        btree.set_retention(Retention::KeepLast(10));
+        btree.create_bucket("users").unwrap();
+        btree.open_bucket("users").unwrap().insert(b"alice".to_vec(), b"1".to_vec()).unwrap();
+        let before_update = btree.commit_id;
+        btree.open_bucket("users").unwrap().insert(b"alice".to_vec(), b"2".to_vec()).unwrap();
+        drop(btree);
+
+        // the pages of the older commit are not handed out again after reopening
+        let mut btree = BTree::new(path, None).unwrap();
+        btree.set_retention(Retention::KeepLast(10));
+        assert!(btree.disk_manager.retired_page_count() > 0);
+        btree.open_bucket("users").unwrap().insert(b"bob".to_vec(), b"3".to_vec()).unwrap();
+
+        let mut snapshot = btree.read_at(before_update).unwrap();
+        assert_eq!(snapshot.list_buckets().unwrap(), vec!["users".to_string()]);
+        assert_eq!(snapshot.bucket_get("users", b"alice").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snapshot.bucket_get("users", b"bob").unwrap(), None);
+    }
+
+    #[test]
+    fn test_default_retention_reuses_pages() {
+        let (_tmp, mut btree) = get_temp_btree(Retention::default());
+        for i in 0..20u64 {
+            btree.insert(b"counter".to_vec(), i.to_le_bytes().to_vec()).unwrap();
+        }
+        assert!(btree.history().unwrap().is_empty());
+        assert_eq!(btree.disk_manager.retired_page_count(), 0);
+
+        let commit_id = btree.commit_id;
+        assert_eq!(btree.read_at(commit_id).unwrap().get(b"counter").unwrap(), Some(19u64.to_le_bytes().to_vec()));
+        assert!(btree.read_at(commit_id - 1).is_err());
+    }
+
+    #[test]
+    fn test_create_open_and_list_snapshots() {
+        let (_tmp, mut btree) = get_temp_btree(Retention::default());
+        btree.insert(b"schema".to_vec(), b"v1".to_vec()).unwrap();
+        btree.create_snapshot("before-migration").unwrap();
+        assert_eq!(btree.create_snapshot("before-migration").unwrap_err().kind(), ErrorKind::AlreadyExists);
+
+        // enough rewrites that unpinned pages would have been reused
+        for i in 0..20u64 {
+            btree.insert(b"schema".to_vec(), format!("v{}", i + 2).into_bytes()).unwrap();
+        }
+
+        let snapshots = btree.list_snapshots().unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].0, "before-migration");
+        let mut snapshot = btree.open_snapshot("before-migration").unwrap();
+        assert_eq!(snapshot.get(b"schema").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(btree.open_snapshot("missing").err().unwrap().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_restore_snapshot() {
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let mut btree = BTree::new(path, None).unwrap();
+        btree.insert(b"a".to_vec(), b"1".to_vec()).unwrap();
+        btree.create_snapshot("good").unwrap();
+        btree.insert(b"a".to_vec(), b"broken".to_vec()).unwrap();
+        btree.insert(b"b".to_vec(), b"broken".to_vec()).unwrap();
+
+        btree.restore_snapshot("good").unwrap();
+        assert_eq!(btree.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(btree.get(b"b").unwrap(), None);
+        drop(btree);
+
+        // the restored root is what the metadata points at, and the snapshot is still there
+        let mut btree = BTree::new(path, None).unwrap();
+        assert_eq!(btree.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(btree.list_snapshots().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_drop_snapshot_releases_pages() {
+        let (_tmp, mut btree) = get_temp_btree(Retention::default());
+        btree.insert(b"a".to_vec(), b"1".to_vec()).unwrap();
+        btree.create_snapshot("pin").unwrap();
+        btree.insert(b"a".to_vec(), b"2".to_vec()).unwrap();
+        assert!(btree.disk_manager.retired_page_count() > 0);
+
+        btree.drop_snapshot("pin").unwrap();
+        assert_eq!(btree.drop_snapshot("pin").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(btree.disk_manager.retired_page_count(), 0);
+        assert_eq!(btree.history_offset, 0);
+    }
+
+    #[test]
+    fn test_snapshot_pins_only_its_pages() {
+        let (tmp, mut btree) = get_temp_btree(Retention::default());
+        btree.insert(b"a".to_vec(), b"1".to_vec()).unwrap();
+        btree.create_snapshot("pin").unwrap();
+        let pinned = btree.disk_manager.page_offsets().unwrap().len();
+        for i in 0..100u32 {
+            btree.insert(b"a".to_vec(), i.to_le_bytes().to_vec()).unwrap();
+        }
+        // pages of the overwritten versions are reused, only the snapshot's own ones are held
+        assert!(btree.disk_manager.retired_page_count() <= pinned);
+        assert!(btree.disk_manager.page_offsets().unwrap().len() < pinned + 10);
+        drop(btree);
+
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), None).unwrap();
+        assert!(btree.disk_manager.retired_page_count() > 0);
+        assert_eq!(btree.open_snapshot("pin").unwrap().get(b"a").unwrap(), Some(b"1".to_vec()));
+        btree.drop_snapshot("pin").unwrap();
+        assert_eq!(btree.disk_manager.retired_page_count(), 0);
+    }
+}
@@ -0,0 +1,433 @@
+use crate::storage::btree::BTree;
+use crate::storage::bucket;
+use crate::storage::diskmanager::{DiskManager, Metadata};
+use crate::storage::history::CommitInfo;
+use crate::storage::node::Node;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+
+const MAGIC: &[u8; 8] = b"BOXERINC";
+
+/// A page the incremental carries, keyed by its offset in the source file
+struct PageCopy {
+    source_offset: u64,
+    height: u16,
+    // catalog leaves hold bucket root offsets that have to be rewritten too
+    catalog: bool,
+    node: Node,
+}
+
+/// Where a page the incremental doesn't carry sits in the commit it builds on
+/// Followed from the main or the catalog root one child index per level, at a catalog leaf the
+/// index picks the bucket whose tree is followed from there
+#[derive(Clone)]
+struct PagePath {
+    from_catalog: bool,
+    steps: Vec<u16>,
+}
+
+/// The pages written between two commits, enough to move a copy of the older commit to the newer one
+/// Offsets are the ones in the source file, restoring maps them onto free pages of the copy
+pub(crate) struct Incremental {
+    since_commit: u64,
+    info: CommitInfo,
+    // children come before their parents so every new page a page points to is already placed
+    pages: Vec<PageCopy>,
+    // unchanged pages the new ones point at
+    paths: HashMap<u64, PagePath>,
+}
+
+impl Incremental {
+    /// Collect the pages reachable from info but not from since
+    pub(crate) fn capture(btree: &mut BTree, since: CommitInfo, info: CommitInfo) -> Result<Self> {
+        let mut since_paths = HashMap::new();
+        collect_paths(btree, since.root_offset, false, false, &mut vec![], &mut since_paths)?;
+        collect_paths(btree, since.catalog_offset, true, true, &mut vec![], &mut since_paths)?;
+
+        let mut incremental = Self {
+            since_commit: since.commit_id,
+            info,
+            pages: vec![],
+            paths: HashMap::new(),
+        };
+        let mut visited = HashSet::new();
+        for (root_offset, catalog) in [(info.root_offset, false), (info.catalog_offset, true)] {
+            if root_offset != 0 {
+                let height = btree.tree_height(root_offset)?;
+                incremental.visit(btree, root_offset, height, catalog, &since_paths, &mut visited)?;
+            }
+        }
+        Ok(incremental)
+    }
+
+    fn visit(
+        &mut self,
+        btree: &mut BTree,
+        offset: u64,
+        height: usize,
+        catalog: bool,
+        since_paths: &HashMap<u64, PagePath>,
+        visited: &mut HashSet<u64>,
+    ) -> Result<()> {
+        if offset == 0 || !visited.insert(offset) {
+            return Ok(());
+        }
+        // the whole subtree is unchanged, the copy being restored onto already has it
+        if let Some(path) = since_paths.get(&offset) {
+            self.paths.insert(offset, path.clone());
+            return Ok(());
+        }
+
+        let node = btree.disk_manager.load_node_from_disk(offset)?;
+        if height > 0 {
+            for &child in &node.children {
+                self.visit(btree, child, height - 1, catalog, since_paths, visited)?;
+            }
+        } else if catalog {
+            for value in &node.values {
+                let root_offset = bucket::decode_root(value)?;
+                if root_offset != 0 {
+                    let height = btree.tree_height(root_offset)?;
+                    self.visit(btree, root_offset, height, false, since_paths, visited)?;
+                }
+            }
+        }
+        self.pages.push(PageCopy {
+            source_offset: offset,
+            height: height as u16,
+            catalog,
+            node,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// magic, page size (u16), since commit, commit, root and catalog offsets (u64 each),
+    /// then the pages and the paths of the unchanged pages, each preceded by their count (u64)
+    pub(crate) fn write(&self, path: &str, disk_manager: &DiskManager) -> Result<()> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let mut out = BufWriter::new(file);
+        out.write_all(MAGIC)?;
+        out.write_all(&disk_manager.config.page_size.to_le_bytes())?;
+        for field in [self.since_commit, self.info.commit_id, self.info.root_offset, self.info.catalog_offset] {
+            out.write_all(&field.to_le_bytes())?;
+        }
+
+        out.write_all(&(self.pages.len() as u64).to_le_bytes())?;
+        for page in &self.pages {
+            // source offset (u64) + height (u16) + catalog flag (u8) + the encoded page
+            let encoded = Node::encode_node(&page.node, disk_manager.config.clone())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "page no longer fits the page size"))?;
+            out.write_all(&page.source_offset.to_le_bytes())?;
+            out.write_all(&page.height.to_le_bytes())?;
+            out.write_all(&[page.catalog as u8])?;
+            out.write_all(&encoded)?;
+        }
+
+        out.write_all(&(self.paths.len() as u64).to_le_bytes())?;
+        for (offset, path) in &self.paths {
+            // source offset (u64) + root flag (u8) + number of steps (u16) + the steps (u16 each)
+            out.write_all(&offset.to_le_bytes())?;
+            out.write_all(&[path.from_catalog as u8])?;
+            out.write_all(&(path.steps.len() as u16).to_le_bytes())?;
+            for step in &path.steps {
+                out.write_all(&step.to_le_bytes())?;
+            }
+        }
+        out.flush()?;
+        out.get_ref().sync_all()
+    }
+
+    pub(crate) fn read(path: &str, page_size: u16) -> Result<Self> {
+        let mut input = Input { buf: fs::read(path)?, pos: 0 };
+        if input.take(MAGIC.len())? != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} is not an incremental backup", path)));
+        }
+        if input.u16()? != page_size {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} was taken with a different page size", path)));
+        }
+        let since_commit = input.u64()?;
+        let info = CommitInfo {
+            commit_id: input.u64()?,
+            timestamp: 0,
+            root_offset: input.u64()?,
+            catalog_offset: input.u64()?,
+        };
+
+        let mut pages = vec![];
+        for _ in 0..input.u64()? {
+            pages.push(PageCopy {
+                source_offset: input.u64()?,
+                height: input.u16()?,
+                catalog: input.u8()? != 0,
+                node: Node::try_decode_node(input.take(page_size as usize)?)?,
+            });
+        }
+
+        let mut paths = HashMap::new();
+        for _ in 0..input.u64()? {
+            let offset = input.u64()?;
+            let from_catalog = input.u8()? != 0;
+            let steps = (0..input.u16()?).map(|_| input.u16()).collect::<Result<_>>()?;
+            paths.insert(offset, PagePath { from_catalog, steps });
+        }
+        Ok(Self { since_commit, info, pages, paths })
+    }
+
+    /// Write the carried pages onto free pages of a copy of the since commit and switch it over
+    /// The metadata is written last, so the copy keeps the older commit if this fails part way
+    pub(crate) fn apply(&self, disk_manager: &mut DiskManager) -> Result<()> {
+        let metadata = disk_manager.read_metadata()?;
+        if metadata.commit_id != self.since_commit {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("incremental backup applies to commit {}, not commit {}", self.since_commit, metadata.commit_id),
+            ));
+        }
+
+        let mut placed: HashMap<u64, u64> = HashMap::new();
+        for page in &self.pages {
+            let mut node = page.node.clone();
+            if page.height > 0 {
+                for child in node.children.iter_mut() {
+                    *child = self.place(disk_manager, &metadata, &placed, *child)?;
+                }
+            } else if page.catalog {
+                for value in node.values.iter_mut() {
+                    let root_offset = self.place(disk_manager, &metadata, &placed, bucket::decode_root(value)?)?;
+                    *value = bucket::encode_root(root_offset);
+                }
+            }
+            let offset = disk_manager.get_new_offset()?;
+            disk_manager.write_node(offset, &node)?;
+            placed.insert(page.source_offset, offset);
+        }
+
+        let new_metadata = Metadata {
+            root_offset: self.place(disk_manager, &metadata, &placed, self.info.root_offset)?,
+            catalog_offset: self.place(disk_manager, &metadata, &placed, self.info.catalog_offset)?,
+            commit_id: self.info.commit_id,
+            history_offset: 0,
+            timestamp: self.info.timestamp,
+        };
+        disk_manager.write_metadata(&new_metadata)
+    }
+
+    /// Offset in the copy of the page at source_offset
+    fn place(&self, disk_manager: &mut DiskManager, metadata: &Metadata, placed: &HashMap<u64, u64>, source_offset: u64) -> Result<u64> {
+        if source_offset == 0 {
+            return Ok(0);
+        }
+        if let Some(&offset) = placed.get(&source_offset) {
+            return Ok(offset);
+        }
+        let Some(path) = self.paths.get(&source_offset) else {
+            return Err(missing_page(source_offset));
+        };
+
+        let mut offset = if path.from_catalog { metadata.catalog_offset } else { metadata.root_offset };
+        let mut in_catalog = path.from_catalog;
+        for &step in &path.steps {
+            let node = disk_manager.load_node_from_disk(offset)?;
+            let step = step as usize;
+            offset = if !node.children.is_empty() {
+                *node.children.get(step).ok_or_else(|| missing_page(source_offset))?
+            } else if in_catalog {
+                in_catalog = false;
+                bucket::decode_root(node.values.get(step).ok_or_else(|| missing_page(source_offset))?)?
+            } else {
+                return Err(missing_page(source_offset));
+            };
+        }
+        Ok(offset)
+    }
+}
+
+/// Record the path to every page of the tree at offset, catalog is whether it is the catalog
+/// leaves of other trees are never read, their path is known from their parent
+fn collect_paths(
+    btree: &mut BTree,
+    root_offset: u64,
+    from_catalog: bool,
+    catalog: bool,
+    steps: &mut Vec<u16>,
+    paths: &mut HashMap<u64, PagePath>,
+) -> Result<()> {
+    if root_offset == 0 {
+        return Ok(());
+    }
+    let height = btree.tree_height(root_offset)?;
+    collect_subtree_paths(btree, root_offset, height, from_catalog, catalog, steps, paths)
+}
+
+fn collect_subtree_paths(
+    btree: &mut BTree,
+    offset: u64,
+    height: usize,
+    from_catalog: bool,
+    catalog: bool,
+    steps: &mut Vec<u16>,
+    paths: &mut HashMap<u64, PagePath>,
+) -> Result<()> {
+    if paths.contains_key(&offset) {
+        return Ok(());
+    }
+    paths.insert(offset, PagePath { from_catalog, steps: steps.clone() });
+    if height == 0 && !catalog {
+        return Ok(());
+    }
+
+    let node = btree.disk_manager.load_node_from_disk(offset)?;
+    if height > 0 {
+        for (pos, &child) in node.children.iter().enumerate() {
+            steps.push(pos as u16);
+            collect_subtree_paths(btree, child, height - 1, from_catalog, catalog, steps, paths)?;
+            steps.pop();
+        }
+    } else {
+        for (pos, value) in node.values.iter().enumerate() {
+            steps.push(pos as u16);
+            collect_paths(btree, bucket::decode_root(value)?, from_catalog, false, steps, paths)?;
+            steps.pop();
+        }
+    }
+    Ok(())
+}
+
+fn missing_page(source_offset: u64) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("incremental backup doesn't lead to page {}", source_offset))
+}
+
+/// Reads the little endian fields of an incremental backup
+struct Input {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Input {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.buf.len() - self.pos < len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "incremental backup is truncated"));
+        }
+        self.pos += len;
+        Ok(&self.buf[self.pos - len..self.pos])
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::{BTree, Retention};
+    use crate::storage::testing::{key, small_page_config};
+    use std::io::ErrorKind;
+    use tempfile::{NamedTempFile, TempDir};
+
+    type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+    /// Entries of the main tree and of the users bucket
+    fn contents(btree: &mut BTree) -> (Entries, Entries) {
+        let main = btree.range(..).map(|r| r.unwrap()).collect();
+        let users = btree.open_bucket("users").unwrap().range(..).unwrap().map(|r| r.unwrap()).collect();
+        (main, users)
+    }
+
+    #[test]
+    fn test_restore_base_and_incrementals() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        btree.set_retention(Retention::KeepLast(1000));
+        btree.create_bucket("users").unwrap();
+        for i in 0..200 {
+            btree.insert(key(i), b"v".to_vec()).unwrap();
+            btree.open_bucket("users").unwrap().insert(key(i), b"u".to_vec()).unwrap();
+        }
+
+        let dir = TempDir::new().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        btree.backup_to(&path("base")).unwrap();
+        let base_commit = btree.commit_id;
+
+        btree.insert(key(7), b"changed".to_vec()).unwrap();
+        btree.delete(&key(150)).unwrap();
+        btree.open_bucket("users").unwrap().insert(b"zzz".to_vec(), b"new".to_vec()).unwrap();
+        let written = btree.incremental_backup(base_commit, &path("inc1")).unwrap();
+        // only the pages along the changed paths
+        assert!(written < btree.reachable_pages().unwrap().len() / 4);
+        let first_commit = btree.commit_id;
+
+        btree.delete_range(key(20)..key(60)).unwrap();
+        btree.create_bucket("empty").unwrap();
+        btree.incremental_backup(first_commit, &path("inc2")).unwrap();
+
+        let mut restored = BTree::restore(&path("base"), &[&path("inc1"), &path("inc2")], &path("restored"), Some(small_page_config())).unwrap();
+        assert_eq!(restored.commit_id, btree.commit_id);
+        assert_eq!(contents(&mut restored), contents(&mut btree));
+        assert_eq!(restored.list_buckets().unwrap(), vec!["empty", "users"]);
+    }
+
+    #[test]
+    fn test_restore_rejects_chain_out_of_order() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        btree.set_retention(Retention::KeepLast(10));
+        btree.insert(key(0), b"v".to_vec()).unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        btree.backup_to(&path("base")).unwrap();
+        let base_commit = btree.commit_id;
+        btree.insert(key(1), b"v".to_vec()).unwrap();
+        btree.incremental_backup(base_commit, &path("inc1")).unwrap();
+        let first_commit = btree.commit_id;
+        btree.insert(key(2), b"v".to_vec()).unwrap();
+        btree.incremental_backup(first_commit, &path("inc2")).unwrap();
+
+        let err = BTree::restore(&path("base"), &[&path("inc2")], &path("restored"), Some(small_page_config())).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        // the base isn't an incremental backup
+        let err = BTree::restore(&path("base"), &[&path("base")], &path("restored2"), Some(small_page_config())).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // a page that doesn't decode is an error rather than a panic
+        let mut damaged = std::fs::read(path("inc1")).unwrap();
+        // magic, page size, since commit, commit info and page count, then the first page's offset, height and catalog flag
+        let first_page = super::MAGIC.len() + 2 + 8 + 24 + 8 + 8 + 2 + 1;
+        damaged[first_page] = 0xff;
+        std::fs::write(path("inc1"), damaged).unwrap();
+        let err = BTree::restore(&path("base"), &[&path("inc1")], &path("restored3"), Some(small_page_config())).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_incremental_backup_needs_retained_commit() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), None).unwrap();
+        btree.insert(key(0), b"v".to_vec()).unwrap();
+        btree.insert(key(1), b"v".to_vec()).unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let err = btree.incremental_backup(1, dir.path().join("inc").to_str().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        // a named snapshot keeps the commit around
+        btree.create_snapshot("nightly").unwrap();
+        let since = btree.list_snapshots().unwrap()[0].1.commit_id;
+        btree.insert(key(2), b"v".to_vec()).unwrap();
+        assert!(btree.incremental_backup(since, dir.path().join("inc").to_str().unwrap()).unwrap() > 0);
+    }
+}
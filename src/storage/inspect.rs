@@ -0,0 +1,365 @@
+use crate::storage::configs::{BNODE_INTERNAL, BNODE_LEAF, PAGE_PREFIX, PAGE_SLOTTED, VAL_HAS_EXPIRY};
+use crate::storage::page::HEADER_SIZE;
+use crate::text;
+use std::fmt;
+use std::ops::Range;
+
+// escaped characters of a key or value shown by Display before it is cut off
+const MAX_SHOWN_CHARS: usize = 40;
+
+/// Byte by byte layout of one page as encode_node wrote it, see DiskManager::inspect_page
+/// Nothing is assumed to be valid, whatever doesn't add up is listed in problems
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageLayout {
+    pub offset: u64,
+    /// Node type without the format flag
+    pub node_type: u8,
+    /// Whether the page is in the slotted format, legacy pages have no free space pointer
+    pub slotted: bool,
+    pub key_count: u16,
+    /// Start of the lowest cell of a slotted page
+    pub free_pointer: u16,
+    /// Bytes between the free space pointer and the end of a slotted page that no cell takes
+    pub fragmented: u16,
+    /// Prefix the page strips from every key, None unless the page stores one
+    pub prefix: Option<Vec<u8>>,
+    /// Child pointers of an internal node
+    pub children: Vec<u64>,
+    /// Where the slot array says each cell starts
+    pub slots: Vec<u16>,
+    pub cells: Vec<Cell>,
+    /// Byte ranges nothing in the page refers to
+    pub unused: Vec<Range<usize>>,
+    pub problems: Vec<String>,
+    pub bytes: Vec<u8>,
+}
+
+/// A key/value cell, range covers its length fields, key, expiry and value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    pub slot: usize,
+    pub range: Range<usize>,
+    /// The whole key, including the page prefix the cell leaves out
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    /// 0 if the entry never expires
+    pub expiry: u64,
+}
+
+impl PageLayout {
+    pub fn is_leaf(&self) -> bool {
+        self.node_type == BNODE_LEAF
+    }
+
+    pub fn unused_bytes(&self) -> usize {
+        self.unused.iter().map(|range| range.len()).sum()
+    }
+
+    /// Classic hexdump of the page, runs of identical lines are collapsed into a *
+    pub fn hexdump(&self) -> String {
+        let mut dump = String::new();
+        let mut previous: Option<&[u8]> = None;
+        let mut skipping = false;
+        for (i, line) in self.bytes.chunks(16).enumerate() {
+            if previous == Some(line) {
+                if !skipping {
+                    dump.push_str("*\n");
+                    skipping = true;
+                }
+                continue;
+            }
+            previous = Some(line);
+            skipping = false;
+            let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let ascii: String = line.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+            dump.push_str(&format!("{:08x}  {:<47}  |{}|\n", i * 16, hex.join(" "), ascii));
+        }
+        dump.push_str(&format!("{:08x}\n", self.bytes.len()));
+        dump
+    }
+}
+
+/// Lay out the bytes of the page at offset
+pub(crate) fn inspect(offset: u64, bytes: Vec<u8>) -> PageLayout {
+    let page_size = bytes.len();
+    let field = |at: usize| bytes.get(at..at + 2).map_or(0, |b| u16::from_le_bytes([b[0], b[1]]));
+    let first = bytes.first().copied().unwrap_or(0);
+    let slotted = first & PAGE_SLOTTED != 0;
+    let mut layout = PageLayout {
+        offset,
+        node_type: first & !(PAGE_SLOTTED | PAGE_PREFIX),
+        slotted,
+        key_count: field(1),
+        free_pointer: 0,
+        fragmented: 0,
+        prefix: None,
+        children: vec![],
+        slots: vec![],
+        cells: vec![],
+        unused: vec![],
+        problems: vec![],
+        bytes: vec![],
+    };
+    // every byte range something refers to, with a name for the problems
+    let mut regions: Vec<(Range<usize>, String)> = vec![(0..HEADER_SIZE.min(page_size), "header".to_string())];
+    if layout.node_type != BNODE_INTERNAL && layout.node_type != BNODE_LEAF {
+        layout.problems.push(format!("unknown node type {}", layout.node_type));
+    }
+    let key_count = layout.key_count as usize;
+
+    let mut cursor = HEADER_SIZE;
+    if slotted && first & PAGE_PREFIX != 0 {
+        let end = cursor + 2 + field(cursor) as usize;
+        match bytes.get(cursor + 2..end) {
+            Some(prefix) => layout.prefix = Some(prefix.to_vec()),
+            None => layout.problems.push(format!("key prefix at {}..{} is past the end of the page", cursor, end)),
+        }
+        regions.push((cursor..end.min(page_size), "key prefix".to_string()));
+        cursor = end;
+    }
+    if layout.node_type == BNODE_INTERNAL {
+        let end = cursor + (key_count + 1) * 8;
+        for start in (cursor..end).step_by(8) {
+            match bytes.get(start..start + 8) {
+                Some(b) => layout.children.push(u64::from_le_bytes(b.try_into().unwrap())),
+                None => {
+                    layout.problems.push(format!("child pointer at {}..{} is past the end of the page", start, start + 8));
+                    break;
+                }
+            }
+        }
+        regions.push((cursor..end.min(page_size), "child pointers".to_string()));
+        cursor = end;
+    }
+    let slots_end = cursor + key_count * 2;
+    for start in (cursor..slots_end).step_by(2) {
+        match bytes.get(start..start + 2) {
+            Some(b) => layout.slots.push(u16::from_le_bytes([b[0], b[1]])),
+            None => {
+                layout.problems.push(format!("slot at {}..{} is past the end of the page", start, start + 2));
+                break;
+            }
+        }
+    }
+    if cursor < page_size {
+        regions.push((cursor..slots_end.min(page_size), "slots".to_string()));
+    }
+
+    for (slot, &start) in layout.slots.iter().enumerate() {
+        match read_cell(&bytes, slot, start as usize, layout.node_type == BNODE_LEAF) {
+            Ok(mut cell) => {
+                if let Some(prefix) = &layout.prefix {
+                    cell.key.splice(..0, prefix.iter().copied());
+                }
+                regions.push((cell.range.clone(), format!("cell {}", slot)));
+                layout.cells.push(cell);
+            }
+            Err(problem) => layout.problems.push(problem),
+        }
+    }
+    if slotted {
+        let free_pointer = layout.cells.iter().map(|cell| cell.range.start).min().unwrap_or(page_size);
+        let cells_len: usize = layout.cells.iter().map(|cell| cell.range.len()).sum();
+        layout.free_pointer = free_pointer as u16;
+        layout.fragmented = page_size.saturating_sub(free_pointer).saturating_sub(cells_len) as u16;
+    }
+    for pair in layout.cells.windows(2) {
+        if pair[0].key >= pair[1].key {
+            layout.problems.push(format!("keys of cells {} and {} are out of order", pair[0].slot, pair[1].slot));
+        }
+    }
+
+    regions.sort_by_key(|(range, _)| (range.start, range.end));
+    let mut covered = 0;
+    let mut last_name = String::new();
+    for (range, name) in &regions {
+        if range.start < covered {
+            layout.problems.push(format!("{} at {}..{} overlaps {}", name, range.start, range.end, last_name));
+        } else if range.start > covered {
+            layout.unused.push(covered..range.start);
+        }
+        if range.end > covered {
+            covered = range.end;
+            last_name = name.clone();
+        }
+    }
+    if covered < page_size {
+        layout.unused.push(covered..page_size);
+    }
+    layout.bytes = bytes;
+    layout
+}
+
+fn read_cell(bytes: &[u8], slot: usize, start: usize, is_leaf: bool) -> Result<Cell, String> {
+    let past_end = |what: &str, range: Range<usize>| format!("{} of cell {} at {}..{} is past the end of the page", what, slot, range.start, range.end);
+    let field = |at: usize| bytes.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let key_len = field(start).ok_or_else(|| past_end("key length", start..start + 2))? as usize;
+    let val_len_field = field(start + 2).ok_or_else(|| past_end("value length", start + 2..start + 4))?;
+    let has_expiry = is_leaf && val_len_field & VAL_HAS_EXPIRY != 0;
+    let val_len = if is_leaf { (val_len_field & !VAL_HAS_EXPIRY) as usize } else { 0 };
+
+    let key_start = start + 4;
+    let key = bytes.get(key_start..key_start + key_len).ok_or_else(|| past_end("key", key_start..key_start + key_len))?;
+    let mut val_start = key_start + key_len;
+    let mut expiry = 0;
+    if has_expiry {
+        let b = bytes.get(val_start..val_start + 8).ok_or_else(|| past_end("expiry", val_start..val_start + 8))?;
+        expiry = u64::from_le_bytes(b.try_into().unwrap());
+        val_start += 8;
+    }
+    let value = bytes.get(val_start..val_start + val_len).ok_or_else(|| past_end("value", val_start..val_start + val_len))?;
+    Ok(Cell {
+        slot,
+        range: start..val_start + val_len,
+        key: key.to_vec(),
+        value: value.to_vec(),
+        expiry,
+    })
+}
+
+fn shown(bytes: &[u8]) -> String {
+    let escaped = text::escape(bytes);
+    match escaped.char_indices().nth(MAX_SHOWN_CHARS) {
+        Some((end, _)) => format!("{}..", &escaped[..end]),
+        None => escaped,
+    }
+}
+
+impl fmt::Display for PageLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.node_type {
+            BNODE_LEAF => "leaf",
+            BNODE_INTERNAL => "internal",
+            _ => "unknown",
+        };
+        writeln!(f, "page {}, {} bytes", self.offset, self.bytes.len())?;
+        write!(f, "header 0..{}: node type {} ({}), {} keys", HEADER_SIZE, self.node_type, kind, self.key_count)?;
+        match self.slotted {
+            true => writeln!(f, ", cells from {}, {} bytes fragmented", self.free_pointer, self.fragmented)?,
+            false => writeln!(f, ", legacy format")?,
+        }
+        let mut children_start = HEADER_SIZE;
+        if let Some(prefix) = &self.prefix {
+            children_start += 2 + prefix.len();
+            writeln!(f, "key prefix {}..{}: {} ({} bytes)", HEADER_SIZE, children_start, shown(prefix), prefix.len())?;
+        }
+        if !self.children.is_empty() {
+            let children: Vec<String> = self.children.iter().map(u64::to_string).collect();
+            writeln!(f, "children {}..{}: {}", children_start, children_start + self.children.len() * 8, children.join(" "))?;
+        }
+        let slots_start = children_start + self.children.len() * 8;
+        let slots: Vec<String> = self.slots.iter().map(u16::to_string).collect();
+        writeln!(f, "slots {}..{}: {}", slots_start, slots_start + self.slots.len() * 2, slots.join(" "))?;
+        for cell in &self.cells {
+            write!(f, "cell {} {}..{}: key {} ({} bytes)", cell.slot, cell.range.start, cell.range.end, shown(&cell.key), cell.key.len())?;
+            if self.is_leaf() {
+                write!(f, ", value {} ({} bytes)", shown(&cell.value), cell.value.len())?;
+            }
+            if cell.expiry != 0 {
+                write!(f, ", expires {}", cell.expiry)?;
+            }
+            writeln!(f)?;
+        }
+        for range in &self.unused {
+            writeln!(f, "unused {}..{} ({} bytes)", range.start, range.end, range.len())?;
+        }
+        for problem in &self.problems {
+            writeln!(f, "problem: {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::node::Node;
+    use crate::storage::testing::small_page_config;
+
+    fn leaf() -> Node {
+        Node {
+            keys: vec![b"apple".to_vec(), b"pear".to_vec()],
+            values: vec![b"red".to_vec(), b"green".to_vec()],
+            children: vec![],
+            expiries: vec![0, 99],
+        }
+    }
+
+    #[test]
+    fn test_inspect_leaf() {
+        let bytes = Node::encode_node(&leaf(), small_page_config()).unwrap();
+        let layout = inspect(128, bytes);
+        assert!(layout.problems.is_empty(), "{:?}", layout.problems);
+        assert_eq!((layout.slotted, layout.key_count, layout.free_pointer), (true, 2, 95));
+        // cells are written from the end of the page
+        assert_eq!(layout.slots, vec![116, 95]);
+        assert_eq!(layout.cells[0].range, 116..128);
+        assert_eq!(layout.cells[1].range, 95..116);
+        assert_eq!(layout.cells[1].expiry, 99);
+        assert_eq!(layout.unused, vec![7..95]);
+        assert_eq!(layout.unused_bytes(), 128 - leaf().encoded_size());
+        let shown = layout.to_string();
+        assert!(shown.contains("header 0..3: node type 1 (leaf), 2 keys, cells from 95, 0 bytes fragmented"));
+        assert!(shown.contains("cell 1 95..116: key pear (4 bytes), value green (5 bytes), expires 99"));
+        assert!(layout.hexdump().contains("*\n"));
+    }
+
+    #[test]
+    fn test_inspect_internal() {
+        let node = Node {
+            keys: vec![b"m".to_vec()],
+            values: vec![],
+            children: vec![256, 384],
+            expiries: vec![],
+        };
+        let layout = inspect(128, Node::encode_node(&node, small_page_config()).unwrap());
+        assert!(layout.problems.is_empty(), "{:?}", layout.problems);
+        assert_eq!(layout.children, vec![256, 384]);
+        assert_eq!(layout.cells[0].range, 123..128);
+        assert!(layout.to_string().contains("children 3..19: 256 384"));
+    }
+
+    #[test]
+    fn test_inspect_key_prefix() {
+        let node = Node {
+            keys: vec![b"user/1".to_vec(), b"user/2".to_vec()],
+            values: vec![b"a".to_vec(), b"b".to_vec()],
+            children: vec![],
+            expiries: vec![],
+        };
+        let layout = inspect(128, Node::encode_node(&node, small_page_config()).unwrap());
+        assert!(layout.problems.is_empty(), "{:?}", layout.problems);
+        assert_eq!(layout.prefix, Some(b"user/".to_vec()));
+        assert_eq!(layout.slots, vec![122, 116]);
+        assert_eq!(layout.cells[1].key, b"user/2");
+        let shown = layout.to_string();
+        assert!(shown.contains("key prefix 3..10: user/ (5 bytes)"));
+        assert!(shown.contains("slots 10..14: 122 116"));
+    }
+
+    #[test]
+    fn test_inspect_flags_damage() {
+        let mut bytes = Node::encode_node(&leaf(), small_page_config()).unwrap();
+        // the second slot points at the first cell, which leaves the real second cell unused
+        bytes[5..7].copy_from_slice(&116u16.to_le_bytes());
+        let layout = inspect(128, bytes.clone());
+        assert_eq!(layout.problems, vec![
+            "keys of cells 0 and 1 are out of order",
+            "cell 1 at 116..128 overlaps cell 0",
+        ]);
+        assert_eq!(layout.unused, vec![7..116]);
+
+        bytes[5..7].copy_from_slice(&127u16.to_le_bytes());
+        let layout = inspect(128, bytes);
+        assert_eq!(layout.problems, vec!["key length of cell 1 at 127..129 is past the end of the page"]);
+
+        let mut bytes = Node::encode_node(&leaf(), small_page_config()).unwrap();
+        // the second slot points at itself
+        bytes[5..7].copy_from_slice(&5u16.to_le_bytes());
+        assert_eq!(inspect(128, bytes).problems, vec!["keys of cells 0 and 1 are out of order", "cell 1 at 5..14 overlaps slots"]);
+
+        let mut bytes = vec![0u8; 128];
+        bytes[0] = 7;
+        assert_eq!(inspect(128, bytes).problems, vec!["unknown node type 7"]);
+    }
+}
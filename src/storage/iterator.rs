@@ -0,0 +1,111 @@
+use crate::storage::btree::now_millis;
+use crate::storage::diskmanager::DiskManager;
+use crate::storage::node::Node;
+use std::io::Result;
+use std::ops::Bound;
+
+/// Iterator over the key/value pairs of a tree within a key range, in key order
+/// Nodes are loaded from disk lazily as the iterator advances, expired entries are skipped
+pub struct RangeIter<'a> {
+    disk_manager: &'a mut DiskManager,
+    // each entry is a node on the current path and the index of the next child or key to visit
+    stack: Vec<(Node, usize)>,
+    root: Option<Node>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    now: u64,
+}
+
+impl<'a> RangeIter<'a> {
+    pub(crate) fn new(disk_manager: &'a mut DiskManager, root: Node, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Self {
+        Self {
+            disk_manager,
+            stack: vec![],
+            root: Some(root),
+            start,
+            end,
+            now: now_millis(),
+        }
+    }
+
+    /// Descend from the root to the leaf where the start bound belongs
+    fn seek(&mut self, root: Node) -> Result<()> {
+        let mut node = root;
+        while !node.children.is_empty() {
+            let pos = match &self.start {
+                Bound::Included(key) | Bound::Excluded(key) => node.child_index(key),
+                Bound::Unbounded => 0,
+            };
+            let child = self.disk_manager.load_node_from_disk(node.children[pos])?;
+            self.stack.push((node, pos + 1));
+            node = child;
+        }
+
+        let pos = match &self.start {
+            Bound::Included(key) => node.find_key(key).unwrap_or_else(|pos| pos),
+            Bound::Excluded(key) => match node.find_key(key) {
+                Ok(pos) => pos + 1,
+                Err(pos) => pos,
+            },
+            Bound::Unbounded => 0,
+        };
+        self.stack.push((node, pos));
+        Ok(())
+    }
+
+    fn past_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => key > end.as_slice(),
+            Bound::Excluded(end) => key >= end.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn advance(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if let Some(root) = self.root.take() {
+            self.seek(root)?;
+        }
+
+        while let Some((node, idx)) = self.stack.last_mut() {
+            if node.children.is_empty() {
+                if *idx < node.keys.len() {
+                    let key = node.keys[*idx].clone();
+                    let expired = node.is_expired(*idx, self.now);
+                    let value = node.values[*idx].clone();
+                    *idx += 1;
+                    if self.past_end(&key) {
+                        self.stack.clear();
+                        return Ok(None);
+                    }
+                    if expired {
+                        continue;
+                    }
+                    return Ok(Some((key, value)));
+                }
+            } else if *idx < node.children.len() {
+                let child_offset = node.children[*idx];
+                *idx += 1;
+                let child = self.disk_manager.load_node_from_disk(child_offset)?;
+                self.stack.push((child, 0));
+                continue;
+            }
+            self.stack.pop();
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for RangeIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.advance() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                // stop after the first error
+                self.stack.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use std::io::{Error, ErrorKind, Result};
+
+/// Combines the existing value of a key (None if absent) with an operand into the new value
+pub type MergeOperator = Box<dyn Fn(Option<&[u8]>, &[u8]) -> Result<Vec<u8>>>;
+
+/// Operators every tree starts with, registered under these names
+pub(crate) fn builtin_operators() -> Vec<(&'static str, MergeOperator)> {
+    vec![
+        ("u64_add", Box::new(u64_add)),
+        ("u64_max", Box::new(u64_max)),
+        ("append", Box::new(append)),
+    ]
+}
+
+/// Treats both values as little endian u64 counters and adds them, wrapping on overflow
+pub fn u64_add(existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+    let current = existing.map(read_u64).transpose()?.unwrap_or(0);
+    Ok(current.wrapping_add(read_u64(operand)?).to_le_bytes().to_vec())
+}
+
+/// Keeps the larger of two little endian u64 values
+pub fn u64_max(existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+    let current = existing.map(read_u64).transpose()?.unwrap_or(0);
+    Ok(current.max(read_u64(operand)?).to_le_bytes().to_vec())
+}
+
+/// Appends the operand bytes to the end of the existing value
+pub fn append(existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+    let mut value = existing.unwrap_or_default().to_vec();
+    value.extend_from_slice(operand);
+    Ok(value)
+}
+
+fn read_u64(bytes: &[u8]) -> Result<u64> {
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "u64 merge values must be 8 bytes"))?;
+    Ok(u64::from_le_bytes(bytes))
+}
@@ -1,4 +1,39 @@
+mod backup;
 mod btree;
+mod bucket;
+mod check;
 mod configs;
+mod csv;
+mod diff;
+mod dot;
+mod dump;
 mod node;
 mod diskmanager;
+mod history;
+mod incremental;
+mod inspect;
+mod iterator;
+mod merge;
+mod page;
+mod recover;
+mod stats;
+#[cfg(test)]
+mod testing;
+mod transaction;
+
+pub use backup::Backup;
+pub use btree::{BTree, CompareAndSwapError};
+pub use bucket::Bucket;
+pub use check::{check, check_with_config, CheckReport, Violation, ViolationKind};
+pub use configs::StorageConfig;
+pub use csv::{import_csv, ColumnType, CsvOptions, ImportMode, ImportSummary, KeyColumn, Rejection};
+pub use diff::{DiffEvent, DiffIter};
+pub use dump::{read_dump, write_dump, DumpFormat, DumpReader};
+pub use history::{CommitInfo, Retention, Snapshot};
+pub use inspect::{Cell, PageLayout};
+pub use iterator::RangeIter;
+pub use merge::{append, u64_add, u64_max, MergeOperator};
+pub use page::SlottedPage;
+pub use recover::{recover, recover_with_config, RecoveryReport};
+pub use stats::{LevelStats, TreeStats};
+pub use transaction::Transaction;
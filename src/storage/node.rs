@@ -1,94 +1,112 @@
-use crate::storage::configs::{StorageConfig, BNODE_INTERNAL, BNODE_LEAF};
+use crate::storage::configs::{StorageConfig, BNODE_INTERNAL, BNODE_LEAF, PAGE_SLOTTED, VAL_HAS_EXPIRY};
+use crate::storage::page::{self, SlottedPage, HEADER_SIZE};
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Node {
     pub keys: Vec<Vec<u8>>,
     pub children: Vec<u64>,
     pub values: Vec<Vec<u8>>,
+    pub expiries: Vec<u64>, // unix millis each leaf entry expires at, 0 if it never expires
 }
 
 impl Node {
-    /// Encode the keys, values, and children of a node + metadata
-    /// Node = node_type (u8) + num_of_keys (u16) + pointers (u64) + offsets (u16) + KV pairs (4000 bytes) + unused space
-    /// KV pairs = key_len (u16) + val_len (u16) + key bytes + val bytes
-    pub fn encode_node(node: &Node, storage_config: StorageConfig) -> Option<Vec<u8>> {
-        let mut buf = vec![0u8; storage_config.page_size as usize];
-        let mut node_type = BNODE_LEAF;
-        if !node.children.is_empty() {
-            node_type = BNODE_INTERNAL;
-        };
-        buf[0] = node_type;
+    /// Index of the child that may contain key, keys equal to a separator live in the right child
+    pub fn child_index(&self, key: &[u8]) -> usize {
+        match self.find_key(key) {
+            Ok(pos) => pos + 1,
+            Err(pos) => pos,
+        }
+    }
+
+    /// Binary search for key, Ok(pos) if it is present, Err(pos) with its insert position otherwise
+    pub fn find_key(&self, key: &[u8]) -> Result<usize, usize> {
+        self.keys.binary_search_by(|k| k.as_slice().cmp(key))
+    }
 
-        let num_keys = node.keys.len() as u16;
-        buf[1..3].copy_from_slice(&num_keys.to_le_bytes());
+    /// Expiry of the leaf entry at pos, 0 if it never expires
+    pub fn expiry(&self, pos: usize) -> u64 {
+        self.expiries.get(pos).copied().unwrap_or(0)
+    }
 
-        let mut cursor = 3;
-        if node_type == BNODE_INTERNAL {
-            // encode child pointers
-            assert_eq!(node.children.len(), node.keys.len() + 1);
+    /// Whether the leaf entry at pos has expired at time now (unix millis)
+    pub fn is_expired(&self, pos: usize, now: u64) -> bool {
+        let expiry = self.expiry(pos);
+        expiry != 0 && expiry <= now
+    }
 
-            for child_ptr in &node.children {
-                let ptr_bytes = child_ptr.to_le_bytes();
-                buf[cursor..cursor + 8].copy_from_slice(&ptr_bytes);
-                cursor += 8;
-            }
+    /// Number of bytes encode_node needs for this node, excluding unused space
+    pub fn encoded_size(&self) -> usize {
+        let prefix_len = page::prefix_len(&self.keys);
+        let mut size = HEADER_SIZE + self.children.len() * 8 + self.keys.len() * 2;
+        if prefix_len > 0 {
+            size += 2 + prefix_len;
         }
-        let offsets_start = cursor;
-        cursor += (num_keys as usize) * 2;
-        for i in 0..num_keys as usize {
-            let key = &node.keys[i];
-            let val = if node_type == BNODE_LEAF {
-                &node.values[i]
-            } else {
-                &[] as &[u8]
-            };
-            let key_len = key.len() as u16;
-            let val_len = val.len() as u16;
-
-            assert!(key_len <= storage_config.max_key_size);
-            assert!(val_len <= storage_config.max_val_size);
-
-            let offset = cursor as u16;
-            let offset_pos = offsets_start + i * 2;
-            buf[offset_pos..offset_pos + 2].copy_from_slice(&offset.to_le_bytes());
-
-            let needed_space = 2+2+key.len()+val.len(); // 2 bytes for each length
-            if cursor + needed_space > buf.len() {
-                return None;
+        for i in 0..self.keys.len() {
+            size += 4 + self.keys[i].len() - prefix_len;
+            if self.children.is_empty() {
+                size += self.values[i].len();
+                if self.expiry(i) != 0 {
+                    size += 8;
+                }
             }
+        }
+        size
+    }
 
-            buf[cursor..cursor + 2].copy_from_slice(&key_len.to_le_bytes());
-            buf[cursor + 2..cursor + 4].copy_from_slice(&val_len.to_le_bytes());
-            cursor += 4;
+    /// Encode the keys, values, and children of a node as a slotted page, see SlottedPage for the layout
+    /// None if the node doesn't fit in a page
+    pub fn encode_node(node: &Node, storage_config: StorageConfig) -> Option<Vec<u8>> {
+        if !node.children.is_empty() {
+            assert_eq!(node.children.len(), node.keys.len() + 1);
+        }
+        for (i, key) in node.keys.iter().enumerate() {
+            assert!(key.len() <= storage_config.max_key_size as usize);
+            if node.children.is_empty() {
+                assert!(node.values[i].len() <= storage_config.max_val_size as usize);
+            }
+        }
+        SlottedPage::from_node(node, storage_config.page_size as usize).map(SlottedPage::into_bytes)
+    }
 
-            // Write key bytes
-            buf[cursor..cursor + key_len as usize].copy_from_slice(key);
-            cursor += key_len as usize;
+    pub fn decode_node(buf: Vec<u8>) -> Node {
+        Self::try_decode_node(&buf).expect("page is not a valid node")
+    }
 
-            // Write value bytes
-            buf[cursor..cursor + val_len as usize].copy_from_slice(val);
-            cursor += val_len as usize;
+    /// Decode a page that may be damaged, fails instead of reading past the end of the page
+    pub fn try_decode_node(buf: &[u8]) -> std::io::Result<Node> {
+        if buf.first().is_some_and(|&node_type| node_type & PAGE_SLOTTED != 0) {
+            return SlottedPage::from_bytes(buf.to_vec()).map(|page| page.to_node());
         }
-
-        Some(buf)
+        Self::decode_legacy_node(buf)
     }
 
-    pub fn decode_node(buf: Vec<u8>) -> Node {
-        let node_type = buf[0];
+    /// Decode a page written before the slotted format
+    /// Node = node_type (u8) + num_of_keys (u16) + pointers (u64) + offsets (u16) + KV pairs + unused space
+    /// KV pairs = key_len (u16) + val_len (u16) + key bytes + val bytes, packed right after the offsets
+    fn decode_legacy_node(buf: &[u8]) -> std::io::Result<Node> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+        let read = |start: usize, len: usize| {
+            buf.get(start..start + len)
+                .ok_or_else(|| invalid(format!("bytes {}..{} are past the end of the page", start, start + len)))
+        };
+
+        let node_type = *buf.first().ok_or_else(|| invalid("page is empty".to_string()))?;
+        if node_type != BNODE_INTERNAL && node_type != BNODE_LEAF {
+            return Err(invalid(format!("unknown node type {}", node_type)));
+        }
         let is_leaf = node_type == BNODE_LEAF;
 
-        let num_keys = u16::from_le_bytes([buf[1], buf[2]]) as usize;
+        let num_keys = u16::from_le_bytes(read(1, 2)?.try_into().unwrap()) as usize;
 
         let mut keys = Vec::with_capacity(num_keys);
         let mut values = Vec::with_capacity(num_keys);
+        let mut expiries = Vec::with_capacity(num_keys);
         let mut children = Vec::with_capacity(num_keys + 1);
 
         let mut cursor = 3;
         if !is_leaf {
             for i in 0..num_keys + 1 {
-                let start = 3 + i * 8;
-                let end = start + 8;
-                let child = u64::from_le_bytes(buf[start..end].try_into().unwrap());
+                let child = u64::from_le_bytes(read(3 + i * 8, 8)?.try_into().unwrap());
                 children.push(child);
             }
             cursor += (num_keys + 1) * 8;
@@ -96,36 +114,39 @@ impl Node {
 
         let mut offsets = Vec::with_capacity(num_keys);
         for i in 0..num_keys {
-            let start = cursor + i * 2;
-            let offset = u16::from_le_bytes([buf[start], buf[start + 1]]);
+            let offset = u16::from_le_bytes(read(cursor + i * 2, 2)?.try_into().unwrap());
             offsets.push(offset);
         }
 
         for offset in offsets {
             let offset = offset as usize;
-            let key_len = u16::from_le_bytes([buf[offset], buf[offset + 1]]) as usize;
-            let val_len = u16::from_le_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+            let key_len = u16::from_le_bytes(read(offset, 2)?.try_into().unwrap()) as usize;
+            let val_len_field = u16::from_le_bytes(read(offset + 2, 2)?.try_into().unwrap());
+            let has_expiry = val_len_field & VAL_HAS_EXPIRY != 0;
+            let val_len = (val_len_field & !VAL_HAS_EXPIRY) as usize;
 
             let key_start = offset + 4;
-            let key_end = key_start + key_len;
-            let val_start = key_end;
-            let val_end = val_start + val_len;
-
-            let key = buf[key_start..key_end].to_vec();
+            let mut val_start = key_start + key_len;
 
-            keys.push(key);
+            keys.push(read(key_start, key_len)?.to_vec());
 
             if is_leaf {
-                let val = buf[val_start..val_end].to_vec();
-                values.push(val);
+                let mut expiry = 0;
+                if has_expiry {
+                    expiry = u64::from_le_bytes(read(val_start, 8)?.try_into().unwrap());
+                    val_start += 8;
+                }
+                values.push(read(val_start, val_len)?.to_vec());
+                expiries.push(expiry);
             }
         }
 
-        Node {
+        Ok(Node {
             keys,
             children,
             values,
-        }
+            expiries,
+        })
     }
 }
 
@@ -141,6 +162,7 @@ mod test {
             keys,
             values,
             children,
+            expiries: vec![],
         }
     }
 
@@ -150,6 +172,7 @@ mod test {
             keys: vec![b"key1".to_vec()],
             values: vec![b"value1".to_vec()],
             children: vec![],
+            expiries: vec![],
         };
 
         let encoded = Node::encode_node(&node, StorageConfig::default());
@@ -178,6 +201,7 @@ mod test {
             keys: vec![b"key1".to_vec(), b"key2".to_vec()],
             values: vec![],
             children: vec![10, 20, 30],
+            expiries: vec![],
         };
 
         let encoded = Node::encode_node(&node, StorageConfig::default());
@@ -187,4 +211,64 @@ mod test {
         assert_eq!(node.values, decoded.values);
         assert_eq!(node.children, decoded.children);
     }
+
+    #[test]
+    fn test_encode_decode_expiries() {
+        let node = Node {
+            keys: vec![b"key1".to_vec(), b"key2".to_vec()],
+            values: vec![b"value1".to_vec(), b"value2".to_vec()],
+            children: vec![],
+            expiries: vec![0, 1_700_000_000_000],
+        };
+
+        let encoded = Node::encode_node(&node, StorageConfig::default()).unwrap();
+        // "key" is stored once, with its length
+        assert_eq!(node.encoded_size(), 3 + (2 + 3) + 2 * 2 + (4 + 1 + 6) * 2 + 8);
+        let decoded = Node::decode_node(encoded);
+
+        assert_eq!(decoded, node);
+        assert!(!decoded.is_expired(0, u64::MAX));
+        assert!(decoded.is_expired(1, 1_700_000_000_000));
+        assert!(!decoded.is_expired(1, 1_699_999_999_999));
+    }
+
+    #[test]
+    fn test_try_decode_damaged_page() {
+        let encoded = Node::encode_node(&create_sample_node(), StorageConfig::default()).unwrap();
+        assert_eq!(Node::try_decode_node(&encoded).unwrap(), Node::decode_node(encoded.clone()));
+
+        // a key offset pointing past the end of the page
+        let mut damaged = encoded.clone();
+        damaged[HEADER_SIZE..HEADER_SIZE + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(Node::try_decode_node(&damaged).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        let mut damaged = encoded;
+        damaged[0] = 7;
+        assert!(Node::try_decode_node(&damaged).is_err());
+        assert!(Node::try_decode_node(&[]).is_err());
+    }
+
+    #[test]
+    fn test_decode_legacy_page() {
+        // a leaf as pages were written before the slotted format, cells packed right after the slots
+        let mut page = vec![0u8; 128];
+        page[0] = BNODE_LEAF;
+        page[1..3].copy_from_slice(&2u16.to_le_bytes());
+        page[3..5].copy_from_slice(&7u16.to_le_bytes());
+        page[5..7].copy_from_slice(&16u16.to_le_bytes());
+        page[7..16].copy_from_slice(b"\x02\x00\x03\x00k1red");
+        page[16..35].copy_from_slice(b"\x02\x00\x05\x80k2\x63\x00\x00\x00\x00\x00\x00\x00green");
+
+        let node = Node::try_decode_node(&page).unwrap();
+        assert_eq!(node, Node {
+            keys: vec![b"k1".to_vec(), b"k2".to_vec()],
+            values: vec![b"red".to_vec(), b"green".to_vec()],
+            children: vec![],
+            expiries: vec![0, 99],
+        });
+        // written back in the slotted format
+        let encoded = Node::encode_node(&node, StorageConfig::default()).unwrap();
+        assert_eq!(encoded[0], BNODE_LEAF | PAGE_SLOTTED);
+        assert_eq!(Node::decode_node(encoded), node);
+    }
 }
@@ -0,0 +1,510 @@
+use crate::storage::configs::{BNODE_INTERNAL, BNODE_LEAF, PAGE_PREFIX, PAGE_SLOTTED, VAL_HAS_EXPIRY};
+use crate::storage::node::Node;
+use std::cmp::Ordering;
+use std::io::{Error, ErrorKind, Result};
+
+/// Node type and flags (u8) and key count (u16), the same as the legacy header
+pub const HEADER_SIZE: usize = 3;
+
+/// A node in the slotted page format, edited in place on its bytes
+/// Page = header + [prefix] + child pointers (u64, internal nodes only) + slots (u16) + free space + cells
+/// With PAGE_PREFIX set in the node type byte, prefix_len (u16) + prefix bytes follow the header and
+/// the cells only hold what comes after the prefix in each key
+/// Slots grow from the front and hold the offset of each cell in key order, cells are written
+/// from the end of the page towards the front and the free space pointer is where the lowest one
+/// starts. Cells are laid out as in the legacy format:
+/// key_len (u16) + val_len (u16) + key bytes + expiry (u64, if the top bit of val_len is set) + val bytes
+/// Space of removed cells is counted as fragmented until defragment packs the cells against the end
+/// of the page again. Neither the free space pointer nor the fragmented bytes are stored, both are
+/// worked out from the slots when a page is read so small pages keep room for their cells
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlottedPage {
+    buf: Vec<u8>,
+    free_pointer: usize,
+    fragmented: usize,
+}
+
+impl SlottedPage {
+    /// An empty page, an internal page starts out with a single child pointer set to 0
+    pub fn new(is_leaf: bool, page_size: usize) -> Self {
+        let mut buf = vec![0u8; page_size];
+        buf[0] = if is_leaf { BNODE_LEAF } else { BNODE_INTERNAL } | PAGE_SLOTTED;
+        Self { buf, free_pointer: page_size, fragmented: 0 }
+    }
+
+    /// Take the bytes of a page, checking that every slot points at a cell inside the page so
+    /// the accessors can't read past it
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+        if buf.len() < HEADER_SIZE || buf[0] & PAGE_SLOTTED == 0 {
+            return Err(invalid("not a slotted page".to_string()));
+        }
+        let node_type = buf[0] & !(PAGE_SLOTTED | PAGE_PREFIX);
+        if node_type != BNODE_INTERNAL && node_type != BNODE_LEAF {
+            return Err(invalid(format!("unknown node type {}", node_type)));
+        }
+        let page_size = buf.len();
+        let mut page = Self { buf, free_pointer: page_size, fragmented: 0 };
+        if page.has_prefix() && (HEADER_SIZE + 2 > page_size || page.children_start() > page_size) {
+            return Err(invalid("key prefix is past the end of the page".to_string()));
+        }
+        let front_end = page.front_end();
+        if front_end > page_size {
+            return Err(invalid(format!("{} slots don't fit in the page", page.key_count())));
+        }
+        for slot in 0..page.key_count() {
+            let start = page.slot(slot);
+            let fits = start + 4 <= page_size && start + page.cell_len(start) <= page_size;
+            if start < front_end || !fits {
+                return Err(invalid(format!("cell {} at {} is outside the cell area", slot, start)));
+            }
+        }
+        if !page.measure_cells() {
+            return Err(invalid("cells take more space than the cell area".to_string()));
+        }
+        Ok(page)
+    }
+
+    /// Lay out a node as a new page, None if it doesn't fit
+    /// The prefix shared by all keys is stored once when that takes less space, see prefix_len
+    pub fn from_node(node: &Node, page_size: usize) -> Option<Self> {
+        let mut page = Self::new(node.children.is_empty(), page_size);
+        let prefix_len = prefix_len(&node.keys);
+        if prefix_len > 0 && HEADER_SIZE + 2 + prefix_len <= page_size {
+            page.buf[0] |= PAGE_PREFIX;
+            page.set_u16(HEADER_SIZE, prefix_len);
+            page.buf[HEADER_SIZE + 2..HEADER_SIZE + 2 + prefix_len].copy_from_slice(&node.keys[0][..prefix_len]);
+        }
+        if page.front_end() > page_size {
+            return None;
+        }
+        if let Some(&first_child) = node.children.first() {
+            page.set_child(0, first_child);
+        }
+        for (i, key) in node.keys.iter().enumerate() {
+            let inserted = match node.children.get(i + 1) {
+                Some(&right_child) => page.insert_separator(i, key, right_child),
+                None => page.insert_entry(i, key, &node.values[i], node.expiry(i)),
+            };
+            if !inserted {
+                return None;
+            }
+        }
+        Some(page)
+    }
+
+    pub fn to_node(&self) -> Node {
+        let count = self.key_count();
+        let is_leaf = self.is_leaf();
+        Node {
+            keys: (0..count).map(|i| self.key(i)).collect(),
+            children: (0..self.child_count()).map(|i| self.child(i)).collect(),
+            values: if is_leaf { (0..count).map(|i| self.value(i).to_vec()).collect() } else { vec![] },
+            expiries: if is_leaf { (0..count).map(|i| self.expiry(i)).collect() } else { vec![] },
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.buf[0] & !(PAGE_SLOTTED | PAGE_PREFIX) == BNODE_LEAF
+    }
+
+    fn has_prefix(&self) -> bool {
+        self.buf[0] & PAGE_PREFIX != 0
+    }
+
+    /// Bytes every key on the page starts with, empty unless the page was written with a prefix
+    pub fn prefix(&self) -> &[u8] {
+        match self.has_prefix() {
+            true => &self.buf[HEADER_SIZE + 2..self.children_start()],
+            false => &[],
+        }
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.u16_at(1)
+    }
+
+    /// Start of the lowest cell, the contiguous free space ends here
+    pub fn free_pointer(&self) -> usize {
+        self.free_pointer
+    }
+
+    /// Bytes of removed cells that defragment would win back
+    pub fn fragmented(&self) -> usize {
+        self.fragmented
+    }
+
+    /// Bytes available to new cells and their slots, counting what defragment would win back
+    pub fn free_space(&self) -> usize {
+        self.free_pointer() - self.front_end() + self.fragmented()
+    }
+
+    /// Bytes the page would need without any free space, the same as Node::encoded_size
+    pub fn used_size(&self) -> usize {
+        self.buf.len() - self.free_space()
+    }
+
+    fn child_count(&self) -> usize {
+        if self.is_leaf() { 0 } else { self.key_count() + 1 }
+    }
+
+    fn children_start(&self) -> usize {
+        match self.has_prefix() {
+            true => HEADER_SIZE + 2 + self.u16_at(HEADER_SIZE),
+            false => HEADER_SIZE,
+        }
+    }
+
+    fn slots_start(&self) -> usize {
+        self.children_start() + self.child_count() * 8
+    }
+
+    /// End of the header, child pointers and slots
+    fn front_end(&self) -> usize {
+        self.slots_start() + self.key_count() * 2
+    }
+
+    fn slot(&self, slot: usize) -> usize {
+        self.u16_at(self.slots_start() + slot * 2)
+    }
+
+    fn cell_len(&self, start: usize) -> usize {
+        let key_len = self.u16_at(start);
+        let val_len_field = self.u16_at(start + 2) as u16;
+        let expiry_len = if val_len_field & VAL_HAS_EXPIRY != 0 { 8 } else { 0 };
+        4 + key_len + expiry_len + (val_len_field & !VAL_HAS_EXPIRY) as usize
+    }
+
+    /// Key at slot with the page prefix put back in front
+    pub fn key(&self, slot: usize) -> Vec<u8> {
+        [self.prefix(), self.key_suffix(slot)].concat()
+    }
+
+    /// Key at slot as stored in its cell, without the page prefix
+    fn key_suffix(&self, slot: usize) -> &[u8] {
+        let start = self.slot(slot);
+        let key_len = self.u16_at(start);
+        &self.buf[start + 4..start + 4 + key_len]
+    }
+
+    pub fn expiry(&self, slot: usize) -> u64 {
+        let start = self.slot(slot);
+        if self.u16_at(start + 2) as u16 & VAL_HAS_EXPIRY == 0 {
+            return 0;
+        }
+        let at = start + 4 + self.u16_at(start);
+        u64::from_le_bytes(self.buf[at..at + 8].try_into().unwrap())
+    }
+
+    pub fn value(&self, slot: usize) -> &[u8] {
+        let start = self.slot(slot);
+        let end = start + self.cell_len(start);
+        let val_len = (self.u16_at(start + 2) as u16 & !VAL_HAS_EXPIRY) as usize;
+        &self.buf[end - val_len..end]
+    }
+
+    pub fn child(&self, i: usize) -> u64 {
+        let at = self.children_start() + i * 8;
+        u64::from_le_bytes(self.buf[at..at + 8].try_into().unwrap())
+    }
+
+    pub fn set_child(&mut self, i: usize, offset: u64) {
+        let at = self.children_start() + i * 8;
+        self.buf[at..at + 8].copy_from_slice(&offset.to_le_bytes());
+    }
+
+    /// Binary search over the slots, Ok(slot) if key is present, Err(slot) with its insert position otherwise
+    pub fn find_key(&self, key: &[u8]) -> std::result::Result<usize, usize> {
+        let (mut low, mut high) = (0, self.key_count());
+        let prefix = self.prefix();
+        let Some(key) = key.strip_prefix(prefix) else {
+            // every key on the page starts with the prefix, so key sorts before or after all of them
+            return Err(if key < prefix { 0 } else { high });
+        };
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.key_suffix(mid).cmp(key) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(low)
+    }
+
+    /// Insert a leaf entry at slot, returns false if it doesn't fit even after defragmenting
+    /// or doesn't start with the page prefix
+    pub fn insert_entry(&mut self, slot: usize, key: &[u8], value: &[u8], expiry: u64) -> bool {
+        let Some(suffix) = key.strip_prefix(self.prefix()) else {
+            return false;
+        };
+        let cell = encode_cell(suffix, value, expiry);
+        self.insert_cell(slot, &cell, None)
+    }
+
+    /// Insert a separator at slot of an internal page along with the child to its right
+    /// returns false if it doesn't fit even after defragmenting or doesn't start with the page prefix
+    pub fn insert_separator(&mut self, slot: usize, key: &[u8], right_child: u64) -> bool {
+        let Some(suffix) = key.strip_prefix(self.prefix()) else {
+            return false;
+        };
+        let cell = encode_cell(suffix, &[], 0);
+        self.insert_cell(slot, &cell, Some(right_child))
+    }
+
+    /// Overwrite the value and expiry of the leaf entry at slot, returns false if the new cell doesn't fit
+    pub fn replace_value(&mut self, slot: usize, value: &[u8], expiry: u64) -> bool {
+        let cell = encode_cell(self.key_suffix(slot), value, expiry);
+        if cell.len() > self.free_space() + self.cell_len(self.slot(slot)) {
+            return false;
+        }
+        self.remove_entry(slot);
+        self.insert_cell(slot, &cell, None)
+    }
+
+    /// Remove the leaf entry at slot
+    pub fn remove_entry(&mut self, slot: usize) {
+        self.remove_cell(slot, false);
+    }
+
+    /// Remove the separator at slot of an internal page along with the child to its right
+    pub fn remove_separator(&mut self, slot: usize) {
+        self.remove_cell(slot, true);
+    }
+
+    /// Move every cell against the end of the page, so all free space is contiguous again
+    pub fn defragment(&mut self) {
+        let cells: Vec<Vec<u8>> = (0..self.key_count())
+            .map(|slot| {
+                let start = self.slot(slot);
+                self.buf[start..start + self.cell_len(start)].to_vec()
+            })
+            .collect();
+        let front_end = self.front_end();
+        let mut free_pointer = self.buf.len();
+        self.buf[front_end..].fill(0);
+        for (slot, cell) in cells.iter().enumerate() {
+            free_pointer -= cell.len();
+            self.buf[free_pointer..free_pointer + cell.len()].copy_from_slice(cell);
+            let at = self.slots_start() + slot * 2;
+            self.set_u16(at, free_pointer);
+        }
+        self.free_pointer = free_pointer;
+        self.fragmented = 0;
+    }
+
+    fn insert_cell(&mut self, slot: usize, cell: &[u8], right_child: Option<u64>) -> bool {
+        let front_growth = 2 + if right_child.is_some() { 8 } else { 0 };
+        if cell.len() + front_growth > self.free_space() {
+            return false;
+        }
+        if cell.len() + front_growth > self.free_pointer() - self.front_end() {
+            self.defragment();
+        }
+        let start = self.free_pointer - cell.len();
+        self.buf[start..start + cell.len()].copy_from_slice(cell);
+        self.free_pointer = start;
+
+        let mut front_end = self.front_end();
+        let mut slots_start = self.slots_start();
+        if let Some(right_child) = right_child {
+            let at = self.children_start() + (slot + 1) * 8;
+            self.buf.copy_within(at..front_end, at + 8);
+            self.buf[at..at + 8].copy_from_slice(&right_child.to_le_bytes());
+            front_end += 8;
+            slots_start += 8;
+        }
+        let at = slots_start + slot * 2;
+        self.buf.copy_within(at..front_end, at + 2);
+        self.set_u16(at, start);
+        self.set_u16(1, self.key_count() + 1);
+        true
+    }
+
+    fn remove_cell(&mut self, slot: usize, with_right_child: bool) {
+        let front_end = self.front_end();
+        let at = self.slots_start() + slot * 2;
+        self.buf.copy_within(at + 2..front_end, at);
+        let mut new_front_end = front_end - 2;
+        if with_right_child {
+            let at = self.children_start() + (slot + 1) * 8;
+            self.buf.copy_within(at + 8..new_front_end, at);
+            new_front_end -= 8;
+        }
+        self.buf[new_front_end..front_end].fill(0);
+        self.set_u16(1, self.key_count() - 1);
+        // the page reads back the same once the free space pointer moves past a removed lowest cell
+        self.measure_cells();
+    }
+
+    /// Set the free space pointer to the lowest cell and count the rest of the cell area that no
+    /// slot points at as fragmented, false if the cells take more than the cell area
+    fn measure_cells(&mut self) -> bool {
+        let starts = (0..self.key_count()).map(|slot| self.slot(slot));
+        self.free_pointer = starts.min().unwrap_or(self.buf.len());
+        let cells_len: usize = (0..self.key_count()).map(|slot| self.cell_len(self.slot(slot))).sum();
+        match (self.buf.len() - self.free_pointer).checked_sub(cells_len) {
+            Some(fragmented) => {
+                self.fragmented = fragmented;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn u16_at(&self, at: usize) -> usize {
+        u16::from_le_bytes([self.buf[at], self.buf[at + 1]]) as usize
+    }
+
+    fn set_u16(&mut self, at: usize, value: usize) {
+        self.buf[at..at + 2].copy_from_slice(&(value as u16).to_le_bytes());
+    }
+}
+
+/// Length of the prefix shared by all keys that from_node stores once, 0 when that wouldn't save
+/// space: each key gets shorter by it, but the page needs its length and one copy of it
+pub(crate) fn prefix_len(keys: &[Vec<u8>]) -> usize {
+    let Some((first, rest)) = keys.split_first() else {
+        return 0;
+    };
+    let len = rest.iter().fold(first.len(), |len, key| {
+        first[..len].iter().zip(key).take_while(|(a, b)| a == b).count()
+    });
+    if len * keys.len() > len + 2 { len } else { 0 }
+}
+
+fn encode_cell(key: &[u8], value: &[u8], expiry: u64) -> Vec<u8> {
+    let mut cell = Vec::with_capacity(4 + key.len() + 8 + value.len());
+    let val_len = if expiry != 0 { value.len() as u16 | VAL_HAS_EXPIRY } else { value.len() as u16 };
+    cell.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    cell.extend_from_slice(&val_len.to_le_bytes());
+    cell.extend_from_slice(key);
+    if expiry != 0 {
+        cell.extend_from_slice(&expiry.to_le_bytes());
+    }
+    cell.extend_from_slice(value);
+    cell
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::testing::key;
+
+    #[test]
+    fn test_insert_remove_and_defragment() {
+        let mut page = SlottedPage::new(true, 128);
+        assert_eq!(page.free_space(), 128 - HEADER_SIZE);
+        // inserted out of order, the slots keep key order
+        for i in [3, 1, 2, 0] {
+            let slot = page.find_key(&key(i)).unwrap_err();
+            assert!(page.insert_entry(slot, &key(i), b"v", if i == 2 { 42 } else { 0 }));
+        }
+        assert_eq!(page.find_key(&key(2)), Ok(2));
+        assert_eq!(page.expiry(2), 42);
+        // pages built up by inserts keep every key whole
+        assert_eq!(page.used_size(), HEADER_SIZE + 4 * 2 + 4 * (4 + 7 + 1) + 8);
+
+        page.remove_entry(1);
+        assert_eq!(page.key_count(), 3);
+        assert_eq!(page.fragmented(), 4 + 7 + 1);
+        assert_eq!(page.find_key(&key(1)), Err(1));
+        assert!(page.replace_value(0, b"longer value", 0));
+        assert_eq!(page.value(0), b"longer value");
+
+        // a cell bigger than the contiguous gap fits once the page is defragmented
+        let free_space = page.free_space();
+        let value = vec![7u8; free_space - 2 - 4 - 7];
+        assert!(page.insert_entry(1, &key(1), &value, 0));
+        assert_eq!((page.free_space(), page.fragmented()), (0, 0));
+        assert!(!page.insert_entry(4, &key(9), b"", 0));
+        let node = page.to_node();
+        assert_eq!(node.keys, (0..4).map(key).collect::<Vec<_>>());
+        assert_eq!(node.values[1], value);
+        assert_eq!(SlottedPage::from_bytes(page.into_bytes()).unwrap().to_node(), node);
+    }
+
+    #[test]
+    fn test_internal_page() {
+        let node = Node {
+            keys: vec![key(1), key(2)],
+            values: vec![],
+            children: vec![10, 20, 30],
+            expiries: vec![],
+        };
+        let mut page = SlottedPage::from_node(&node, 128).unwrap();
+        assert_eq!(page.to_node(), node);
+        assert!(page.insert_separator(1, b"key0001a", 15));
+        page.remove_separator(0);
+        assert_eq!(page.to_node(), Node {
+            keys: vec![b"key0001a".to_vec(), key(2)],
+            values: vec![],
+            children: vec![10, 15, 30],
+            expiries: vec![],
+        });
+    }
+
+    #[test]
+    fn test_prefix_compression() {
+        let keys: Vec<Vec<u8>> = (0..4).map(|i| format!("tenant-0042/path/{}", i).into_bytes()).collect();
+        let node = Node {
+            keys: keys.clone(),
+            values: vec![b"v".to_vec(); 4],
+            children: vec![],
+            expiries: vec![0; 4],
+        };
+        let mut page = SlottedPage::from_node(&node, 256).unwrap();
+        assert_eq!(page.prefix(), b"tenant-0042/path/");
+        assert_eq!(page.used_size(), node.encoded_size());
+        assert!(node.encoded_size() < HEADER_SIZE + 4 * 2 + keys.iter().map(|key| 4 + key.len() + 1).sum::<usize>());
+        assert_eq!(page.to_node(), node);
+
+        assert_eq!(page.find_key(&keys[2]), Ok(2));
+        assert_eq!(page.find_key(b"tenant-0042/path/25"), Err(3));
+        assert_eq!(page.find_key(b"tenant-0041"), Err(0));
+        assert_eq!(page.find_key(b"tenant-0043"), Err(4));
+        // keys without the prefix need the page to be laid out again
+        assert!(!page.insert_entry(4, b"tenant-0043", b"v", 0));
+        assert!(page.insert_entry(4, b"tenant-0042/path/9", b"w", 0));
+        assert_eq!(page.key(4), b"tenant-0042/path/9");
+
+        // few or short shared prefixes aren't worth storing
+        assert_eq!(prefix_len(&[b"abc".to_vec()]), 0);
+        assert_eq!(prefix_len(&[b"ab1".to_vec(), b"ab2".to_vec()]), 0);
+        assert_eq!(prefix_len(&[b"abc1".to_vec(), b"abc2".to_vec()]), 3);
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_damage() {
+        let page = SlottedPage::from_node(&Node {
+            keys: vec![key(1)],
+            values: vec![b"v".to_vec()],
+            children: vec![],
+            expiries: vec![],
+        }, 128).unwrap();
+        let bytes = page.into_bytes();
+
+        let mut damaged = bytes.clone();
+        // the only slot points into the header
+        damaged[HEADER_SIZE..HEADER_SIZE + 2].copy_from_slice(&1u16.to_le_bytes());
+        assert!(SlottedPage::from_bytes(damaged).is_err());
+        let mut damaged = bytes.clone();
+        damaged[HEADER_SIZE..HEADER_SIZE + 2].copy_from_slice(&200u16.to_le_bytes());
+        assert!(SlottedPage::from_bytes(damaged).is_err());
+        let mut damaged = bytes.clone();
+        // more slots than the page has room for
+        damaged[1..3].copy_from_slice(&100u16.to_le_bytes());
+        assert!(SlottedPage::from_bytes(damaged).is_err());
+        let mut damaged = bytes;
+        damaged[0] = 5 | PAGE_SLOTTED;
+        assert!(SlottedPage::from_bytes(damaged).is_err());
+    }
+}
@@ -0,0 +1,434 @@
+use crate::storage::btree::{in_range, now_millis, BTree, PutMode};
+use crate::storage::bucket;
+use crate::storage::configs::StorageConfig;
+use crate::storage::diskmanager::DiskManager;
+use crate::storage::node::Node;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Bound;
+use std::path::Path;
+
+// key -> value and expiry
+type Entries = BTreeMap<Vec<u8>, (Vec<u8>, u64)>;
+
+// start and end of the keys a subtree covers
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+// keys written per commit while rebuilding, so replaced pages can be reused along the way
+const BATCH_SIZE: usize = 512;
+
+/// What recover found in the damaged file and wrote to the new one
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecoveryReport {
+    pub pages_scanned: usize,
+    /// Leaves holding at least one entry that were taken into the new file
+    pub leaves_recovered: usize,
+    /// Pages that didn't decode or broke a leaf invariant
+    pub pages_skipped: usize,
+    pub keys_recovered: usize,
+    /// Keys found on more than one of the leaves that fill damaged ranges, the one at the highest offset won
+    pub duplicates: usize,
+}
+
+/// Salvage the database file at src into a new file at dst, written with the default storage config
+pub fn recover(src: &str, dst: &str) -> Result<RecoveryReport> {
+    recover_with_config(src, dst, StorageConfig::default())
+}
+
+/// Salvage every entry that can still be read from src into a new file at dst
+/// The trees the metadata leads to are walked first and the leaves they reach are taken as they
+/// are, so deleted keys and old values left behind in freed pages stay gone. Only the key ranges
+/// of subtrees that didn't decode are filled from the other pages of the file, each tried as a leaf
+/// whatever points at it, and when those leaves disagree the one at the higher offset wins. Without
+/// readable metadata or catalog that fallback covers every key of the main tree
+pub fn recover_with_config(src: &str, dst: &str, storage_config: StorageConfig) -> Result<RecoveryReport> {
+    if !Path::new(src).is_file() {
+        return Err(Error::new(ErrorKind::NotFound, format!("{} does not exist", src)));
+    }
+    if Path::new(dst).exists() {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", dst)));
+    }
+    let mut disk_manager = DiskManager::open_read_only(src, storage_config.clone())?;
+
+    let mut report = RecoveryReport {
+        pages_scanned: 0,
+        leaves_recovered: 0,
+        pages_skipped: 0,
+        keys_recovered: 0,
+        duplicates: 0,
+    };
+    let now = now_millis();
+    let mut salvage = Salvage {
+        trees: BTreeMap::new(),
+        damaged: vec![],
+        reached: HashSet::new(),
+        now,
+    };
+    salvage.walk_metadata(&mut disk_manager, &mut report);
+
+    for offset in disk_manager.page_offsets()? {
+        report.pages_scanned += 1;
+        if salvage.reached.contains(&offset) {
+            continue;
+        }
+        let Some(node) = read_leaf(&mut disk_manager, offset) else {
+            report.pages_skipped += 1;
+            continue;
+        };
+        let mut used = false;
+        for (pos, (key, value)) in node.keys.iter().zip(&node.values).enumerate() {
+            let Some(owner) = salvage.damaged_owner(key) else {
+                continue;
+            };
+            used = true;
+            if node.is_expired(pos, now) {
+                continue;
+            }
+            // pages are scanned in offset order, so a later one replaces what an earlier one held
+            if salvage.trees.entry(owner).or_default().insert(key.clone(), (value.clone(), node.expiry(pos))).is_some() {
+                report.duplicates += 1;
+            }
+        }
+        if used {
+            report.leaves_recovered += 1;
+        }
+    }
+
+    let mut btree = BTree::new(dst, Some(storage_config))?;
+    for (owner, entries) in salvage.trees {
+        report.keys_recovered += entries.len();
+        let entries: Vec<_> = entries.into_iter().collect();
+        let mut root_offset = match &owner {
+            Some(name) => {
+                btree.create_bucket(name)?;
+                0
+            }
+            None => btree.root_offset,
+        };
+        for batch in entries.chunks(BATCH_SIZE) {
+            for (key, (value, expiry)) in batch {
+                if let (Some(new_root_offset), _) = btree.put_in_tree(root_offset, key, value.clone(), *expiry, PutMode::Upsert)? {
+                    root_offset = new_root_offset;
+                }
+            }
+            // a bucket's new root goes through the catalog, in the same commit
+            let (main_root_offset, catalog_offset) = match &owner {
+                Some(name) => {
+                    let catalog_offset = btree.catalog_offset;
+                    (btree.root_offset, btree.insert_into_tree(catalog_offset, name.as_bytes(), &bucket::encode_root(root_offset))?.0)
+                }
+                None => (root_offset, btree.catalog_offset),
+            };
+            btree.commit(main_root_offset, catalog_offset)?;
+        }
+    }
+    Ok(report)
+}
+
+/// What the walk from the metadata found
+struct Salvage {
+    /// Bucket name, None for the main tree, to the entries recovered for it
+    trees: BTreeMap<Option<String>, Entries>,
+    /// Key ranges of subtrees that didn't decode and the tree they belong to
+    damaged: Vec<(Option<String>, KeyRange)>,
+    /// Pages that decoded on the walk, catalog and history pages included
+    reached: HashSet<u64>,
+    now: u64,
+}
+
+impl Salvage {
+    /// Walk the main tree, the catalog and every bucket, the history is only marked as reached
+    fn walk_metadata(&mut self, disk_manager: &mut DiskManager, report: &mut RecoveryReport) {
+        let everything = (None, (Bound::Unbounded, Bound::Unbounded));
+        let Ok(metadata) = disk_manager.read_metadata() else {
+            self.damaged.push(everything);
+            return;
+        };
+        let mut history = vec![];
+        walk(disk_manager, metadata.history_offset, &mut history);
+        self.reached.extend(history.into_iter().map(|(offset, _)| offset));
+
+        let mut catalog = BTreeMap::new();
+        let mut catalog_damaged = vec![];
+        self.walk_tree(disk_manager, metadata.catalog_offset, &mut catalog, &mut catalog_damaged, report);
+        if !catalog_damaged.is_empty() {
+            // the buckets the damaged part of the catalog named are unknown, their keys go to the main tree
+            self.damaged.push(everything);
+        }
+        for (key, (value, _)) in catalog {
+            let (Ok(name), Ok(root_offset)) = (bucket::decode_name(&key), bucket::decode_root(&value)) else {
+                continue;
+            };
+            let mut entries = BTreeMap::new();
+            let mut damaged = vec![];
+            self.walk_tree(disk_manager, root_offset, &mut entries, &mut damaged, report);
+            let owner = Some(name);
+            // bucket ranges go before the main tree's, so their keys aren't taken by a catalog fallback
+            for range in damaged {
+                self.damaged.insert(0, (owner.clone(), range));
+            }
+            self.trees.insert(owner, entries);
+        }
+
+        let mut entries = BTreeMap::new();
+        let mut damaged = vec![];
+        self.walk_tree(disk_manager, metadata.root_offset, &mut entries, &mut damaged, report);
+        self.damaged.extend(damaged.into_iter().map(|range| (None, range)));
+        self.trees.insert(None, entries);
+    }
+
+    /// Take the entries of every leaf reachable from root_offset, and the key range of every
+    /// subtree that didn't decode or broke a leaf invariant, a root offset of 0 is an empty tree
+    fn walk_tree(
+        &mut self,
+        disk_manager: &mut DiskManager,
+        root_offset: u64,
+        entries: &mut Entries,
+        damaged: &mut Vec<KeyRange>,
+        report: &mut RecoveryReport,
+    ) {
+        if root_offset == 0 {
+            return;
+        }
+        let mut stack = vec![(root_offset, Bound::Unbounded, Bound::Unbounded)];
+        while let Some((offset, start, end)) = stack.pop() {
+            let node = match is_page_offset(disk_manager, offset) && !self.reached.contains(&offset) {
+                true => disk_manager.load_node_from_disk(offset).ok(),
+                false => None,
+            };
+            let Some(node) = node.filter(|node| is_intact(disk_manager, node) && node.keys.iter().all(|key| in_range(key, &start, &end))) else {
+                damaged.push((start, end));
+                continue;
+            };
+            self.reached.insert(offset);
+            if node.children.is_empty() {
+                if !node.keys.is_empty() {
+                    report.leaves_recovered += 1;
+                }
+                for (pos, (key, value)) in node.keys.iter().zip(&node.values).enumerate() {
+                    if !node.is_expired(pos, self.now) {
+                        entries.insert(key.clone(), (value.clone(), node.expiry(pos)));
+                    }
+                }
+                continue;
+            }
+            for (i, &child_offset) in node.children.iter().enumerate() {
+                let child_start = match i {
+                    0 => start.clone(),
+                    i => Bound::Included(node.keys[i - 1].clone()),
+                };
+                let child_end = match node.keys.get(i) {
+                    Some(key) => Bound::Excluded(key.clone()),
+                    None => end.clone(),
+                };
+                stack.push((child_offset, child_start, child_end));
+            }
+        }
+    }
+
+    /// The tree a key left on an unreached page belongs to, if it falls in a damaged range
+    fn damaged_owner(&self, key: &[u8]) -> Option<Option<String>> {
+        self.damaged.iter().find(|(_, (start, end))| in_range(key, start, end)).map(|(owner, _)| owner.clone())
+    }
+}
+
+/// Add every readable page of the tree at root_offset to pages
+fn walk(disk_manager: &mut DiskManager, root_offset: u64, pages: &mut Vec<(u64, Node)>) {
+    let mut seen = HashSet::new();
+    let mut stack = vec![root_offset];
+    while let Some(offset) = stack.pop() {
+        if !is_page_offset(disk_manager, offset) || !seen.insert(offset) {
+            continue;
+        }
+        let Ok(node) = disk_manager.load_node_from_disk(offset) else {
+            continue;
+        };
+        stack.extend(&node.children);
+        pages.push((offset, node));
+    }
+}
+
+fn is_page_offset(disk_manager: &DiskManager, offset: u64) -> bool {
+    let config = &disk_manager.config;
+    offset >= config.first_page_offset && (offset - config.first_page_offset).is_multiple_of(config.page_size as u64)
+}
+
+/// The page at offset if it is a leaf that looks intact
+fn read_leaf(disk_manager: &mut DiskManager, offset: u64) -> Option<Node> {
+    let node = disk_manager.load_node_from_disk(offset).ok()?;
+    (node.children.is_empty() && is_intact(disk_manager, &node)).then_some(node)
+}
+
+/// Whether a node that decoded keeps its keys sorted and its entries within the configured limits
+/// pages carry no checksum, so a node damaged in a way that still passes is taken as is
+fn is_intact(disk_manager: &DiskManager, node: &Node) -> bool {
+    let config = &disk_manager.config;
+    node.keys.windows(2).all(|pair| pair[0] < pair[1])
+        && node.keys.iter().all(|key| key.len() <= config.max_key_size as usize)
+        && node.values.iter().all(|value| value.len() <= config.max_val_size as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::{check_with_config, Retention};
+    use crate::storage::testing::{key, small_page_config};
+    use tempfile::{NamedTempFile, TempDir};
+
+    fn leaf(keys: &[&[u8]], value: &[u8]) -> Node {
+        Node {
+            keys: keys.iter().map(|key| key.to_vec()).collect(),
+            values: keys.iter().map(|_| value.to_vec()).collect(),
+            children: vec![],
+            expiries: vec![],
+        }
+    }
+
+    /// Overwrite the page at offset with bytes that don't decode
+    fn smash(path: &std::path::Path, offset: u64) {
+        let mut file = std::fs::read(path).unwrap();
+        file[offset as usize..offset as usize + 128].fill(0xff);
+        std::fs::write(path, file).unwrap();
+    }
+
+    #[test]
+    fn test_recover_without_metadata() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        for i in 0..200 {
+            btree.insert(key(i), key(i)).unwrap();
+        }
+        let internal = btree.root_offset;
+        drop(btree);
+        smash(tmp.path(), 0);
+        smash(tmp.path(), internal);
+
+        let dir = TempDir::new().unwrap();
+        let dst = dir.path().join("recovered.db");
+        let dst = dst.to_str().unwrap();
+        let report = recover_with_config(tmp.path().to_str().unwrap(), dst, small_page_config()).unwrap();
+        assert_eq!(report.keys_recovered, 200);
+        assert!(report.pages_skipped >= 2);
+
+        let mut recovered = BTree::new(dst, Some(small_page_config())).unwrap();
+        let entries: Vec<_> = recovered.range(..).map(|r| r.unwrap()).collect();
+        assert_eq!(entries, (0..200).map(|i| (key(i), key(i))).collect::<Vec<_>>());
+        assert!(check_with_config(dst, small_page_config()).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_recover_fills_damaged_ranges_from_higher_offsets() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        btree.create_bucket("users").unwrap();
+        btree.open_bucket("users").unwrap().insert(b"alice".to_vec(), b"1".to_vec()).unwrap();
+        btree.insert(b"a".to_vec(), b"main".to_vec()).unwrap();
+        // two stray leaves past the end of the trees, the second one is newer
+        let end = btree.disk_manager.page_offsets().unwrap().last().unwrap() + 128;
+        btree.disk_manager.append_node_to_disk(end, &leaf(&[b"b", b"c"], b"old"));
+        btree.disk_manager.append_node_to_disk(end + 128, &leaf(&[b"c"], b"new"));
+        let root_offset = btree.root_offset;
+        drop(btree);
+
+        let dir = TempDir::new().unwrap();
+        let dst = dir.path().join("intact.db");
+        let report = recover_with_config(tmp.path().to_str().unwrap(), dst.to_str().unwrap(), small_page_config()).unwrap();
+        // nothing is damaged, so the stray leaves are left out
+        assert_eq!((report.keys_recovered, report.duplicates), (2, 0));
+
+        // with the root of the main tree gone, every main tree key is taken from the leaves left
+        smash(tmp.path(), root_offset);
+        let dst = dir.path().join("recovered.db");
+        let dst = dst.to_str().unwrap();
+        let report = recover_with_config(tmp.path().to_str().unwrap(), dst, small_page_config()).unwrap();
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(report.keys_recovered, 3);
+
+        let mut recovered = BTree::new(dst, Some(small_page_config())).unwrap();
+        let entries: Vec<_> = recovered.range(..).map(|r| r.unwrap()).collect();
+        assert_eq!(entries, vec![(b"b".to_vec(), b"old".to_vec()), (b"c".to_vec(), b"new".to_vec())]);
+        assert_eq!(recovered.list_buckets().unwrap(), vec!["users"]);
+        assert_eq!(recovered.open_bucket("users").unwrap().get(b"alice").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_recover_keeps_deleted_keys_deleted() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        for i in 0..200 {
+            btree.insert(key(i), key(i)).unwrap();
+        }
+        // the leaf holding key 150 goes to the end of the file while nothing can be reused, then
+        // the delete writes its new copy into one of the freed pages below it and leaves the old one behind
+        btree.set_retention(Retention::KeepLast(1000));
+        btree.delete_range(key(0)..key(40)).unwrap();
+        btree.insert(key(150), b"old".to_vec()).unwrap();
+        btree.set_retention(Retention::KeepLast(1));
+        btree.insert(key(10), vec![]).unwrap();
+        btree.delete(&key(150)).unwrap();
+        let mut leaf_offset = btree.root_offset;
+        let mut node = btree.root.clone();
+        while !node.children.is_empty() {
+            leaf_offset = node.children[node.child_index(&key(150))];
+            node = btree.disk_manager.load_node_from_disk(leaf_offset).unwrap();
+        }
+        let stale = btree.disk_manager.page_offsets().unwrap().into_iter().filter(|&offset| offset > leaf_offset).any(|offset| {
+            read_leaf(&mut btree.disk_manager, offset).is_some_and(|leaf| leaf.find_key(&key(150)).is_ok())
+        });
+        assert!(stale);
+        drop(btree);
+
+        let dir = TempDir::new().unwrap();
+        let dst = dir.path().join("recovered.db");
+        let dst = dst.to_str().unwrap();
+        recover_with_config(tmp.path().to_str().unwrap(), dst, small_page_config()).unwrap();
+        let mut recovered = BTree::new(dst, Some(small_page_config())).unwrap();
+        assert_eq!(recovered.get(&key(150)).unwrap(), None);
+        assert_eq!(recovered.get(&key(151)).unwrap(), Some(key(151)));
+        assert_eq!(recovered.range(..).count(), 160);
+    }
+
+    #[test]
+    fn test_recover_keeps_expiry_of_bucket_keys() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        btree.create_bucket("sessions").unwrap();
+        btree.open_bucket("sessions").unwrap().insert(b"forever".to_vec(), b"1".to_vec()).unwrap();
+        // buckets have no ttl api yet, so the entry is written the way the transaction would
+        let expiry = now_millis() + 3_600_000;
+        let root_offset = btree.bucket_root("sessions").unwrap().unwrap();
+        let (root_offset, _) = btree.put_in_tree(root_offset, b"token", b"2".to_vec(), expiry, PutMode::Upsert).unwrap();
+        let catalog_offset = btree.insert_into_tree(btree.catalog_offset, b"sessions", &bucket::encode_root(root_offset.unwrap())).unwrap().0;
+        btree.commit(btree.root_offset, catalog_offset).unwrap();
+        drop(btree);
+
+        let dir = TempDir::new().unwrap();
+        let dst = dir.path().join("recovered.db");
+        let dst = dst.to_str().unwrap();
+        recover_with_config(tmp.path().to_str().unwrap(), dst, small_page_config()).unwrap();
+
+        let mut recovered = BTree::new(dst, Some(small_page_config())).unwrap();
+        let root_offset = recovered.bucket_root("sessions").unwrap().unwrap();
+        let leaf = recovered.load_root(root_offset).unwrap();
+        assert_eq!(leaf.keys, vec![b"forever".to_vec(), b"token".to_vec()]);
+        assert_eq!((leaf.expiry(0), leaf.expiry(1)), (0, expiry));
+    }
+
+    #[test]
+    fn test_recover_refuses_existing_destination() {
+        let src = NamedTempFile::new().unwrap();
+        BTree::new(src.path().to_str().unwrap(), None).unwrap();
+        let dst = NamedTempFile::new().unwrap();
+        let err = recover(src.path().to_str().unwrap(), dst.path().to_str().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        // an empty source is left alone rather than given a metadata page
+        let empty = NamedTempFile::new().unwrap();
+        let dir = TempDir::new().unwrap();
+        let dst = dir.path().join("recovered.db");
+        let err = recover(empty.path().to_str().unwrap(), dst.to_str().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(std::fs::metadata(empty.path()).unwrap().len(), 0);
+        assert!(!dst.exists());
+    }
+}
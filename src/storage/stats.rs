@@ -0,0 +1,119 @@
+use crate::storage::btree::BTree;
+use std::io::Result;
+
+/// Shape and space usage of the main tree, and how much of the file is dead
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeStats {
+    /// Number of levels, 1 when the root is a leaf
+    pub height: usize,
+    pub internal_pages: usize,
+    pub leaf_pages: usize,
+    /// Entries stored on the leaves, expired ones that haven't been swept included
+    pub keys: usize,
+    /// One entry per level, the root first
+    pub levels: Vec<LevelStats>,
+    pub page_size: u16,
+    pub file_size: u64,
+    /// Page slots in the file
+    pub total_pages: usize,
+    /// Page slots the current commit doesn't reach from any of its trees, whether they are free to
+    /// reuse or still held by retained commits. Compacting gives them back to the file system
+    pub dead_pages: usize,
+}
+
+/// How full the pages of one level are, as the bytes encode_node uses over page_size
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelStats {
+    pub pages: usize,
+    pub min_fill: f64,
+    pub avg_fill: f64,
+    pub max_fill: f64,
+}
+
+/// Walk the main tree level by level
+pub(crate) fn collect(btree: &mut BTree) -> Result<TreeStats> {
+    let page_size = btree.storage_config.page_size;
+    let mut stats = TreeStats {
+        height: 0,
+        internal_pages: 0,
+        leaf_pages: 0,
+        keys: 0,
+        levels: vec![],
+        page_size,
+        file_size: btree.disk_manager.file_size()?,
+        total_pages: btree.disk_manager.page_offsets()?.len(),
+        dead_pages: 0,
+    };
+    stats.dead_pages = stats.total_pages - btree.reachable_pages()?.len();
+
+    let mut level = vec![btree.root_offset];
+    while !level.is_empty() {
+        let mut next = vec![];
+        let mut fills = vec![];
+        for offset in level {
+            let node = btree.load_root(offset)?;
+            fills.push(node.encoded_size() as f64 / page_size as f64);
+            if node.children.is_empty() {
+                stats.leaf_pages += 1;
+                stats.keys += node.keys.len();
+            } else {
+                stats.internal_pages += 1;
+                next.extend(&node.children);
+            }
+        }
+        stats.levels.push(LevelStats {
+            pages: fills.len(),
+            min_fill: fills.iter().copied().fold(f64::INFINITY, f64::min),
+            avg_fill: fills.iter().sum::<f64>() / fills.len() as f64,
+            max_fill: fills.iter().copied().fold(0.0, f64::max),
+        });
+        level = next;
+    }
+    stats.height = stats.levels.len();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::testing::{key, small_page_config};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_stats_of_empty_tree() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        let stats = btree.stats().unwrap();
+        assert_eq!((stats.height, stats.internal_pages, stats.leaf_pages, stats.keys), (1, 0, 1, 0));
+        assert_eq!(stats.levels[0].pages, 1);
+        assert_eq!(stats.file_size, 128 * (stats.total_pages as u64 + 1));
+    }
+
+    #[test]
+    fn test_stats_count_levels_and_dead_pages() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut btree = BTree::new(tmp.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        for i in 0..300 {
+            btree.insert(key(i), key(i)).unwrap();
+        }
+        let stats = btree.stats().unwrap();
+        assert_eq!(stats.keys, 300);
+        assert_eq!(stats.height, btree.tree_height(btree.root_offset).unwrap() + 1);
+        assert_eq!(stats.levels.iter().map(|level| level.pages).sum::<usize>(), stats.internal_pages + stats.leaf_pages);
+        assert_eq!(stats.levels.last().unwrap().pages, stats.leaf_pages);
+        for level in &stats.levels {
+            assert!(0.0 < level.min_fill && level.min_fill <= level.avg_fill && level.avg_fill <= level.max_fill && level.max_fill <= 1.0);
+        }
+        // copy-on-write leaves the replaced pages behind
+        assert!(stats.dead_pages > 0);
+        assert_eq!(stats.dead_pages, btree.disk_manager.free_page_count() + btree.disk_manager.retired_page_count());
+
+        // a bulk loaded copy packs its leaves fuller
+        let copy = NamedTempFile::new().unwrap();
+        let mut packed = BTree::new(copy.path().to_str().unwrap(), Some(small_page_config())).unwrap();
+        packed.bulk_load((0..300).map(|i| Ok((key(i), key(i))))).unwrap();
+        let packed_stats = packed.stats().unwrap();
+        assert!(packed_stats.leaf_pages < stats.leaf_pages);
+        assert!(packed_stats.levels.last().unwrap().avg_fill > stats.levels.last().unwrap().avg_fill);
+    }
+}
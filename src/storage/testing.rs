@@ -0,0 +1,17 @@
+use crate::storage::StorageConfig;
+
+/// Pages small enough that a few dozen keys already make a tree several levels deep
+pub(crate) fn small_page_config() -> StorageConfig {
+    StorageConfig {
+        page_size: 128,
+        max_key_size: 32,
+        max_val_size: 32,
+        metadata_offset: 0,
+        first_page_offset: 128,
+    }
+}
+
+/// Keys that sort in the same order as i
+pub(crate) fn key(i: usize) -> Vec<u8> {
+    format!("key{:04}", i).into_bytes()
+}
@@ -0,0 +1,158 @@
+use crate::storage::btree::BTree;
+use crate::storage::bucket;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+
+/// A batch of writes across buckets that becomes visible with a single metadata write
+/// Dropping a transaction without committing discards its writes
+pub struct Transaction<'a> {
+    btree: &'a mut BTree,
+    // bucket roots changed by this transaction, None for dropped buckets
+    // flushed into the catalog on commit
+    buckets: BTreeMap<String, Option<u64>>,
+    committed: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(btree: &'a mut BTree) -> Self {
+        Self {
+            btree,
+            buckets: BTreeMap::new(),
+            committed: false,
+        }
+    }
+
+    /// Root offset of a bucket as seen by this transaction
+    fn bucket_root(&mut self, name: &str) -> Result<Option<u64>> {
+        match self.buckets.get(name) {
+            Some(root) => Ok(*root),
+            None => self.btree.bucket_root(name),
+        }
+    }
+
+    pub fn create_bucket(&mut self, name: &str) -> Result<()> {
+        if self.bucket_root(name)?.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("bucket {} already exists", name)));
+        }
+        // an empty bucket has no pages until the first insert
+        self.buckets.insert(name.to_string(), Some(0));
+        Ok(())
+    }
+
+    pub fn drop_bucket(&mut self, name: &str) -> Result<()> {
+        let root_offset = self.bucket_root(name)?.ok_or_else(|| bucket::not_found(name))?;
+        if root_offset != 0 {
+            let height = self.btree.tree_height(root_offset)?;
+            self.btree.free_subtree(root_offset, height)?;
+        }
+        self.buckets.insert(name.to_string(), None);
+        Ok(())
+    }
+
+    pub fn get(&mut self, bucket: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let root_offset = self.bucket_root(bucket)?.ok_or_else(|| bucket::not_found(bucket))?;
+        self.btree.get_from_tree(root_offset, key)
+    }
+
+    pub fn insert(&mut self, bucket: &str, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let root_offset = self.bucket_root(bucket)?.ok_or_else(|| bucket::not_found(bucket))?;
+        let (new_root_offset, previous) = self.btree.insert_into_tree(root_offset, &key, &value)?;
+        self.buckets.insert(bucket.to_string(), Some(new_root_offset));
+        Ok(previous)
+    }
+
+    /// Delete key from a bucket, returns the value it held
+    pub fn delete(&mut self, bucket: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let root_offset = self.bucket_root(bucket)?.ok_or_else(|| bucket::not_found(bucket))?;
+        match self.btree.delete_from_tree(root_offset, key)? {
+            Some((new_root_offset, removed)) => {
+                self.buckets.insert(bucket.to_string(), Some(new_root_offset));
+                Ok(Some(removed))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Write the new bucket roots into the catalog and switch every root over at once
+    pub fn commit(mut self) -> Result<()> {
+        let mut catalog_offset = self.btree.catalog_offset;
+        for (name, root_offset) in std::mem::take(&mut self.buckets) {
+            match root_offset {
+                Some(root_offset) => {
+                    let value = bucket::encode_root(root_offset);
+                    catalog_offset = self.btree.insert_into_tree(catalog_offset, name.as_bytes(), &value)?.0;
+                }
+                None => {
+                    if let Some((new_catalog_offset, _)) = self.btree.delete_from_tree(catalog_offset, name.as_bytes())? {
+                        catalog_offset = new_catalog_offset;
+                    }
+                }
+            }
+        }
+
+        let root_offset = self.btree.root_offset;
+        self.btree.commit(root_offset, catalog_offset)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            // give back the pages this transaction wrote
+            self.btree.disk_manager.rollback_pages();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn get_temp_btree() -> BTree {
+        let tmp = NamedTempFile::new().unwrap();
+        BTree::new(tmp.path().to_str().unwrap(), None).unwrap()
+    }
+
+    #[test]
+    fn test_commit_spans_buckets() {
+        let mut btree = get_temp_btree();
+        btree.create_bucket("accounts").unwrap();
+        btree.create_bucket("ledger").unwrap();
+
+        let mut tx = btree.begin();
+        tx.insert("accounts", b"alice".to_vec(), b"90".to_vec()).unwrap();
+        tx.insert("ledger", b"0001".to_vec(), b"alice -10".to_vec()).unwrap();
+        // reads inside the transaction see its own writes
+        assert_eq!(tx.get("accounts", b"alice").unwrap(), Some(b"90".to_vec()));
+        tx.commit().unwrap();
+
+        assert_eq!(btree.open_bucket("accounts").unwrap().get(b"alice").unwrap(), Some(b"90".to_vec()));
+        assert_eq!(btree.open_bucket("ledger").unwrap().get(b"0001").unwrap(), Some(b"alice -10".to_vec()));
+    }
+
+    #[test]
+    fn test_uncommitted_transaction_is_discarded() {
+        let mut btree = get_temp_btree();
+        btree.create_bucket("accounts").unwrap();
+
+        let mut tx = btree.begin();
+        tx.create_bucket("ledger").unwrap();
+        tx.insert("accounts", b"alice".to_vec(), b"90".to_vec()).unwrap();
+        tx.insert("ledger", b"0001".to_vec(), b"alice -10".to_vec()).unwrap();
+        drop(tx);
+
+        assert_eq!(btree.list_buckets().unwrap(), vec!["accounts".to_string()]);
+        assert_eq!(btree.open_bucket("accounts").unwrap().get(b"alice").unwrap(), None);
+    }
+
+    #[test]
+    fn test_missing_bucket() {
+        let mut btree = get_temp_btree();
+        let mut tx = btree.begin();
+        let err = tx.insert("nope", b"a".to_vec(), b"1".to_vec()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
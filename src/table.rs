@@ -0,0 +1,348 @@
+use crate::storage::{BTree, RangeIter, StorageConfig};
+use crate::tuple::{self, Element};
+use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+
+/// Types that can be used as table keys
+/// Encoded keys must sort byte by byte in the same order as the values they encode
+pub trait KeyCodec: Sized {
+    fn encode_key(&self) -> Vec<u8>;
+    fn decode_key(bytes: &[u8]) -> Result<Self>;
+}
+
+/// Types that can be stored as table values
+pub trait ValueCodec: Sized {
+    fn encode_value(&self) -> Vec<u8>;
+    fn decode_value(bytes: &[u8]) -> Result<Self>;
+}
+
+/// Typed view over a BTree, keys and values are encoded on the way in and decoded on the way out
+pub struct Table<K, V> {
+    btree: BTree,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K: KeyCodec, V: ValueCodec> Table<K, V> {
+    pub fn new(btree: BTree) -> Self {
+        Self {
+            btree,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Open or create the tree at path and wrap it
+    pub fn open(path: &str, storage_config: Option<StorageConfig>) -> Result<Self> {
+        Ok(Self::new(BTree::new(path, storage_config)?))
+    }
+
+    pub fn get(&mut self, key: &K) -> Result<Option<V>> {
+        match self.btree.get(&key.encode_key())? {
+            Some(bytes) => Ok(Some(V::decode_value(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Insert or overwrite key, returns the value it held before
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        match self.btree.insert(key.encode_key(), value.encode_value())? {
+            Some(bytes) => Ok(Some(V::decode_value(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Delete key, returns the value it held
+    pub fn delete(&mut self, key: &K) -> Result<Option<V>> {
+        match self.btree.delete(&key.encode_key())? {
+            Some(bytes) => Ok(Some(V::decode_value(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Iterate over the entries within range in key order
+    pub fn range<R: RangeBounds<K>>(&mut self, range: R) -> TableIter<'_, K, V> {
+        let start = range.start_bound().map(|k| k.encode_key());
+        let end = range.end_bound().map(|k| k.encode_key());
+        TableIter {
+            inner: self.btree.range((start, end)),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Unwrap the underlying untyped tree
+    pub fn into_inner(self) -> BTree {
+        self.btree
+    }
+}
+
+/// Iterator returned by Table::range
+pub struct TableIter<'a, K, V> {
+    inner: RangeIter<'a>,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K: KeyCodec, V: ValueCodec> Iterator for TableIter<'_, K, V> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.inner.next()?;
+        Some(entry.and_then(|(key, value)| Ok((K::decode_key(&key)?, V::decode_value(&value)?))))
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn fixed<const N: usize>(bytes: &[u8]) -> Result<[u8; N]> {
+    bytes.try_into().map_err(|_| invalid("encoded value has the wrong length"))
+}
+
+macro_rules! impl_unsigned {
+    ($($t:ty),*) => {
+        $(
+            impl KeyCodec for $t {
+                // big endian so byte order matches numeric order
+                fn encode_key(&self) -> Vec<u8> {
+                    self.to_be_bytes().to_vec()
+                }
+
+                fn decode_key(bytes: &[u8]) -> Result<Self> {
+                    Ok(<$t>::from_be_bytes(fixed(bytes)?))
+                }
+            }
+
+            impl ValueCodec for $t {
+                fn encode_value(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn decode_value(bytes: &[u8]) -> Result<Self> {
+                    Ok(<$t>::from_le_bytes(fixed(bytes)?))
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_signed {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl KeyCodec for $t {
+                // flip the sign bit so negative numbers sort before positive ones
+                fn encode_key(&self) -> Vec<u8> {
+                    ((*self as $u) ^ (1 << (<$u>::BITS - 1))).to_be_bytes().to_vec()
+                }
+
+                fn decode_key(bytes: &[u8]) -> Result<Self> {
+                    Ok((<$u>::from_be_bytes(fixed(bytes)?) ^ (1 << (<$u>::BITS - 1))) as $t)
+                }
+            }
+
+            impl ValueCodec for $t {
+                fn encode_value(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn decode_value(bytes: &[u8]) -> Result<Self> {
+                    Ok(<$t>::from_le_bytes(fixed(bytes)?))
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_float {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl KeyCodec for $t {
+                // positive floats get the sign bit set, negative floats get every bit flipped
+                fn encode_key(&self) -> Vec<u8> {
+                    let bits = self.to_bits();
+                    let bits = if bits >> (<$u>::BITS - 1) == 1 { !bits } else { bits ^ (1 << (<$u>::BITS - 1)) };
+                    bits.to_be_bytes().to_vec()
+                }
+
+                fn decode_key(bytes: &[u8]) -> Result<Self> {
+                    let bits = <$u>::from_be_bytes(fixed(bytes)?);
+                    let bits = if bits >> (<$u>::BITS - 1) == 1 { bits ^ (1 << (<$u>::BITS - 1)) } else { !bits };
+                    Ok(<$t>::from_bits(bits))
+                }
+            }
+
+            impl ValueCodec for $t {
+                fn encode_value(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn decode_value(bytes: &[u8]) -> Result<Self> {
+                    Ok(<$t>::from_le_bytes(fixed(bytes)?))
+                }
+            }
+        )*
+    };
+}
+
+impl_unsigned!(u8, u16, u32, u64);
+impl_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64);
+impl_float!(f32 => u32, f64 => u64);
+
+impl KeyCodec for bool {
+    fn encode_key(&self) -> Vec<u8> {
+        vec![*self as u8]
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self> {
+        match bytes {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            _ => Err(invalid("encoded bool must be a single 0 or 1 byte")),
+        }
+    }
+}
+
+impl ValueCodec for bool {
+    fn encode_value(&self) -> Vec<u8> {
+        self.encode_key()
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Self> {
+        Self::decode_key(bytes)
+    }
+}
+
+impl KeyCodec for String {
+    fn encode_key(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self> {
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid("encoded string is not valid utf8"))
+    }
+}
+
+impl ValueCodec for String {
+    fn encode_value(&self) -> Vec<u8> {
+        self.encode_key()
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Self> {
+        Self::decode_key(bytes)
+    }
+}
+
+impl KeyCodec for Vec<u8> {
+    fn encode_key(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl ValueCodec for Vec<u8> {
+    fn encode_value(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Self> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl<const N: usize> KeyCodec for [u8; N] {
+    fn encode_key(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self> {
+        fixed(bytes)
+    }
+}
+
+impl<const N: usize> ValueCodec for [u8; N] {
+    fn encode_value(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Self> {
+        fixed(bytes)
+    }
+}
+
+/// Composite keys use the tuple encoding
+impl KeyCodec for Vec<Element> {
+    fn encode_key(&self) -> Vec<u8> {
+        tuple::pack(self)
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self> {
+        tuple::unpack(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn get_temp_table<K: KeyCodec, V: ValueCodec>() -> Table<K, V> {
+        let tmp = NamedTempFile::new().unwrap();
+        Table::open(tmp.path().to_str().unwrap(), None).unwrap()
+    }
+
+    fn roundtrip_key<K: KeyCodec + PartialEq + std::fmt::Debug>(key: K) {
+        assert_eq!(K::decode_key(&key.encode_key()).unwrap(), key);
+    }
+
+    #[test]
+    fn test_key_codecs_roundtrip() {
+        roundtrip_key(42u32);
+        roundtrip_key(-42i16);
+        roundtrip_key(-1.25f64);
+        roundtrip_key(true);
+        roundtrip_key("hello".to_string());
+        roundtrip_key(vec![0u8, 1, 2]);
+        roundtrip_key([9u8; 4]);
+        roundtrip_key(vec![Element::from(1u64), Element::from("a")]);
+        assert!(u32::decode_key(&[1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_signed_and_float_keys_sort() {
+        let ints = [i32::MIN, -5, -1, 0, 1, 5, i32::MAX];
+        for pair in ints.windows(2) {
+            assert!(pair[0].encode_key() < pair[1].encode_key());
+        }
+
+        let floats = [f64::NEG_INFINITY, -3.5, -0.5, 0.0, 0.5, 3.5, f64::INFINITY];
+        for pair in floats.windows(2) {
+            assert!(pair[0].encode_key() < pair[1].encode_key());
+        }
+    }
+
+    #[test]
+    fn test_table_get_insert_delete() {
+        let mut table: Table<u64, String> = get_temp_table();
+        assert_eq!(table.insert(1, "one".to_string()).unwrap(), None);
+        table.insert(2, "two".to_string()).unwrap();
+        assert_eq!(table.insert(2, "deux".to_string()).unwrap(), Some("two".to_string()));
+
+        assert_eq!(table.get(&1).unwrap(), Some("one".to_string()));
+        assert_eq!(table.delete(&1).unwrap(), Some("one".to_string()));
+        assert_eq!(table.get(&1).unwrap(), None);
+        assert_eq!(table.get(&2).unwrap(), Some("deux".to_string()));
+    }
+
+    #[test]
+    fn test_table_range() {
+        let mut table: Table<i64, u32> = get_temp_table();
+        for i in -50..50 {
+            table.insert(i, (i + 50) as u32).unwrap();
+        }
+
+        let entries: Vec<(i64, u32)> = table.range(-3..3).map(|r| r.unwrap()).collect();
+        assert_eq!(entries, (-3..3).map(|i| (i, (i + 50) as u32)).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,214 @@
+use std::fmt::Write;
+use std::io::{Error, ErrorKind, Result};
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// How binary keys and values are written as text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// UTF-8 as is, with backslash escapes for everything else, see `escape`
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "utf8" => Ok(Format::Utf8),
+            "hex" => Ok(Format::Hex),
+            "base64" => Ok(Format::Base64),
+            _ => Err(invalid(format!("unknown format {}, expected utf8, hex or base64", name))),
+        }
+    }
+
+    pub fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            Format::Utf8 => escape(bytes),
+            Format::Hex => to_hex(bytes),
+            Format::Base64 => to_base64(bytes),
+        }
+    }
+
+    pub fn decode(&self, text: &str) -> Result<Vec<u8>> {
+        match self {
+            Format::Utf8 => unescape(text),
+            Format::Hex => from_hex(text),
+            Format::Base64 => from_base64(text),
+        }
+    }
+}
+
+/// Printable UTF-8 is kept, backslashes are doubled and any other byte becomes \xNN
+/// The result never contains a tab or a newline, so it can be used as a field of a line based format
+pub fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    for byte in c.encode_utf8(&mut buf).bytes() {
+                        escaped.push_str(&format!("\\x{:02x}", byte));
+                    }
+                }
+                c => escaped.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    escaped
+}
+
+/// Reverse of `escape`
+pub fn unescape(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match rest {
+            [b'\\', tail @ ..] => {
+                bytes.push(b'\\');
+                rest = tail;
+            }
+            [b'x', high, low, tail @ ..] => {
+                bytes.push(hex_value(*high)? << 4 | hex_value(*low)?);
+                rest = tail;
+            }
+            _ => return Err(invalid(format!("bad escape in {}, expected \\\\ or \\xNN", text))),
+        }
+    }
+    Ok(bytes)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(text: &str) -> Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return Err(invalid(format!("{} has an odd number of hex digits", text)));
+    }
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| Ok(hex_value(pair[0])? << 4 | hex_value(pair[1])?))
+        .collect()
+}
+
+/// Standard base64 with padding
+pub fn to_base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| group | (byte as u32) << (16 - 8 * i));
+        // 3 bytes fill 4 characters, a shorter chunk fills one more character than it has bytes
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+pub fn from_base64(text: &str) -> Result<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return Err(invalid("base64 length is not a multiple of 4".to_string()));
+    }
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    for (n, chunk) in text.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && n + 1 != text.len() / 4) {
+            return Err(invalid("misplaced base64 padding".to_string()));
+        }
+        let mut group = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let value = BASE64_ALPHABET
+                .iter()
+                .position(|&a| a == c)
+                .ok_or_else(|| invalid(format!("{} is not a base64 character", c as char)))?;
+            group = group << 6 | value as u32;
+        }
+        group <<= 6 * padding;
+        bytes.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(bytes)
+}
+
+/// Quote s as a JSON string
+pub fn json_quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn hex_value(digit: u8) -> Result<u8> {
+    (digit as char)
+        .to_digit(16)
+        .map(|value| value as u8)
+        .ok_or_else(|| invalid(format!("{} is not a hex digit", digit as char)))
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_escape_roundtrip() {
+        let bytes = b"tab\there \\ caf\xc3\xa9 \xff\x00".to_vec();
+        let escaped = escape(&bytes);
+        assert_eq!(escaped, "tab\\x09here \\\\ café \\xff\\x00");
+        assert_eq!(unescape(&escaped).unwrap(), bytes);
+        assert!(unescape("bad\\q").is_err());
+        assert!(unescape("short\\x4").is_err());
+    }
+
+    #[test]
+    fn test_hex_roundtrip() {
+        assert_eq!(to_hex(b"\x00\xabz"), "00ab7a");
+        assert_eq!(from_hex("00AB7a").unwrap(), b"\x00\xabz");
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_base64_roundtrip() {
+        let cases: [(&[u8], &str); 5] = [
+            (b"", ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob\xff", "Zm9vYv8="),
+        ];
+        for (bytes, encoded) in cases {
+            assert_eq!(to_base64(bytes), encoded);
+            assert_eq!(from_base64(encoded).unwrap(), bytes);
+        }
+        assert!(from_base64("Zg=").is_err());
+        assert!(from_base64("Zg==Zg==").is_err());
+        assert!(from_base64("Z!==").is_err());
+    }
+}
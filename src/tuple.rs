@@ -0,0 +1,336 @@
+use std::io::{Error, ErrorKind, Result};
+
+// type codes, the order of these decides how different types sort against each other
+const NESTED_END: u8 = 0x00;
+const BYTES_CODE: u8 = 0x01;
+const STRING_CODE: u8 = 0x02;
+const NESTED_CODE: u8 = 0x03;
+const INT_CODE: u8 = 0x04;
+const UINT_CODE: u8 = 0x05;
+const FLOAT_CODE: u8 = 0x06;
+const FALSE_CODE: u8 = 0x07;
+const TRUE_CODE: u8 = 0x08;
+
+// 0x00 inside bytes/strings is escaped as 0x00 0xFF so it can't be confused with the terminator
+const ESCAPE: u8 = 0xFF;
+
+/// A single typed element of a composite key
+#[derive(Debug, Clone, PartialEq)]
+pub enum Element {
+    Bytes(Vec<u8>),
+    String(String),
+    Tuple(Vec<Element>),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Bool(bool),
+}
+
+/// Encode a tuple into bytes
+/// Comparing two encoded tuples byte by byte gives the same order as comparing
+/// the tuples element by element, and the encoding of a tuple is a prefix of the
+/// encoding of any tuple that extends it
+pub fn pack(elements: &[Element]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for element in elements {
+        encode_element(&mut buf, element);
+    }
+    buf
+}
+
+/// Decode bytes produced by `pack` back into a tuple
+pub fn unpack(buf: &[u8]) -> Result<Vec<Element>> {
+    let mut cursor = 0;
+    let mut elements = Vec::new();
+    while cursor < buf.len() {
+        elements.push(decode_element(buf, &mut cursor)?);
+    }
+    Ok(elements)
+}
+
+/// Key range covering every tuple that starts with `prefix`
+/// start is inclusive and end is exclusive, so the pair can be used for a prefix scan
+pub fn prefix_range(prefix: &[Element]) -> (Vec<u8>, Vec<u8>) {
+    let start = pack(prefix);
+    // every element starts with a type code below 0xFF
+    let mut end = start.clone();
+    end.push(0xFF);
+    (start, end)
+}
+
+fn encode_element(buf: &mut Vec<u8>, element: &Element) {
+    match element {
+        Element::Bytes(bytes) => {
+            buf.push(BYTES_CODE);
+            encode_escaped(buf, bytes);
+        }
+        Element::String(s) => {
+            buf.push(STRING_CODE);
+            encode_escaped(buf, s.as_bytes());
+        }
+        Element::Tuple(elements) => {
+            buf.push(NESTED_CODE);
+            for element in elements {
+                encode_element(buf, element);
+            }
+            buf.push(NESTED_END);
+        }
+        Element::Int(i) => {
+            // flipping the sign bit puts negative numbers before positive ones
+            buf.push(INT_CODE);
+            buf.extend_from_slice(&((*i as u64) ^ (1 << 63)).to_be_bytes());
+        }
+        Element::UInt(u) => {
+            buf.push(UINT_CODE);
+            buf.extend_from_slice(&u.to_be_bytes());
+        }
+        Element::Float(f) => {
+            // positive floats only need the sign bit set, negative floats need every bit
+            // flipped so larger magnitudes sort first
+            buf.push(FLOAT_CODE);
+            let bits = f.to_bits();
+            let bits = if bits >> 63 == 1 { !bits } else { bits ^ (1 << 63) };
+            buf.extend_from_slice(&bits.to_be_bytes());
+        }
+        Element::Bool(false) => buf.push(FALSE_CODE),
+        Element::Bool(true) => buf.push(TRUE_CODE),
+    }
+}
+
+fn encode_escaped(buf: &mut Vec<u8>, bytes: &[u8]) {
+    for &b in bytes {
+        buf.push(b);
+        if b == 0x00 {
+            buf.push(ESCAPE);
+        }
+    }
+    buf.push(0x00);
+}
+
+fn decode_element(buf: &[u8], cursor: &mut usize) -> Result<Element> {
+    let code = buf[*cursor];
+    *cursor += 1;
+    match code {
+        BYTES_CODE => Ok(Element::Bytes(decode_escaped(buf, cursor)?)),
+        STRING_CODE => {
+            let bytes = decode_escaped(buf, cursor)?;
+            String::from_utf8(bytes)
+                .map(Element::String)
+                .map_err(|_| invalid("string element is not valid utf8"))
+        }
+        NESTED_CODE => {
+            let mut elements = Vec::new();
+            loop {
+                match buf.get(*cursor) {
+                    Some(&NESTED_END) => {
+                        *cursor += 1;
+                        break;
+                    }
+                    Some(_) => elements.push(decode_element(buf, cursor)?),
+                    None => return Err(invalid("nested tuple is missing its terminator")),
+                }
+            }
+            Ok(Element::Tuple(elements))
+        }
+        INT_CODE => {
+            let bits = read_u64(buf, cursor)?;
+            Ok(Element::Int((bits ^ (1 << 63)) as i64))
+        }
+        UINT_CODE => Ok(Element::UInt(read_u64(buf, cursor)?)),
+        FLOAT_CODE => {
+            let bits = read_u64(buf, cursor)?;
+            let bits = if bits >> 63 == 1 { bits ^ (1 << 63) } else { !bits };
+            Ok(Element::Float(f64::from_bits(bits)))
+        }
+        FALSE_CODE => Ok(Element::Bool(false)),
+        TRUE_CODE => Ok(Element::Bool(true)),
+        _ => Err(invalid("unknown type code")),
+    }
+}
+
+fn decode_escaped(buf: &[u8], cursor: &mut usize) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    loop {
+        match buf.get(*cursor) {
+            Some(0x00) if buf.get(*cursor + 1) == Some(&ESCAPE) => {
+                bytes.push(0x00);
+                *cursor += 2;
+            }
+            Some(0x00) => {
+                *cursor += 1;
+                return Ok(bytes);
+            }
+            Some(&b) => {
+                bytes.push(b);
+                *cursor += 1;
+            }
+            None => return Err(invalid("bytes element is missing its terminator")),
+        }
+    }
+}
+
+fn read_u64(buf: &[u8], cursor: &mut usize) -> Result<u64> {
+    let end = *cursor + 8;
+    if end > buf.len() {
+        return Err(invalid("truncated number element"));
+    }
+    let value = u64::from_be_bytes(buf[*cursor..end].try_into().unwrap());
+    *cursor = end;
+    Ok(value)
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+macro_rules! impl_from_int {
+    ($variant:ident, $target:ty, $($t:ty),*) => {
+        $(
+            impl From<$t> for Element {
+                fn from(value: $t) -> Self {
+                    Element::$variant(value as $target)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_int!(Int, i64, i8, i16, i32, i64);
+impl_from_int!(UInt, u64, u8, u16, u32, u64);
+
+impl From<f64> for Element {
+    fn from(value: f64) -> Self {
+        Element::Float(value)
+    }
+}
+
+impl From<bool> for Element {
+    fn from(value: bool) -> Self {
+        Element::Bool(value)
+    }
+}
+
+impl From<&str> for Element {
+    fn from(value: &str) -> Self {
+        Element::String(value.to_string())
+    }
+}
+
+impl From<String> for Element {
+    fn from(value: String) -> Self {
+        Element::String(value)
+    }
+}
+
+impl From<&[u8]> for Element {
+    fn from(value: &[u8]) -> Self {
+        Element::Bytes(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Element {
+    fn from(value: Vec<u8>) -> Self {
+        Element::Bytes(value)
+    }
+}
+
+impl From<Vec<Element>> for Element {
+    fn from(value: Vec<Element>) -> Self {
+        Element::Tuple(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_sorted(tuples: Vec<Vec<Element>>) {
+        let packed: Vec<Vec<u8>> = tuples.iter().map(|t| pack(t)).collect();
+        for i in 1..packed.len() {
+            assert!(packed[i - 1] < packed[i], "{:?} should sort before {:?}", tuples[i - 1], tuples[i]);
+        }
+    }
+
+    #[test]
+    fn test_pack_unpack_roundtrip() {
+        let tuple = vec![
+            Element::from(42u64),
+            Element::from(-7i64),
+            Element::from("tenant\0a"),
+            Element::from(b"\x00\xff\x00".as_slice()),
+            Element::from(1.5f64),
+            Element::from(true),
+            Element::from(vec![Element::from("nested"), Element::from(false)]),
+        ];
+
+        let packed = pack(&tuple);
+        assert_eq!(unpack(&packed).unwrap(), tuple);
+    }
+
+    #[test]
+    fn test_integer_order() {
+        assert_sorted(vec![
+            vec![Element::from(i64::MIN)],
+            vec![Element::from(-1000i64)],
+            vec![Element::from(-1i64)],
+            vec![Element::from(0i64)],
+            vec![Element::from(1i64)],
+            vec![Element::from(i64::MAX)],
+        ]);
+        assert_sorted(vec![
+            vec![Element::from(0u64)],
+            vec![Element::from(255u64)],
+            vec![Element::from(256u64)],
+            vec![Element::from(u64::MAX)],
+        ]);
+    }
+
+    #[test]
+    fn test_float_order() {
+        assert_sorted(vec![
+            vec![Element::from(f64::NEG_INFINITY)],
+            vec![Element::from(-2.5f64)],
+            vec![Element::from(-0.1f64)],
+            vec![Element::from(0.0f64)],
+            vec![Element::from(0.1f64)],
+            vec![Element::from(2.5f64)],
+            vec![Element::from(f64::INFINITY)],
+        ]);
+    }
+
+    #[test]
+    fn test_string_and_composite_order() {
+        assert_sorted(vec![
+            vec![Element::from("a")],
+            vec![Element::from("a"), Element::from(1u64)],
+            vec![Element::from("a\0")],
+            vec![Element::from("ab")],
+            vec![Element::from("b")],
+        ]);
+        assert_sorted(vec![
+            vec![Element::from(vec![Element::from("a")])],
+            vec![Element::from(vec![Element::from("a"), Element::from("b")])],
+            vec![Element::from(vec![Element::from("b")])],
+        ]);
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        let (start, end) = prefix_range(&[Element::from(7u64)]);
+        let inside = pack(&[Element::from(7u64), Element::from(100i64), Element::from("id")]);
+        let before = pack(&[Element::from(6u64), Element::from("zzz")]);
+        let after = pack(&[Element::from(8u64)]);
+
+        assert!(start <= inside && inside < end);
+        assert!(before < start);
+        assert!(after >= end);
+    }
+
+    #[test]
+    fn test_unpack_rejects_truncated_input() {
+        let packed = pack(&[Element::from(1u64)]);
+        assert!(unpack(&packed[..4]).is_err());
+        assert!(unpack(&[BYTES_CODE, b'a']).is_err());
+        assert!(unpack(&[0xEE]).is_err());
+    }
+}